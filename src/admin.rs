@@ -0,0 +1,512 @@
+//! Managing the RRDP repositories of a relay at runtime.
+//!
+//! The repositories are kept in a JSON store, so that changes made
+//! through the admin API survive a restart. The API is served by epic
+//! under `PATH`, and requires a bearer token. The `AdminClient` is
+//! used by the `epic admin` subcommand to talk to it.
+//!
+//! - `GET    <PATH>/repositories` lists the repositories
+//! - `POST   <PATH>/repositories` adds a repository
+//! - `DELETE <PATH>/repositories?notify=<uri>` removes a repository
+//! - `PUT    <PATH>/repositories/mappings?notify=<uri>` sets its disk
+//!   mappings
+//! - `PUT    <PATH>/repositories/refresh?notify=<uri>` sets its refresh
+//! - `POST   <PATH>/repositories/update?notify=<uri>` updates it now
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, anyhow};
+use log::info;
+use reqwest::{Method, blocking::Client, header};
+use rpki::uri;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    fetch::{
+        pool::{DEFAULT_REFRESH_SECONDS, MAX_REFRESH_SECONDS, RepositoryPool, RepositorySource},
+        retrieval::{self, FetchMapper, Fqdn, USER_AGENT},
+    },
+    util,
+};
+
+/// The path under which the admin API is served.
+pub const PATH: &str = "/admin/v1";
+
+/// The default file in which the repositories are kept.
+pub const DEFAULT_STORE_FILE: &str = "repositories.json";
+
+/// An RRDP repository, as it is configured.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RepositoryConfig {
+    /// The RRDP notification URI, which identifies the repository.
+    pub notify: uri::Https,
+
+    /// Local directories to read files from instead of fetching them,
+    /// by the FQDN of their URIs. This is mostly useful for testing.
+    #[serde(default)]
+    pub disk_mappings: BTreeMap<Fqdn, PathBuf>,
+
+    /// Seconds between regular updates.
+    #[serde(default = "default_refresh")]
+    pub refresh: i64,
+}
+
+fn default_refresh() -> i64 {
+    DEFAULT_REFRESH_SECONDS
+}
+
+impl RepositoryConfig {
+    pub fn new(notify: uri::Https) -> Self {
+        RepositoryConfig {
+            notify,
+            disk_mappings: BTreeMap::new(),
+            refresh: DEFAULT_REFRESH_SECONDS,
+        }
+    }
+
+    pub fn source(&self) -> RepositorySource {
+        RepositorySource::Rrdp(self.notify.clone())
+    }
+
+    pub fn fetch_mapper(&self) -> FetchMapper {
+        let mut mapper = FetchMapper::empty();
+        for (fqdn, dir) in &self.disk_mappings {
+            mapper.add_disk_mapper(fqdn.clone(), dir.clone());
+        }
+        mapper
+    }
+}
+
+/// The body of a request to set the refresh of a repository.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct RefreshRequest {
+    pub refresh: i64,
+}
+
+/// The query that selects a repository in requests.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RepositoryQuery {
+    pub notify: uri::Https,
+}
+
+/// The configured repositories, kept in a JSON file.
+///
+/// Changes are saved before they are applied to the pool. If saving
+/// fails, nothing is changed and the error is returned, so that what
+/// runs never differs from what a restart would bring back.
+#[derive(Debug)]
+pub struct RepositoryStore {
+    path: PathBuf,
+    repositories: Vec<RepositoryConfig>,
+}
+
+impl RepositoryStore {
+    /// Loads the store from its file. If there is no file yet, the
+    /// store starts with the given repositories. These are only saved
+    /// when something is changed.
+    pub fn load(path: PathBuf, initial: Vec<RepositoryConfig>) -> anyhow::Result<Self> {
+        let repositories = if path.exists() {
+            let json = util::read_file(&path)?;
+            serde_json::from_slice(json.as_ref())
+                .with_context(|| format!("Cannot parse repositories in {}", path.display()))?
+        } else {
+            initial
+        };
+        Ok(RepositoryStore { path, repositories })
+    }
+
+    pub fn repositories(&self) -> &[RepositoryConfig] {
+        &self.repositories
+    }
+
+    pub fn get(&self, notify: &uri::Https) -> Option<&RepositoryConfig> {
+        self.repositories.iter().find(|r| &r.notify == notify)
+    }
+
+    pub fn contains(&self, notify: &uri::Https) -> bool {
+        self.get(notify).is_some()
+    }
+
+    /// Creates a pool with all repositories in the store.
+    pub fn create_pool(&self) -> anyhow::Result<RepositoryPool> {
+        let mut pool = RepositoryPool::empty();
+        for repository in &self.repositories {
+            pool.add(
+                repository.source(),
+                repository.fetch_mapper(),
+                repository.refresh,
+            )?;
+        }
+        Ok(pool)
+    }
+
+    pub fn add(
+        &mut self,
+        pool: &mut RepositoryPool,
+        repository: RepositoryConfig,
+    ) -> anyhow::Result<()> {
+        Self::check_refresh(repository.refresh)?;
+        if self.contains(&repository.notify) {
+            return Err(anyhow!("Repository {} already exists", repository.notify));
+        }
+        let source = repository.source();
+        if pool.contains(&source) {
+            return Err(anyhow!("Repository {source} is already in the pool"));
+        }
+        let (mapper, refresh) = (repository.fetch_mapper(), repository.refresh);
+        self.save_with(|repositories| repositories.push(repository))?;
+        pool.add(source, mapper, refresh)
+    }
+
+    pub fn remove(&mut self, pool: &mut RepositoryPool, notify: &uri::Https) -> anyhow::Result<()> {
+        let position = self.position(notify)?;
+        self.save_with(|repositories| {
+            repositories.remove(position);
+        })?;
+        pool.remove(&RepositorySource::Rrdp(notify.clone()));
+        Ok(())
+    }
+
+    /// Replaces the disk mappings of a repository.
+    pub fn set_disk_mappings(
+        &mut self,
+        pool: &mut RepositoryPool,
+        notify: &uri::Https,
+        disk_mappings: BTreeMap<Fqdn, PathBuf>,
+    ) -> anyhow::Result<()> {
+        let position = self.position(notify)?;
+        self.save_with(|repositories| repositories[position].disk_mappings = disk_mappings)?;
+        let repository = &self.repositories[position];
+        pool.set_fetch_mapper(&repository.source(), repository.fetch_mapper());
+        Ok(())
+    }
+
+    pub fn set_refresh(
+        &mut self,
+        pool: &mut RepositoryPool,
+        notify: &uri::Https,
+        refresh: i64,
+    ) -> anyhow::Result<()> {
+        Self::check_refresh(refresh)?;
+        let position = self.position(notify)?;
+        self.save_with(|repositories| repositories[position].refresh = refresh)?;
+        pool.set_refresh(&self.repositories[position].source(), refresh);
+        Ok(())
+    }
+
+    /// Applies the changes made to the repositories in the config file,
+    /// from `old` to `new`. Repositories that are in neither, such as
+    /// those added through the admin API, are left alone.
+    pub fn apply_config_change(
+        &mut self,
+        pool: &mut RepositoryPool,
+        old: &[RepositoryConfig],
+        new: &[RepositoryConfig],
+    ) -> anyhow::Result<()> {
+        for repository in old {
+            if !new.iter().any(|r| r.notify == repository.notify)
+                && self.contains(&repository.notify)
+            {
+                self.remove(pool, &repository.notify)?;
+            }
+        }
+        for repository in new {
+            if old.contains(repository) {
+                continue;
+            }
+            if self.contains(&repository.notify) {
+                let notify = &repository.notify;
+                self.set_disk_mappings(pool, notify, repository.disk_mappings.clone())?;
+                self.set_refresh(pool, notify, repository.refresh)?;
+            } else {
+                self.add(pool, repository.clone())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn position(&self, notify: &uri::Https) -> anyhow::Result<usize> {
+        self.repositories
+            .iter()
+            .position(|r| &r.notify == notify)
+            .ok_or_else(|| anyhow!("No such repository: {notify}"))
+    }
+
+    /// Checks that a refresh can be used, before anything is changed.
+    pub fn check_refresh(refresh: i64) -> anyhow::Result<()> {
+        if !(1..=MAX_REFRESH_SECONDS).contains(&refresh) {
+            return Err(anyhow!(
+                "The refresh must be from 1 to {MAX_REFRESH_SECONDS} seconds"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Saves the repositories with the change made to them, and keeps
+    /// the change only if that worked.
+    fn save_with(&mut self, change: impl FnOnce(&mut Vec<RepositoryConfig>)) -> anyhow::Result<()> {
+        let mut repositories = self.repositories.clone();
+        change(&mut repositories);
+        let json = serde_json::to_vec_pretty(&repositories)?;
+        util::write_file(&self.path, &json)
+            .with_context(|| format!("Cannot save repositories to {}", self.path.display()))?;
+        info!("Saved repositories to {}", self.path.display());
+        self.repositories = repositories;
+        Ok(())
+    }
+}
+
+/// Checks a bearer token in an `Authorization` header value against
+/// the expected token. The comparison takes the same time for any
+/// token of the expected length.
+pub fn check_token(authorization: Option<&str>, token: &str) -> bool {
+    let Some(given) = authorization.and_then(|value| value.strip_prefix("Bearer ")) else {
+        return false;
+    };
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Reads a token from a file, ignoring surrounding whitespace.
+pub fn read_token(path: &Path) -> anyhow::Result<String> {
+    let data = util::read_file(path)?;
+    let token = std::str::from_utf8(&data)
+        .with_context(|| format!("Token in {} is not text", path.display()))?
+        .trim()
+        .to_string();
+    if token.is_empty() {
+        return Err(anyhow!("Token in {} is empty", path.display()));
+    }
+    Ok(token)
+}
+
+/// A client for the admin API of a running relay.
+pub struct AdminClient {
+    /// The base URL of the relay, without a trailing slash.
+    server: String,
+    token: String,
+    client: Client,
+}
+
+impl AdminClient {
+    pub fn new(server: &str, token: String) -> anyhow::Result<Self> {
+        // The token must only go to the relay, so its certificate is
+        // checked even when fetching is insecure.
+        let client = retrieval::strict_client_builder()
+            .timeout(Duration::from_secs(60))
+            .build()?;
+        Ok(AdminClient {
+            server: server.trim_end_matches('/').to_string(),
+            token,
+            client,
+        })
+    }
+
+    pub fn repositories(&self) -> anyhow::Result<Vec<RepositoryConfig>> {
+        let body = self.request(Method::GET, "repositories", None, None)?;
+        serde_json::from_slice(&body).context("Cannot parse repositories in response")
+    }
+
+    pub fn add(&self, repository: &RepositoryConfig) -> anyhow::Result<()> {
+        let body = serde_json::to_vec(repository)?;
+        self.request(Method::POST, "repositories", None, Some(body))
+            .map(|_| ())
+    }
+
+    pub fn remove(&self, notify: &uri::Https) -> anyhow::Result<()> {
+        self.request(Method::DELETE, "repositories", Some(notify), None)
+            .map(|_| ())
+    }
+
+    pub fn set_disk_mappings(
+        &self,
+        notify: &uri::Https,
+        disk_mappings: &BTreeMap<Fqdn, PathBuf>,
+    ) -> anyhow::Result<()> {
+        let body = serde_json::to_vec(disk_mappings)?;
+        self.request(
+            Method::PUT,
+            "repositories/mappings",
+            Some(notify),
+            Some(body),
+        )
+        .map(|_| ())
+    }
+
+    pub fn set_refresh(&self, notify: &uri::Https, refresh: i64) -> anyhow::Result<()> {
+        let body = serde_json::to_vec(&RefreshRequest { refresh })?;
+        self.request(
+            Method::PUT,
+            "repositories/refresh",
+            Some(notify),
+            Some(body),
+        )
+        .map(|_| ())
+    }
+
+    pub fn update(&self, notify: &uri::Https) -> anyhow::Result<()> {
+        self.request(Method::POST, "repositories/update", Some(notify), None)
+            .map(|_| ())
+    }
+
+    fn request(
+        &self,
+        method: Method,
+        path: &str,
+        notify: Option<&uri::Https>,
+        body: Option<Vec<u8>>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let url = format!("{}{PATH}/{path}", self.server);
+        let mut request = self
+            .client
+            .request(method.clone(), &url)
+            .header(header::USER_AGENT, USER_AGENT)
+            .bearer_auth(&self.token);
+        if let Some(notify) = notify {
+            request = request.query(&RepositoryQuery {
+                notify: notify.clone(),
+            });
+        }
+        if let Some(body) = body {
+            request = request
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(body);
+        }
+
+        let response = request
+            .send()
+            .with_context(|| format!("Could not {method}: {url}"))?;
+        let status = response.status();
+        let body = response
+            .bytes()
+            .with_context(|| format!("Got no response from {url}"))?;
+        if !status.is_success() {
+            return Err(anyhow!(
+                "{method} {url} failed with {status}: {}",
+                String::from_utf8_lossy(&body).trim()
+            ));
+        }
+        Ok(body.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::{https, test_with_dir};
+
+    use super::*;
+
+    #[test]
+    fn store_persists_changes() {
+        test_with_dir("store_persists_changes", |dir| {
+            let path = dir.join(DEFAULT_STORE_FILE);
+            let notify = https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            let mut store = RepositoryStore::load(path.clone(), vec![]).unwrap();
+            let mut pool = store.create_pool().unwrap();
+            let mut repository = RepositoryConfig::new(notify.clone());
+            repository.refresh = 0;
+            assert!(store.add(&mut pool, repository.clone()).is_err());
+            repository.refresh = i64::MAX;
+            assert!(store.add(&mut pool, repository.clone()).is_err());
+            repository.refresh = 60;
+            store.add(&mut pool, repository.clone()).unwrap();
+            assert!(store.add(&mut pool, repository).is_err());
+
+            let mappings = BTreeMap::from([(
+                Fqdn::from(&notify),
+                PathBuf::from("test-resources/rrdp-rev2656/"),
+            )]);
+            store
+                .set_disk_mappings(&mut pool, &notify, mappings.clone())
+                .unwrap();
+            store.set_refresh(&mut pool, &notify, 30).unwrap();
+            assert!(pool.update_due());
+            assert_eq!(30, pool.repositories().next().unwrap().refresh());
+
+            let loaded = RepositoryStore::load(path.clone(), vec![]).unwrap();
+            assert_eq!(store.repositories(), loaded.repositories());
+            assert_eq!(mappings, loaded.repositories()[0].disk_mappings);
+
+            store.remove(&mut pool, &notify).unwrap();
+            assert!(store.remove(&mut pool, &notify).is_err());
+            assert!(pool.is_empty());
+            assert!(
+                RepositoryStore::load(path, vec![])
+                    .unwrap()
+                    .repositories()
+                    .is_empty()
+            );
+        });
+    }
+
+    #[test]
+    fn store_changes_nothing_unless_saved() {
+        test_with_dir("store_changes_nothing_unless_saved", |dir| {
+            let path = dir.join(DEFAULT_STORE_FILE);
+            let notify = https("https://example.org/notification.xml");
+            let added = RepositoryConfig::new(notify.clone());
+
+            let mut store = RepositoryStore::load(path.clone(), vec![added.clone()]).unwrap();
+            let mut pool = store.create_pool().unwrap();
+
+            // A directory cannot be replaced by the file.
+            std::fs::create_dir(&path).unwrap();
+            assert!(store.set_refresh(&mut pool, &notify, 30).is_err());
+            assert!(store.remove(&mut pool, &notify).is_err());
+            let other = RepositoryConfig::new(https("https://example.net/notification.xml"));
+            assert!(store.add(&mut pool, other.clone()).is_err());
+
+            assert_eq!(&[added], store.repositories());
+            assert_eq!(1, pool.len());
+            assert!(!pool.contains(&other.source()));
+            let repository = pool.repositories().next().unwrap();
+            assert_eq!(DEFAULT_REFRESH_SECONDS, repository.refresh());
+        });
+    }
+
+    #[test]
+    fn apply_config_changes() {
+        test_with_dir("apply_config_changes", |dir| {
+            let configured = RepositoryConfig::new(https("https://example.org/notification.xml"));
+            let removed = RepositoryConfig::new(https("https://example.net/notification.xml"));
+            let added = RepositoryConfig::new(https("https://example.com/notification.xml"));
+            let by_admin = RepositoryConfig::new(https("https://example.nl/notification.xml"));
+
+            let old = vec![configured.clone(), removed.clone()];
+            let initial = vec![configured.clone(), removed.clone(), by_admin.clone()];
+            let mut store = RepositoryStore::load(dir.join(DEFAULT_STORE_FILE), initial).unwrap();
+            let mut pool = store.create_pool().unwrap();
+
+            let mut changed = configured.clone();
+            changed.refresh = 30;
+            let new = vec![changed.clone(), added.clone()];
+            store.apply_config_change(&mut pool, &old, &new).unwrap();
+
+            assert_eq!(&[changed, by_admin, added], store.repositories());
+            assert_eq!(3, pool.len());
+            assert!(!pool.contains(&removed.source()));
+
+            // Nothing changes without a change in the config.
+            store.apply_config_change(&mut pool, &new, &new).unwrap();
+            assert_eq!(3, store.repositories().len());
+        });
+    }
+
+    #[test]
+    fn check_bearer_token() {
+        assert!(check_token(Some("Bearer secret"), "secret"));
+        assert!(!check_token(Some("Bearer secreT"), "secret"));
+        assert!(!check_token(Some("Bearer secret2"), "secret"));
+        assert!(!check_token(Some("secret"), "secret"));
+        assert!(!check_token(None, "secret"));
+    }
+}
@@ -1,17 +1,239 @@
+use anyhow::{Context, anyhow};
 use axum::{
-    Router,
-    extract::Path,
-    http::{StatusCode, header},
-    response::IntoResponse,
-    routing::get,
+    Json, Router,
+    body::{Body, Bytes},
+    extract::{MatchedPath, Path, Query, Request, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    middleware::{self, Next},
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+    routing::{get, post, put},
 };
 use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
-use log::debug;
+use log::{debug, info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::iter;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
-use std::{process::exit, sync::Arc};
+use std::{
+    process::exit,
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use epic::{
+    admin::{
+        self, AdminClient, RefreshRequest, RepositoryConfig, RepositoryQuery, RepositoryStore,
+    },
+    config::{self, Config, ConfigArgs},
+    dashboard,
+    erik::{
+        asn1::{ErikIndex, ErikPartition},
+        batch, bundle,
+        media_type::{ObjectKind, check_media_type},
+        ni::{self, NamedInformation},
+        relay::{IndexEvent, RelayContent},
+        state::ErikPartitionKey,
+    },
+    fetch::{
+        discovery::Discovery,
+        pool::{DEFAULT_REFRESH_SECONDS, PoolStatus, RepositoryPool, RepositorySource},
+        proxy::{ErikProxy, ErikProxyConfig},
+        retrieval::{self, FetchMapper, Fqdn},
+        rrdp::RepoContentElement,
+    },
+    metrics::{self, Metrics},
+    output::{
+        rrdp::{DEFAULT_CLEANUP_AFTER, DEFAULT_MAX_DELTAS, RrdpOutput, RrdpOutputConfig},
+        rsync::{RsyncOutput, RsyncOutputConfig},
+    },
+    publication::{self, PublicationConfig, PublicationServer},
+    status::{IndexReport, ObjectReport, RelayStatus},
+    tls::{ServerCertificate, TlsListener},
+};
+use rpki::{crypto::KeyIdentifier, repository::x509::Time, rrdp::Hash, uri};
+use structopt::StructOpt;
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    sync::{broadcast, watch},
+};
+use tokio_stream::{Stream, StreamExt, wrappers::BroadcastStream};
+
+/// How often we check whether any repository is due for an update.
+const UPDATE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Named objects never change.
+const OBJECT_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// How long to wait for requests and updates to finish when shutting
+/// down.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The number of index updates that are buffered for each
+/// events subscriber.
+const EVENTS_CAPACITY: usize = 64;
+
+type SharedContent = Arc<RwLock<Arc<RelayContent>>>;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "epic")]
+struct Opt {
+    #[structopt(flatten)]
+    config: ConfigArgs,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Manage the repositories of a running relay through its admin
+    /// API.
+    Admin(AdminOpt),
+}
+
+#[derive(StructOpt, Debug)]
+struct AdminOpt {
+    /// The base URL of the relay.
+    #[structopt(long, default_value = "http://localhost:3000")]
+    server: String,
+
+    /// The file with the bearer token of the admin API.
+    #[structopt(long)]
+    token_file: PathBuf,
+
+    /// Also trust the CA certificates in this PEM file for the relay,
+    /// e.g. a local test CA. Can be given more than once.
+    #[structopt(long = "ca-cert", number_of_values = 1)]
+    ca_certs: Vec<PathBuf>,
+
+    #[structopt(subcommand)]
+    action: AdminAction,
+}
+
+#[derive(StructOpt, Debug)]
+enum AdminAction {
+    /// List the repositories, as JSON.
+    List,
+    /// Add an RRDP repository by its notification URI.
+    Add {
+        notify: uri::Https,
 
-use epic::fetch::rrdp::RepoContent;
-use rpki::rrdp::Hash;
+        /// Seconds between updates.
+        #[structopt(long)]
+        refresh: Option<i64>,
+
+        /// Read files for an FQDN from a directory: `<fqdn>=<dir>`.
+        #[structopt(long = "map", parse(try_from_str = parse_disk_mapping))]
+        mappings: Vec<(Fqdn, PathBuf)>,
+    },
+    /// Remove a repository.
+    Remove { notify: uri::Https },
+    /// Set the disk mappings of a repository, replacing those it had.
+    /// Without any, files are fetched over HTTPS again.
+    Map {
+        notify: uri::Https,
+
+        /// Read files for an FQDN from a directory: `<fqdn>=<dir>`.
+        #[structopt(long = "map", parse(try_from_str = parse_disk_mapping))]
+        mappings: Vec<(Fqdn, PathBuf)>,
+    },
+    /// Set the seconds between updates of a repository.
+    Refresh { notify: uri::Https, seconds: i64 },
+    /// Update a repository now.
+    Update { notify: uri::Https },
+}
+
+fn parse_disk_mapping(s: &str) -> Result<(Fqdn, PathBuf), String> {
+    match s.split_once('=') {
+        Some((fqdn, dir)) if !fqdn.is_empty() && !dir.is_empty() => {
+            let Ok(fqdn) = fqdn.parse::<Fqdn>();
+            Ok((fqdn, PathBuf::from(dir)))
+        }
+        _ => Err(format!("expected <fqdn>=<dir>, got: {s}")),
+    }
+}
+
+fn run_admin(opt: AdminOpt) -> anyhow::Result<()> {
+    let token = admin::read_token(&opt.token_file)?;
+    retrieval::set_root_certificates(&opt.ca_certs)?;
+    let client = AdminClient::new(&opt.server, token)?;
+    match opt.action {
+        AdminAction::List => {
+            let repositories = client.repositories()?;
+            println!("{}", serde_json::to_string_pretty(&repositories)?);
+        }
+        AdminAction::Add {
+            notify,
+            refresh,
+            mappings,
+        } => {
+            let mut repository = RepositoryConfig::new(notify);
+            repository.refresh = refresh.unwrap_or(DEFAULT_REFRESH_SECONDS);
+            repository.disk_mappings = mappings.into_iter().collect();
+            client.add(&repository)?;
+            println!("Added {}", repository.notify);
+        }
+        AdminAction::Remove { notify } => {
+            client.remove(&notify)?;
+            println!("Removed {notify}");
+        }
+        AdminAction::Map { notify, mappings } => {
+            client.set_disk_mappings(&notify, &mappings.into_iter().collect())?;
+            println!("Set disk mappings of {notify}");
+        }
+        AdminAction::Refresh { notify, seconds } => {
+            client.set_refresh(&notify, seconds)?;
+            println!("Set refresh of {notify} to {seconds} seconds");
+        }
+        AdminAction::Update { notify } => {
+            client.update(&notify)?;
+            println!("Scheduled an update of {notify}");
+        }
+    }
+    Ok(())
+}
+
+/// The query parameters of an ni request.
+#[derive(Deserialize)]
+struct NiQuery {
+    /// The content type hint.
+    ct: Option<String>,
+}
+
+/// The query parameters of an API object lookup by URI.
+#[derive(Deserialize)]
+struct UriQuery {
+    uri: String,
+}
+
+/// The optional outputs that are written whenever the content changes.
+#[derive(Default)]
+struct Outputs {
+    rrdp: Option<RrdpOutput>,
+    rsync: Option<RsyncOutput>,
+}
+
+impl Outputs {
+    fn update(&mut self, objects: &HashMap<uri::Rsync, Arc<RepoContentElement>>) {
+        if let Some(rrdp) = self.rrdp.as_mut()
+            && let Err(e) = rrdp.update(objects)
+        {
+            warn!("Could not update RRDP output: {e:#}");
+        }
+        if let Some(rsync) = self.rsync.as_ref()
+            && let Err(e) = rsync.write(objects)
+        {
+            warn!("Could not update rsync output: {e:#}");
+        }
+    }
+}
 
 fn bad_hash(val: String) -> (StatusCode, String) {
     (StatusCode::BAD_REQUEST, format!("invalid hash: {val}"))
@@ -21,60 +243,1397 @@ fn not_found(hash: Hash) -> (StatusCode, String) {
     (StatusCode::NOT_FOUND, format!("no such object: {hash}"))
 }
 
-fn der(data: Vec<u8>) -> impl IntoResponse {
+fn no_index(fqdn: Fqdn) -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, format!("no index for: {fqdn}"))
+}
+
+fn unknown_publisher(publisher: &str) -> (StatusCode, String) {
+    (
+        StatusCode::FORBIDDEN,
+        format!("unknown publisher: {publisher}"),
+    )
+}
+
+fn publication_reply(data: Bytes) -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, publication::CONTENT_TYPE)],
+        data,
+    )
+}
+
+fn xml(data: Vec<u8>) -> impl IntoResponse {
     (
         StatusCode::OK,
-        [(header::CONTENT_TYPE, "application/octet-stream+der")],
+        [(header::CONTENT_TYPE, "application/xml")],
         data,
     )
 }
 
-#[tokio::main]
-async fn main() {
-    if let Err(e) = run().await {
+/// Returns true if the request has an If-None-Match header that
+/// matches the ETag.
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Returns DER data with the given ETag and Cache-Control, or 304 if
+/// the client already has it.
+fn cached_der(
+    headers: &HeaderMap,
+    data: Bytes,
+    etag: String,
+    cache_control: &str,
+    content_type: &str,
+) -> Response {
+    let cache_headers = [
+        (header::ETAG, etag.clone()),
+        (header::CACHE_CONTROL, cache_control.to_string()),
+    ];
+    if etag_matches(headers, &etag) {
+        (StatusCode::NOT_MODIFIED, cache_headers).into_response()
+    } else {
+        (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, content_type.to_string())],
+            cache_headers,
+            data,
+        )
+            .into_response()
+    }
+}
+
+/// Returns the seconds that clients may cache an index: half the
+/// interval at which we update it. A proxied index was cached by us
+/// for its TTL already.
+fn index_max_age(proxy_index_ttl: Option<i64>, status: &PoolStatus) -> i64 {
+    match proxy_index_ttl {
+        Some(ttl) => ttl,
+        None => status.min_refresh.unwrap_or(DEFAULT_REFRESH_SECONDS) / 2,
+    }
+    .max(1)
+}
+
+/// A strong ETag for content with the given hash.
+fn hash_etag(hash: &Hash) -> String {
+    format!("\"{}\"", URL_SAFE_NO_PAD.encode(hash.as_slice()))
+}
+
+/// Returns an index. Indexes change, so they may only be cached for
+/// a short time. The batch endpoint is advertised with each index,
+/// and the bundle endpoint with indexes that we serve bundles for:
+/// a proxy does not have the partitions to bundle.
+fn index_response(
+    headers: &HeaderMap,
+    data: Bytes,
+    max_age: i64,
+    media_type: &str,
+    bundles: bool,
+) -> Response {
+    let etag = hash_etag(&Hash::from_data(&data));
+    let mut link = format!("</{}>; rel=\"{}\"", batch::PATH, batch::LINK_REL);
+    if bundles {
+        link.push_str(&format!(
+            ", </{}/>; rel=\"{}\"",
+            bundle::PATH,
+            bundle::LINK_REL
+        ));
+    }
+    (
+        [(header::LINK, link)],
+        cached_der(
+            headers,
+            data,
+            etag,
+            &format!("public, max-age={max_age}"),
+            media_type,
+        ),
+    )
+        .into_response()
+}
+
+/// Returns a named object. Its name is its hash, so it never changes.
+///
+/// The content type follows from what kind of object it is. The hint
+/// in the name comes from the client, so it is never sent back: an
+/// object of an unknown kind is served as opaque bytes, and clients
+/// are told not to guess otherwise.
+fn object_response(
+    headers: &HeaderMap,
+    ni: &NamedInformation,
+    kind: ObjectKind,
+    erik_media_type: &str,
+    data: Bytes,
+    metrics: &Metrics,
+) -> Response {
+    let size = data.len() as u64;
+    let mut response = cached_der(
+        headers,
+        data,
+        hash_etag(&ni.hash()),
+        OBJECT_CACHE_CONTROL,
+        kind.media_type(erik_media_type),
+    );
+    response.headers_mut().insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    if response.status() == StatusCode::OK {
+        metrics.record_served(1, size);
+    }
+    response
+}
+
+fn current(content: &SharedContent) -> Arc<RelayContent> {
+    content.read().unwrap().clone()
+}
+
+/// Announces index updates and removals as Server-Sent Events,
+/// optionally only those for the given scope.
+fn index_event_stream(
+    events: broadcast::Receiver<IndexEvent>,
+    fqdn: Option<Fqdn>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let stream = BroadcastStream::new(events).filter_map(move |event| {
+        // Lagging receivers miss some updates, but a later update
+        // makes them sync anyway.
+        let event = event.ok()?;
+        if fqdn.as_ref().is_some_and(|fqdn| fqdn != event.fqdn()) {
+            return None;
+        }
+        Some(match &event {
+            IndexEvent::Update(update) => {
+                Event::default().event(IndexEvent::UPDATE).json_data(update)
+            }
+            IndexEvent::Removal(removal) => Event::default()
+                .event(IndexEvent::REMOVAL)
+                .json_data(removal),
+        })
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// Updates all repositories in the pool that are due, and replaces
+/// the served content whenever something changed. Any configured
+/// outputs are updated as well. The status of the pool is kept after
+/// every update, so that it can be reported without waiting for the
+/// pool.
+///
+/// Once shutdown is requested, this returns after any update in
+/// progress. That ends the streams of index update events as well.
+async fn update_content(
+    pool: Arc<Mutex<RepositoryPool>>,
+    content: SharedContent,
+    pool_status: Arc<RwLock<PoolStatus>>,
+    outputs: Arc<Mutex<Outputs>>,
+    discovery: Option<Arc<Mutex<Discovery>>>,
+    events: broadcast::Sender<IndexEvent>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    while !*shutdown.borrow() {
+        let pool = pool.clone();
+        let outputs = outputs.clone();
+        let discovery = discovery.clone();
+        let stop = shutdown.clone();
+        let updated = tokio::task::spawn_blocking(move || {
+            let new_trust_anchors = discovery
+                .as_ref()
+                .is_some_and(|discovery| discovery.lock().unwrap().fetch_trust_anchors());
+
+            // The pool is only locked to hand out and take back the
+            // repositories, so that fetching does not hold up others.
+            let mut updates = {
+                let mut pool = pool.lock().unwrap();
+                if !pool.has_due() && !new_trust_anchors {
+                    return None;
+                }
+                pool.start_updates()
+            };
+            for update in &mut updates {
+                if *stop.borrow() {
+                    break;
+                }
+                update.run();
+            }
+            let (new_content, objects, status) = {
+                let mut pool = pool.lock().unwrap();
+                let changed = pool.finish_updates(updates);
+                // Repositories that are found are fetched next time.
+                if let Some(discovery) = discovery
+                    && (changed || new_trust_anchors)
+                {
+                    discovery.lock().unwrap().discover(&mut pool);
+                }
+                let new_content = changed.then(|| RelayContent::from_pool(&pool));
+                let objects = changed.then(|| pool.objects());
+                (new_content, objects, pool.status())
+            };
+            if let Some(objects) = objects {
+                outputs.lock().unwrap().update(&objects);
+            }
+            Some((new_content, status))
+        })
+        .await;
+
+        match updated {
+            Ok(Some((new_content, status))) => {
+                *pool_status.write().unwrap() = status;
+                if let Some(new_content) = new_content {
+                    debug!("Relay content was updated");
+                    let index_events = new_content.index_events(&current(&content));
+                    *content.write().unwrap() = Arc::new(new_content);
+                    for event in index_events {
+                        // There may be no one listening, that is fine.
+                        let _ = events.send(event);
+                    }
+                }
+            }
+            Ok(None) => {}
+            Err(e) => debug!("Repository update task failed: {e}"),
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(UPDATE_CHECK_INTERVAL) => {}
+            _ = shutdown.changed() => {}
+        }
+    }
+    debug!("Stopped updating repositories");
+}
+
+/// Subscribes to index events. If updates have stopped, the
+/// subscription ends right away.
+fn subscribe(events: &broadcast::WeakSender<IndexEvent>) -> broadcast::Receiver<IndexEvent> {
+    match events.upgrade() {
+        Some(events) => events.subscribe(),
+        None => broadcast::channel(1).1,
+    }
+}
+
+/// Resolves once shutdown is requested.
+async fn shutdown_requested(mut shutdown: watch::Receiver<bool>) {
+    // If the sender is gone, nothing else will happen either.
+    let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+}
+
+/// Counts each request by its route and status, with the time taken.
+/// Requests that match no route are not counted.
+async fn track_requests(
+    State(metrics): State<Arc<Metrics>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string());
+    let start = Instant::now();
+    let response = next.run(request).await;
+    if let Some(route) = route {
+        metrics.record_request(&route, response.status().as_u16(), start.elapsed());
+    }
+    response
+}
+
+/// Reports the status of the relay. A proxy has nothing to update, it
+/// is ready to fetch on demand. Otherwise we are ready once we have
+/// updated any repository.
+fn relay_status(is_proxy: bool, pool_status: &PoolStatus, content: &RelayContent) -> RelayStatus {
+    let ready = is_proxy
+        || pool_status
+            .repositories
+            .iter()
+            .any(|repository| repository.last_update.is_some());
+    RelayStatus::new(ready, pool_status, content)
+}
+
+/// The HTML dashboard: an overview at `/`, and the index browser under
+/// `dashboard::PATH`. Objects are named by their hex encoded hash.
+fn dashboard_routes(
+    content: SharedContent,
+    pool_status: Arc<RwLock<PoolStatus>>,
+    is_proxy: bool,
+    erik_media_type: String,
+) -> Router {
+    let html = |code: StatusCode, page: String| {
+        (
+            code,
+            [(header::CONTENT_TYPE, dashboard::CONTENT_TYPE)],
+            page,
+        )
+    };
+    let page_or_not_found = move |page: Option<String>, what: String| match page {
+        Some(page) => html(StatusCode::OK, page),
+        None => html(StatusCode::NOT_FOUND, dashboard::not_found(&what)),
+    };
+    let with_hash =
+        move |val: String, render: &dyn Fn(Hash) -> Option<String>| match Hash::from_str(&val) {
+            Ok(hash) => page_or_not_found(render(hash), not_found(hash).1),
+            Err(_) => html(
+                StatusCode::BAD_REQUEST,
+                dashboard::not_found(&bad_hash(val).1),
+            ),
+        };
+
+    let overview_content = content.clone();
+    let overview = async move || {
+        let pool_status = pool_status.read().unwrap().clone();
+        let content = current(&overview_content);
+        let status = relay_status(is_proxy, &pool_status, &content);
+        let page = dashboard::overview(&status, &pool_status, &content, Time::now());
+        html(StatusCode::OK, page)
+    };
+
+    let index_content = content.clone();
+    let index = async move |Path(fqdn): Path<String>| {
+        let Ok(fqdn) = fqdn.parse::<Fqdn>();
+        let page = dashboard::index(&current(&index_content), &fqdn);
+        page_or_not_found(page, no_index(fqdn).1)
+    };
+
+    let partition_content = content.clone();
+    let partition = async move |Path(val): Path<String>| {
+        let content = current(&partition_content);
+        with_hash(val, &|hash| {
+            dashboard::partition(&content, &hash, Time::now())
+        })
+    };
+
+    let manifest_content = content.clone();
+    let manifest = async move |Path(val): Path<String>| {
+        let content = current(&manifest_content);
+        with_hash(val, &|hash| {
+            dashboard::manifest(&content, &hash, Time::now())
+        })
+    };
+
+    let object = async move |Path(val): Path<String>| {
+        let content = current(&content);
+        with_hash(val, &|hash| {
+            dashboard::object(&content, &hash, &erik_media_type)
+        })
+    };
+
+    let path = dashboard::PATH;
+    Router::new()
+        .route("/", get(overview))
+        .route(&format!("{path}/indexes/{{fqdn}}"), get(index))
+        .route(&format!("{path}/partitions/{{hash}}"), get(partition))
+        .route(&format!("{path}/manifests/{{hash}}"), get(manifest))
+        .route(&format!("{path}/objects/{{hash}}"), get(object))
+}
+
+/// Rejects requests without the bearer token of the admin API.
+async fn require_token(State(token): State<Arc<str>>, request: Request, next: Next) -> Response {
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    if !admin::check_token(authorization, &token) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            "invalid or missing admin token",
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// Runs a change to the repositories on the blocking pool, since it
+/// waits for any update of the pool to finish, and writes the store.
+async fn change_repositories<T: Send + 'static>(
+    store: &Arc<Mutex<RepositoryStore>>,
+    pool: &Arc<Mutex<RepositoryPool>>,
+    change: impl FnOnce(&mut RepositoryStore, &mut RepositoryPool) -> Result<T, (StatusCode, String)>
+    + Send
+    + 'static,
+) -> Result<T, (StatusCode, String)> {
+    let (store, pool) = (store.clone(), pool.clone());
+    tokio::task::spawn_blocking(move || {
+        let mut store = store.lock().unwrap();
+        let mut pool = pool.lock().unwrap();
+        change(&mut store, &mut pool)
+    })
+    .await
+    .unwrap_or_else(|e| {
+        warn!("Admin task failed: {e}");
+        Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "admin task failed".into(),
+        ))
+    })
+}
+
+/// The admin API to manage the RRDP repositories at runtime, under
+/// `admin::PATH`. Changes are saved in the store.
+fn admin_routes(
+    store: Arc<Mutex<RepositoryStore>>,
+    pool: Arc<Mutex<RepositoryPool>>,
+    token: String,
+) -> Router {
+    let no_such_repository = |notify: &uri::Https| {
+        (
+            StatusCode::NOT_FOUND,
+            format!("no such repository: {notify}"),
+        )
+    };
+    let check_refresh = |refresh: i64| {
+        RepositoryStore::check_refresh(refresh)
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("{e:#}")))
+    };
+    let internal = |e: anyhow::Error| {
+        warn!("Cannot change repositories: {e:#}");
+        (StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}"))
+    };
+
+    let (list_store, list_pool) = (store.clone(), pool.clone());
+    let list = async move || {
+        change_repositories(&list_store, &list_pool, |store, _| {
+            Ok(Json(store.repositories().to_vec()))
+        })
+        .await
+    };
+
+    let (add_store, add_pool) = (store.clone(), pool.clone());
+    let add = async move |Json(repository): Json<RepositoryConfig>| {
+        check_refresh(repository.refresh)?;
+        debug!("Admin: add repository {}", repository.notify);
+        change_repositories(&add_store, &add_pool, move |store, pool| {
+            if store.contains(&repository.notify) {
+                return Err((
+                    StatusCode::CONFLICT,
+                    format!("repository already exists: {}", repository.notify),
+                ));
+            }
+            store.add(pool, repository).map_err(internal)
+        })
+        .await
+        .map(|_| StatusCode::CREATED)
+    };
+
+    let (remove_store, remove_pool) = (store.clone(), pool.clone());
+    let remove = async move |Query(query): Query<RepositoryQuery>| {
+        debug!("Admin: remove repository {}", query.notify);
+        change_repositories(&remove_store, &remove_pool, move |store, pool| {
+            if !store.contains(&query.notify) {
+                return Err(no_such_repository(&query.notify));
+            }
+            store.remove(pool, &query.notify).map_err(internal)
+        })
+        .await
+        .map(|_| StatusCode::NO_CONTENT)
+    };
+
+    let (map_store, map_pool) = (store.clone(), pool.clone());
+    let set_mappings =
+        async move |Query(query): Query<RepositoryQuery>,
+                    Json(mappings): Json<BTreeMap<Fqdn, PathBuf>>| {
+            debug!("Admin: set disk mappings of {}", query.notify);
+            change_repositories(&map_store, &map_pool, move |store, pool| {
+                if !store.contains(&query.notify) {
+                    return Err(no_such_repository(&query.notify));
+                }
+                store
+                    .set_disk_mappings(pool, &query.notify, mappings)
+                    .map_err(internal)
+            })
+            .await
+            .map(|_| StatusCode::NO_CONTENT)
+        };
+
+    let (refresh_store, refresh_pool) = (store.clone(), pool.clone());
+    let set_refresh = async move |Query(query): Query<RepositoryQuery>,
+                                  Json(request): Json<RefreshRequest>| {
+        check_refresh(request.refresh)?;
+        debug!("Admin: set refresh of {}", query.notify);
+        change_repositories(&refresh_store, &refresh_pool, move |store, pool| {
+            if !store.contains(&query.notify) {
+                return Err(no_such_repository(&query.notify));
+            }
+            store
+                .set_refresh(pool, &query.notify, request.refresh)
+                .map_err(internal)
+        })
+        .await
+        .map(|_| StatusCode::NO_CONTENT)
+    };
+
+    let update = async move |Query(query): Query<RepositoryQuery>| {
+        debug!("Admin: update repository {}", query.notify);
+        change_repositories(&store, &pool, move |store, pool| {
+            if !store.contains(&query.notify) {
+                return Err(no_such_repository(&query.notify));
+            }
+            pool.schedule_update(&RepositorySource::Rrdp(query.notify));
+            Ok(())
+        })
+        .await
+        .map(|_| StatusCode::ACCEPTED)
+    };
+
+    let path = admin::PATH;
+    Router::new()
+        .route(
+            &format!("{path}/repositories"),
+            get(list).post(add).delete(remove),
+        )
+        .route(&format!("{path}/repositories/mappings"), put(set_mappings))
+        .route(&format!("{path}/repositories/refresh"), put(set_refresh))
+        .route(&format!("{path}/repositories/update"), post(update))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::<str>::from(token),
+            require_token,
+        ))
+}
+
+/// The read-only JSON API for browsing the served content, under
+/// `/api/v1`. Objects are named by their hex encoded SHA-256 hash.
+fn api_routes(content: SharedContent, erik_media_type: String) -> Router {
+    let json_error = |code: StatusCode, message: String| (code, Json(json!({ "error": message })));
+    let parse_hash = move |val: String| {
+        Hash::from_str(&val).map_err(|_| json_error(StatusCode::BAD_REQUEST, bad_hash(val).1))
+    };
+    let decode_partition = move |hash: Hash, bytes: &Bytes| {
+        ErikPartition::decode(bytes.as_ref()).map_err(|e| {
+            warn!("Cannot decode partition {hash}: {e}");
+            json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("invalid partition: {hash}"),
+            )
+        })
+    };
+
+    let list_content = content.clone();
+    let list_indexes = async move || Json(IndexReport::all(&current(&list_content)));
+
+    let index_content = content.clone();
+    let get_index = async move |Path(fqdn): Path<String>| {
+        let Ok(fqdn) = fqdn.parse::<Fqdn>();
+        let content = current(&index_content);
+        let Some(bytes) = content.index(&fqdn) else {
+            return Err(json_error(StatusCode::NOT_FOUND, no_index(fqdn).1));
+        };
+        ErikIndex::decode(bytes.as_ref()).map(Json).map_err(|e| {
+            warn!("Cannot decode index for {fqdn}: {e}");
+            json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("invalid index for: {fqdn}"),
+            )
+        })
+    };
+
+    let key_content = content.clone();
+    let partition_by_key = async move |Path((fqdn, key)): Path<(String, String)>| {
+        let Ok(fqdn) = fqdn.parse::<Fqdn>();
+        let key = ErikPartitionKey::from_str(&key).map_err(|_| {
+            json_error(
+                StatusCode::BAD_REQUEST,
+                format!("invalid partition key: {key}"),
+            )
+        })?;
+        let content = current(&key_content);
+        let hash = content.partition_hash(&fqdn, key).ok_or_else(|| {
+            json_error(
+                StatusCode::NOT_FOUND,
+                format!("no partition {key} for: {fqdn}"),
+            )
+        })?;
+        let bytes = content
+            .partition(&hash)
+            .ok_or_else(|| json_error(StatusCode::NOT_FOUND, not_found(hash).1))?;
+        decode_partition(hash, bytes).map(Json)
+    };
+
+    let hash_content = content.clone();
+    let partition_by_hash = async move |Path(val): Path<String>| {
+        let hash = parse_hash(val)?;
+        let content = current(&hash_content);
+        let bytes = content
+            .partition(&hash)
+            .ok_or_else(|| json_error(StatusCode::NOT_FOUND, not_found(hash).1))?;
+        decode_partition(hash, bytes).map(Json)
+    };
+
+    let manifests_content = content.clone();
+    let manifest_refs = async move |Path(aki): Path<String>| {
+        let aki = KeyIdentifier::from_str(&aki).map_err(|_| {
+            json_error(
+                StatusCode::BAD_REQUEST,
+                format!("invalid key identifier: {aki}"),
+            )
+        })?;
+        let mut mft_refs = current(&manifests_content).manifest_refs(&aki);
+        mft_refs.sort_by(|a, b| a.locations.as_str().cmp(b.locations.as_str()));
+        Ok::<_, (StatusCode, Json<serde_json::Value>)>(Json(mft_refs))
+    };
+
+    let object_content = content.clone();
+    let object_media_type = erik_media_type.clone();
+    let object_by_hash = async move |Path(val): Path<String>| {
+        let hash = parse_hash(val)?;
+        ObjectReport::new(&current(&object_content), &hash, &object_media_type)
+            .map(Json)
+            .ok_or_else(|| json_error(StatusCode::NOT_FOUND, not_found(hash).1))
+    };
+
+    let object_by_uri = async move |Query(query): Query<UriQuery>| {
+        let uri = uri::Rsync::from_str(&query.uri).map_err(|_| {
+            json_error(
+                StatusCode::BAD_REQUEST,
+                format!("invalid rsync URI: {}", query.uri),
+            )
+        })?;
+        let content = current(&content);
+        content
+            .element_by_uri(&uri)
+            .and_then(|(hash, _)| ObjectReport::new(&content, hash, &erik_media_type))
+            .map(Json)
+            .ok_or_else(|| json_error(StatusCode::NOT_FOUND, format!("no object at: {uri}")))
+    };
+
+    Router::new()
+        .route("/api/v1/indexes", get(list_indexes))
+        .route("/api/v1/indexes/{fqdn}", get(get_index))
+        .route(
+            "/api/v1/indexes/{fqdn}/partitions/{key}",
+            get(partition_by_key),
+        )
+        .route("/api/v1/partitions/{hash}", get(partition_by_hash))
+        .route("/api/v1/manifests/{aki}", get(manifest_refs))
+        .route("/api/v1/objects", get(object_by_uri))
+        .route("/api/v1/objects/{hash}", get(object_by_hash))
+}
+
+/// Returns the mapper for fetching from the upstream relay.
+fn upstream_mapper(server: &uri::Https, plain_http: bool) -> FetchMapper {
+    let mut mapper = FetchMapper::empty();
+    if plain_http {
+        mapper.add_http_mapper(server.into(), format!("http://{}", server.authority()));
+    }
+    mapper
+}
+
+/// Creates a pool that mirrors the given scopes from an upstream relay.
+fn create_upstream_pool(
+    server: uri::Https,
+    fqdns: Vec<Fqdn>,
+    plain_http: bool,
+    refresh: i64,
+) -> anyhow::Result<RepositoryPool> {
+    if fqdns.is_empty() {
+        return Err(anyhow!(
+            "Configure upstream_fqdns to set the scopes to mirror"
+        ));
+    }
+
+    let mapper = upstream_mapper(&server, plain_http);
+    let mut pool = RepositoryPool::empty();
+    for fqdn in fqdns {
+        let source = RepositorySource::Erik {
+            server: server.clone(),
+            fqdn,
+        };
+        pool.add(source, mapper.clone(), refresh)?;
+    }
+    Ok(pool)
+}
+
+/// Applies the configuration again, as far as that can be done
+/// without a restart.
+struct Reloader {
+    args: ConfigArgs,
+    config: Config,
+    certificate: Option<Arc<ServerCertificate>>,
+    store: Option<Arc<Mutex<RepositoryStore>>>,
+    pool: Arc<Mutex<RepositoryPool>>,
+}
+
+impl Reloader {
+    /// Reloads the TLS certificate and the configuration. Whatever
+    /// cannot be loaded is kept as it was.
+    fn reload(&mut self) {
+        if let Some(certificate) = &self.certificate
+            && let Err(e) = certificate.reload()
+        {
+            warn!("Cannot reload TLS certificate, keeping the current one: {e:#}");
+        }
+        if let Err(e) = self.reload_config() {
+            warn!("Cannot reload the configuration: {e:#}");
+        }
+    }
+
+    /// Applies the log level, the manifest policy and the changes to
+    /// the configured repositories. Other changes need a restart.
+    fn reload_config(&mut self) -> anyhow::Result<()> {
+        let config = config::post_configure(self.args.clone())?;
+        config::set_log_level(config.log_level);
+        {
+            let mut store = self.store.as_ref().map(|store| store.lock().unwrap());
+            let mut pool = self.pool.lock().unwrap();
+            pool.set_manifest_policy(config.manifest_policy);
+            if let Some(store) = store.as_mut() {
+                store.apply_config_change(
+                    &mut pool,
+                    &self.config.repositories,
+                    &config.repositories,
+                )?;
+            }
+        }
+        if self.config.needs_restart(&config) {
+            warn!("Some configuration changes only take effect after a restart");
+        }
+        info!("Reloaded the configuration");
+        self.config = config;
+        Ok(())
+    }
+}
+
+/// Reloads on SIGHUP, and requests shutdown on SIGTERM or SIGINT.
+/// Reloads run on a blocking task so that a reload waiting for the pool
+/// never holds up a shutdown.
+fn handle_signals(reloader: Reloader, shutdown: watch::Sender<bool>) -> anyhow::Result<()> {
+    let reloader = Arc::new(Mutex::new(reloader));
+    let mut hangups = signal(SignalKind::hangup()).context("Cannot handle SIGHUP")?;
+    let mut terminates = signal(SignalKind::terminate()).context("Cannot handle SIGTERM")?;
+    let mut interrupts = signal(SignalKind::interrupt()).context("Cannot handle SIGINT")?;
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = hangups.recv() => {
+                    info!("Reloading the configuration");
+                    let reloader = reloader.clone();
+                    tokio::task::spawn_blocking(move || reloader.lock().unwrap().reload());
+                }
+                _ = terminates.recv() => break,
+                _ = interrupts.recv() => break,
+            }
+        }
+        info!("Shutting down");
+        shutdown.send_replace(true);
+    });
+    Ok(())
+}
+
+fn main() {
+    let runtime = match tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            println!("Error: Cannot start the runtime: {e}");
+            exit(1)
+        }
+    };
+    let result = runtime.block_on(run());
+    // Blocking tasks such as fetches may still be running, but they
+    // must not keep the process from exiting.
+    runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+    if let Err(e) = result {
         println!("Error: {e}");
         exit(1)
     }
 }
 
 async fn run() -> anyhow::Result<()> {
-    let repo = Arc::new(RepoContent::create_test()?);
+    let opts = Opt::from_args();
+    if let Some(Command::Admin(admin)) = opts.command {
+        return tokio::task::spawn_blocking(move || run_admin(admin)).await?;
+    }
+
+    let args = opts.config.clone();
+    let config = config::configure(opts.config)?;
+    let loaded_config = config.clone();
+    retrieval::set_fetch_mode(config.fetch_mode)?;
+    retrieval::set_root_certificates(&config.ca_certs)?;
+    let erik_media_type = check_media_type(&config.erik_media_type)?.to_string();
+
+    let proxy_index_ttl = match (&config.upstream, &config.proxy_cache_dir) {
+        (Some(_), Some(_)) => Some(config.proxy_index_ttl),
+        _ => None,
+    };
+
+    let certificate = match (&config.tls_cert_file, &config.tls_key_file) {
+        (Some(cert_file), Some(key_file)) if !config.tls_listen.is_empty() => Some(
+            ServerCertificate::load(cert_file.clone(), key_file.clone())?,
+        ),
+        _ => None,
+    };
+
+    let admin_token = match &config.admin_token_file {
+        Some(_) if config.upstream.is_some() => {
+            return Err(anyhow!(
+                "The admin API manages RRDP repositories, it cannot be used with an upstream"
+            ));
+        }
+        Some(path) => Some(admin::read_token(path)?),
+        None => None,
+    };
+
+    let mut proxy = None;
+    let mut store = None;
+    let mut pool = match (config.upstream, config.proxy_cache_dir) {
+        (Some(upstream), Some(cache_dir)) => {
+            let mapper = upstream_mapper(&upstream, config.upstream_http);
+            let config = ErikProxyConfig {
+                upstream,
+                cache_dir,
+                max_cache_size: config.proxy_cache_size * 1024 * 1024,
+                index_ttl: config.proxy_index_ttl,
+            };
+            proxy = Some(Arc::new(ErikProxy::create(config, mapper)?));
+            RepositoryPool::empty()
+        }
+        (Some(server), None) => create_upstream_pool(
+            server,
+            config.upstream_fqdns,
+            config.upstream_http,
+            config.upstream_refresh,
+        )?,
+        (None, Some(_)) => return Err(anyhow!("Configure upstream to set the relay to proxy")),
+        (None, None) => {
+            let loaded =
+                RepositoryStore::load(config.repositories_file, config.repositories.clone())?;
+            let pool = loaded.create_pool()?;
+            store = Some(loaded);
+            pool
+        }
+    };
+    pool.set_manifest_policy(config.manifest_policy);
+    if let Some(publication_dir) = config.publication_dir {
+        let config = PublicationConfig {
+            service_uri: config.publication_service_uri,
+            sia_base: config.publication_sia_base,
+            rrdp_notification_uri: config.publication_rrdp_uri,
+        };
+        pool.add_publication(PublicationServer::create(publication_dir, config)?)?;
+    }
+
+    let rrdp_dir = config.rrdp_dir.clone();
+    let mut outputs = Outputs::default();
+    if let Some(rrdp_dir) = config.rrdp_dir {
+        outputs.rrdp = Some(RrdpOutput::create(RrdpOutputConfig {
+            rrdp_dir,
+            state_path: config.rrdp_state,
+            base_uri: config.rrdp_base_uri,
+            max_deltas: DEFAULT_MAX_DELTAS,
+            cleanup_after: DEFAULT_CLEANUP_AFTER,
+        })?);
+    }
+    if let Some(rsync_dir) = config.rsync_dir {
+        outputs.rsync = Some(RsyncOutput::new(RsyncOutputConfig::new(
+            rsync_dir,
+            config.rsync_include_host,
+        )));
+    }
+
+    let discovery = (!config.tal_files.is_empty()).then(|| {
+        Arc::new(Mutex::new(Discovery::new(
+            config.tal_files,
+            FetchMapper::empty(),
+            config.discovery,
+            DEFAULT_REFRESH_SECONDS,
+        )))
+    });
+
+    let pool = Arc::new(Mutex::new(pool));
+    let content: SharedContent = Arc::new(RwLock::new(Arc::new(RelayContent::empty())));
+
+    let pool_status = Arc::new(RwLock::new(PoolStatus::default()));
+    let metrics = Arc::new(Metrics::default());
+
+    // The update task holds the only sender of index updates, so that
+    // streams of events end when it stops.
+    let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+    let weak_events = events.downgrade();
+    let (shutdown, _) = watch::channel(false);
+    let updates = tokio::spawn(update_content(
+        pool.clone(),
+        content.clone(),
+        pool_status.clone(),
+        Arc::new(Mutex::new(outputs)),
+        discovery,
+        events,
+        shutdown.subscribe(),
+    ));
+
+    let is_proxy = proxy.is_some();
+    let status_content = content.clone();
+    let status_pool = pool_status.clone();
+    let get_status = async move || {
+        let pool_status = status_pool.read().unwrap().clone();
+        let status = relay_status(is_proxy, &pool_status, &current(&status_content));
+        let code = if status.ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        (code, Json(status))
+    };
+
+    let metrics_content = content.clone();
+    let metrics_status = pool_status.clone();
+    let render_metrics = metrics.clone();
+    let get_metrics = async move || {
+        let status = metrics_status.read().unwrap().clone();
+        let rendered = render_metrics.render(&status, &current(&metrics_content));
+        ([(header::CONTENT_TYPE, metrics::CONTENT_TYPE)], rendered)
+    };
+
+    let all_events = weak_events.clone();
+    let index_events = async move || {
+        debug!("GET events for all scopes");
+        index_event_stream(subscribe(&all_events), None)
+    };
+    let scope_events = async move |Path(fqdn): Path<String>| {
+        let Ok(fqdn) = fqdn.parse::<Fqdn>();
+        debug!("GET events for {fqdn}");
+        index_event_stream(subscribe(&weak_events), Some(fqdn))
+    };
 
-    let named_information = async move |Path((alg, val)): Path<(String, String)>| {
-        if alg != "sha-256" {
+    let rrdp_file = async move |Path(path): Path<String>| {
+        let Some(rrdp_dir) = rrdp_dir else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        if path.split('/').any(|part| part.is_empty() || part == "..") {
+            return (StatusCode::BAD_REQUEST, format!("invalid path: {path}")).into_response();
+        }
+
+        debug!("GET RRDP file {path}");
+        match tokio::fs::read(rrdp_dir.join(&path)).await {
+            Ok(data) => xml(data).into_response(),
+            Err(_) => (StatusCode::NOT_FOUND, format!("no such file: {path}")).into_response(),
+        }
+    };
+
+    let publish_pool = pool.clone();
+    let publish = async move |Path(publisher): Path<String>, body: Bytes| {
+        debug!("POST publication query for {publisher}");
+        let pool = publish_pool.clone();
+        let handle = publisher.clone();
+        let reply = tokio::task::spawn_blocking(move || {
+            let mut pool = pool.lock().unwrap();
+            pool.publication_mut()
+                .map(|server| server.process(&handle, &body))
+        })
+        .await;
+
+        match reply {
+            Ok(Some(Ok(Some(reply)))) => publication_reply(reply).into_response(),
+            Ok(Some(Ok(None))) => unknown_publisher(&publisher).into_response(),
+            Ok(Some(Err(e))) => {
+                // The details are for us, not for the publisher.
+                warn!("Cannot process publication query for {publisher}: {e:#}");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+            Ok(None) => StatusCode::NOT_FOUND.into_response(),
+            Err(e) => {
+                warn!("Publication task failed: {e}");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    };
+
+    let index_content = content.clone();
+    let index_status = pool_status.clone();
+    let index_proxy = proxy.clone();
+    let index_media_type = erik_media_type.clone();
+    let erik_index = async move |Path(fqdn): Path<String>, headers: HeaderMap| {
+        let Ok(fqdn) = fqdn.parse::<Fqdn>();
+        debug!("GET index for {fqdn}");
+
+        let media_type = ObjectKind::ErikIndex.media_type(&index_media_type);
+        let index_max_age = index_max_age(proxy_index_ttl, &index_status.read().unwrap());
+        if let Some(bytes) = current(&index_content).index(&fqdn) {
+            return index_response(&headers, bytes.clone(), index_max_age, media_type, true);
+        }
+        let Some(proxy) = index_proxy.clone() else {
+            return no_index(fqdn).into_response();
+        };
+        let proxied = tokio::task::spawn_blocking(move || {
+            let bytes = proxy.index(&fqdn);
+            (fqdn, bytes)
+        })
+        .await;
+        match proxied {
+            Ok((_, Ok(bytes))) => index_response(&headers, bytes, index_max_age, media_type, false),
+            Ok((fqdn, Err(e))) => {
+                debug!("Cannot proxy index for {fqdn}: {e:#}");
+                no_index(fqdn).into_response()
+            }
+            Err(e) => {
+                warn!("Proxy task failed: {e}");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    };
+
+    let batch_content = content.clone();
+    let batch_proxy = proxy.clone();
+    let batch_metrics = metrics.clone();
+    let batch_objects = async move |body: Bytes| {
+        let hashes = match batch::decode_request(&body) {
+            Ok(hashes) => hashes,
+            Err(e) => return (StatusCode::BAD_REQUEST, format!("{e:#}")).into_response(),
+        };
+        debug!("POST batch of {} objects", hashes.len());
+
+        let content = current(&batch_content);
+        let mut found: Vec<(Hash, Option<Bytes>)> = hashes
+            .into_iter()
+            .map(|hash| (hash, content.object(&hash).cloned()))
+            .collect();
+
+        if let Some(proxy) = batch_proxy.clone()
+            && found.iter().any(|(_, data)| data.is_none())
+        {
+            let proxied = tokio::task::spawn_blocking(move || {
+                found
+                    .into_iter()
+                    .map(|(hash, data)| (hash, data.or_else(|| proxy.object(hash).ok())))
+                    .collect()
+            })
+            .await;
+            match proxied {
+                Ok(proxied) => found = proxied,
+                Err(e) => {
+                    warn!("Proxy task failed: {e}");
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+            }
+        }
+
+        let served = found.iter().flat_map(|(_, data)| data.as_ref());
+        batch_metrics.record_served(
+            served.clone().count() as u64,
+            served.map(|data| data.len() as u64).sum(),
+        );
+
+        let entries: anyhow::Result<Vec<_>> = found
+            .iter()
+            .map(|(hash, data)| batch::encode_entry(hash, data.as_ref()))
+            .collect();
+        let entries = match entries {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Cannot encode batch response: {e:#}");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+
+        // The objects are sent as they are kept, not copied into
+        // one body.
+        let chunks = entries
+            .into_iter()
+            .flat_map(|(head, data)| iter::once(head).chain(data))
+            .map(Ok::<_, Infallible>);
+        (
+            [(header::CONTENT_TYPE, batch::CONTENT_TYPE)],
+            Body::from_stream(tokio_stream::iter(chunks)),
+        )
+            .into_response()
+    };
+
+    let bundle_content = content.clone();
+    let bundle_metrics = metrics.clone();
+    let partition_bundle = async move |Path(val): Path<String>, headers: HeaderMap| {
+        let Some(hash) = URL_SAFE_NO_PAD
+            .decode(val.as_bytes())
+            .ok()
+            .and_then(|h| Hash::try_from(h.as_slice()).ok())
+        else {
+            return bad_hash(val).into_response();
+        };
+        debug!("GET bundle for partition {hash}");
+
+        let etag = hash_etag(&hash);
+        if etag_matches(&headers, &etag) {
             return (
-                StatusCode::BAD_REQUEST,
-                "unsupported hashing algorithm: {alg}",
+                StatusCode::NOT_MODIFIED,
+                [
+                    (header::ETAG, etag),
+                    (header::CACHE_CONTROL, OBJECT_CACHE_CONTROL.to_string()),
+                ],
             )
                 .into_response();
         }
-        match URL_SAFE_NO_PAD.decode(val.as_bytes()) {
-            Ok(h) if h.len() == 32 => {
-                if let Ok(hash) = Hash::try_from(h.as_slice()) {
-                    debug!("GET {hash}");
 
-                    let r = Arc::clone(&repo);
-                    let objects = r.elements();
-                    match objects.get(&hash) {
-                        Some(obj) => der(obj.data().to_vec()).into_response(),
-                        None => not_found(hash).into_response(),
-                    }
-                } else {
-                    bad_hash(val).into_response()
-                }
+        let content = current(&bundle_content);
+        let bundled = tokio::task::spawn_blocking(move || {
+            content.bundle(&hash).map(|objects| {
+                let size = objects.iter().map(|(_, data)| data.len() as u64).sum();
+                let bundle = bundle::write_bundle(objects.iter().map(|(h, d)| (h, d)));
+                (objects.len() as u64, size, bundle)
+            })
+        })
+        .await;
+        match bundled {
+            Ok(Some((count, size, Ok(data)))) => {
+                bundle_metrics.record_served(count, size);
+                (
+                    StatusCode::OK,
+                    [
+                        (header::CONTENT_TYPE, bundle::CONTENT_TYPE.to_string()),
+                        (header::ETAG, etag),
+                        (header::CACHE_CONTROL, OBJECT_CACHE_CONTROL.to_string()),
+                    ],
+                    data,
+                )
+                    .into_response()
+            }
+            Ok(None) => not_found(hash).into_response(),
+            Ok(Some((_, _, Err(e)))) => {
+                warn!("Cannot create bundle for {hash}: {e:#}");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+            Err(e) => {
+                warn!("Bundle task failed: {e}");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    };
+
+    let api = api_routes(content.clone(), erik_media_type.clone());
+    let store = store.map(|store| Arc::new(Mutex::new(store)));
+    let admin = match (admin_token, &store) {
+        (Some(token), Some(store)) => admin_routes(store.clone(), pool.clone(), token),
+        _ => Router::new(),
+    };
+    let dashboard = dashboard_routes(
+        content.clone(),
+        pool_status.clone(),
+        is_proxy,
+        erik_media_type.clone(),
+    );
+
+    let ni_metrics = metrics.clone();
+    let named_information = async move |Path((alg, val)): Path<(String, String)>,
+                                        Query(query): Query<NiQuery>,
+                                        headers: HeaderMap| {
+        let ni = match NamedInformation::from_well_known(&alg, &val, query.ct.as_deref()) {
+            Ok(ni) => ni,
+            Err(e) => return (StatusCode::BAD_REQUEST, format!("{e:#}")).into_response(),
+        };
+        let hash = ni.hash();
+        debug!("GET {ni}");
+
+        let content = current(&content);
+        if let Some(data) = content.object(&hash) {
+            let kind = content.object_kind(&hash).unwrap_or(ObjectKind::Unknown);
+            return object_response(
+                &headers,
+                &ni,
+                kind,
+                &erik_media_type,
+                data.clone(),
+                &ni_metrics,
+            );
+        }
+        let Some(proxy) = proxy.clone() else {
+            return not_found(hash).into_response();
+        };
+        let proxied = tokio::task::spawn_blocking(move || {
+            proxy
+                .object(hash)
+                .map(|data| (ObjectKind::from_content(&data), data))
+        })
+        .await;
+        match proxied {
+            Ok(Ok((kind, data))) => {
+                object_response(&headers, &ni, kind, &erik_media_type, data, &ni_metrics)
+            }
+            Ok(Err(e)) => {
+                debug!("Cannot proxy {hash}: {e:#}");
+                not_found(hash).into_response()
+            }
+            Err(e) => {
+                warn!("Proxy task failed: {e}");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
             }
-            _ => bad_hash(val).into_response(),
         }
     };
 
     let app = Router::new()
-        .route("/", get(|| async { "EPIC: Erik Protocol Implementation Concept" }))
-        .route("/.well-known/ni/{alg}/{val}", get(named_information));
+        .route("/.well-known/erik/index/{fqdn}", get(erik_index))
+        .route("/events", get(index_events))
+        .route("/events/{fqdn}", get(scope_events))
+        .route(
+            &format!("/{}/{{alg}}/{{val}}", ni::WELL_KNOWN_PATH),
+            get(named_information),
+        )
+        .route(&format!("/{}", batch::PATH), post(batch_objects))
+        .route(
+            &format!("/{}/{{hash}}", bundle::PATH),
+            get(partition_bundle),
+        )
+        .route("/rfc8181/{publisher}", post(publish))
+        .route("/rrdp/{*path}", get(rrdp_file))
+        .route("/metrics", get(get_metrics))
+        .route("/status", get(get_status))
+        .merge(api)
+        .merge(dashboard)
+        .merge(admin)
+        .route_layer(middleware::from_fn_with_state(metrics, track_requests));
+
+    let mut servers = tokio::task::JoinSet::new();
+    for address in config.listen {
+        let listener = tokio::net::TcpListener::bind(&address)
+            .await
+            .with_context(|| format!("Cannot listen on {address}"))?;
+        info!("Listening on {address}");
+        let server = axum::serve(listener, app.clone())
+            .with_graceful_shutdown(shutdown_requested(shutdown.subscribe()));
+        servers.spawn(server.into_future());
+    }
+    if let Some(certificate) = certificate.as_ref() {
+        let tls_config = certificate.server_config()?;
+        for address in config.tls_listen {
+            let listener = tokio::net::TcpListener::bind(&address)
+                .await
+                .with_context(|| format!("Cannot listen on {address}"))?;
+            info!("Listening for HTTPS on {address}");
+            let listener = TlsListener::new(listener, tls_config.clone())?;
+            let server = axum::serve(listener, app.clone())
+                .with_graceful_shutdown(shutdown_requested(shutdown.subscribe()));
+            servers.spawn(server.into_future());
+        }
+    }
 
-    let listener = tokio::net::TcpListener::bind("[::]:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    let reloader = Reloader {
+        args,
+        config: loaded_config,
+        certificate,
+        store: store.clone(),
+        pool,
+    };
+    handle_signals(reloader, shutdown.clone())?;
+
+    // Requests in progress may finish, but not forever.
+    let drained = async move {
+        while let Some(served) = servers.join_next().await {
+            served??;
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+    let deadline = async {
+        shutdown_requested(shutdown.subscribe()).await;
+        tokio::time::sleep(SHUTDOWN_TIMEOUT).await;
+    };
+    tokio::select! {
+        drained = drained => drained?,
+        _ = deadline => warn!("Stopped waiting for connections to close"),
+    }
 
-    debug!("# Server Logs");
+    match tokio::time::timeout(SHUTDOWN_TIMEOUT, updates).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => warn!("Repository update task failed: {e}"),
+        Err(_) => warn!("Stopped waiting for repository updates to finish"),
+    }
+    info!("Stopped");
+    log::logger().flush();
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn if_none_match(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(header::IF_NONE_MATCH, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn etags() {
+        let etag = hash_etag(&Hash::from_data(b"index"));
+        let other = hash_etag(&Hash::from_data(b"other"));
+
+        assert!(!etag_matches(&HeaderMap::new(), &etag));
+        assert!(etag_matches(&if_none_match(&["*"]), &etag));
+
+        let headers = if_none_match(&[&format!("{other}, W/{etag}")]);
+        assert!(etag_matches(&headers, &etag));
+        assert!(!etag_matches(&headers, "\"elsewhere\""));
+
+        assert!(etag_matches(&if_none_match(&[&other, &etag]), &etag));
+        assert!(!etag_matches(&if_none_match(&[&other]), &etag));
+
+        // Only the client's copy is not modified.
+        let data = Bytes::from_static(b"index");
+        let cached = cached_der(&headers, data.clone(), etag.clone(), "no-cache", "a/b");
+        assert_eq!(StatusCode::NOT_MODIFIED, cached.status());
+        assert_eq!(etag, cached.headers()[header::ETAG]);
+        assert!(cached.headers().get(header::CONTENT_TYPE).is_none());
+
+        let fresh = cached_der(&HeaderMap::new(), data, etag, "no-cache", "a/b");
+        assert_eq!(StatusCode::OK, fresh.status());
+        assert_eq!("no-cache", fresh.headers()[header::CACHE_CONTROL]);
+        assert_eq!("a/b", fresh.headers()[header::CONTENT_TYPE]);
+    }
+
+    #[test]
+    fn index_links() {
+        let data = Bytes::from_static(b"index");
+        let links = |bundles| {
+            let response = index_response(&HeaderMap::new(), data.clone(), 30, "a/b", bundles);
+            response.headers()[header::LINK]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+
+        let served = links(true);
+        assert!(served.contains(batch::LINK_REL));
+        assert!(served.contains(bundle::LINK_REL));
+
+        // A proxy cannot serve bundles, so it does not offer them.
+        let proxied = links(false);
+        assert!(proxied.contains(batch::LINK_REL));
+        assert!(!proxied.contains(bundle::LINK_REL));
+    }
+
+    #[test]
+    fn object_content_type() {
+        let data = Bytes::from_static(b"<script>");
+        let hash = Hash::from_data(&data);
+        let value = URL_SAFE_NO_PAD.encode(hash.as_slice());
+        let ni = NamedInformation::from_well_known("sha-256", &value, Some("text/html")).unwrap();
+        let metrics = Metrics::default();
+
+        // The hint in the name is not sent back.
+        let kind = ObjectKind::Unknown;
+        let response = object_response(&HeaderMap::new(), &ni, kind, "a/b", data.clone(), &metrics);
+        assert_eq!(
+            "application/octet-stream",
+            response.headers()[header::CONTENT_TYPE]
+        );
+        assert_eq!(
+            "nosniff",
+            response.headers()[header::X_CONTENT_TYPE_OPTIONS]
+        );
+
+        let kind = ObjectKind::ErikPartition;
+        let response = object_response(&HeaderMap::new(), &ni, kind, "a/b", data, &metrics);
+        assert_eq!("a/b", response.headers()[header::CONTENT_TYPE]);
+    }
+
+    #[test]
+    fn index_max_age_follows_refresh() {
+        let mut status = PoolStatus::default();
+        assert_eq!(DEFAULT_REFRESH_SECONDS / 2, index_max_age(None, &status));
+        status.min_refresh = Some(10);
+        assert_eq!(5, index_max_age(None, &status));
+        status.min_refresh = Some(1);
+        assert_eq!(1, index_max_age(None, &status));
+        assert_eq!(3, index_max_age(Some(3), &status));
+    }
+}
@@ -1,14 +1,27 @@
 // Get ErikIndex or Partition files and dump them as somewhat readable JSON.
 
-use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use std::{path::PathBuf, thread, time::Duration};
+
+use anyhow::anyhow;
 use rpki::{rrdp, uri};
 use structopt::StructOpt;
 
 use epic::{
-    erik::asn1::{ErikIndex, ErikPartition},
-    fetch::retrieval::{FetchMapper, Fqdn},
+    erik::{
+        asn1::{ErikIndex, ErikPartition},
+        ni::NamedInformation,
+        relay::IndexEvent,
+    },
+    fetch::{
+        erik::ErikSyncState,
+        retrieval::{self, FetchMapper, FetchMode, Fqdn},
+    },
+    output::rsync::{RsyncOutput, RsyncOutputConfig},
 };
 
+/// How long to wait before reconnecting to the events stream.
+const WATCH_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
 fn main() {
     if let Err(e) = try_main() {
         eprintln!("Error: {e}");
@@ -18,19 +31,63 @@ fn main() {
 
 fn try_main() -> Result<(), anyhow::Error> {
     let opts = Opt::from_args();
+    if opts.insecure {
+        retrieval::set_fetch_mode(FetchMode::Insecure)?;
+    }
+    retrieval::set_root_certificates(&opts.ca_certs)?;
+
+    let mut fetch_mapper = FetchMapper::empty();
+    if opts.http {
+        fetch_mapper.add_http_mapper(
+            (&opts.server).into(),
+            format!("http://{}", opts.server.authority()),
+        );
+    }
+
+    if let Mode::Watch {
+        rsync_dir,
+        rsync_include_host,
+    } = opts.mode
+    {
+        let output =
+            rsync_dir.map(|dir| RsyncOutput::new(RsyncOutputConfig::new(dir, rsync_include_host)));
+        return watch(opts.server, opts.fqdn, fetch_mapper, output);
+    }
 
-    let fetch_mapper = FetchMapper::empty();
-    let uri = match opts.mode {
+    if let Mode::Sync {
+        rsync_dir,
+        rsync_include_host,
+    } = opts.mode
+    {
+        let state = ErikSyncState::create(opts.server, opts.fqdn, fetch_mapper)?;
+        let output = RsyncOutput::new(RsyncOutputConfig::new(rsync_dir, rsync_include_host));
+        output.write(&state.objects())?;
+        println!(
+            "Wrote {} objects to {}",
+            state.elements().len(),
+            output.config().current().display()
+        );
+        return Ok(());
+    }
+
+    let uri = match &opts.mode {
         Mode::Index => opts
             .server
             .join(".well-known/erik/index/".as_ref())?
             .join(opts.fqdn.as_bytes())?,
-        Mode::Partition { hash } => {
-            let base64_hash = URL_SAFE_NO_PAD.encode(hash.as_slice());
-            opts.server
-                .join(".well-known/ni/sha-256/".as_ref())?
-                .join(base64_hash.as_bytes())?
+        Mode::Partition { hash, ni } => {
+            let ni = match (hash, ni) {
+                (_, Some(ni)) => ni.clone(),
+                (Some(hash), None) => NamedInformation::new(*hash),
+                (None, None) => return Err(anyhow!("Either --hash or --ni is required")),
+            };
+            // A name with an authority says where to get it.
+            match ni.authority_uri() {
+                Some(uri) => uri?,
+                None => ni.well_known_uri(&opts.server)?,
+            }
         }
+        Mode::Sync { .. } | Mode::Watch { .. } => unreachable!("handled above"),
     };
 
     let output = match opts.mode {
@@ -46,6 +103,7 @@ fn try_main() -> Result<(), anyhow::Error> {
 
             serde_json::to_string_pretty(&partition)?
         }
+        Mode::Sync { .. } | Mode::Watch { .. } => unreachable!("handled above"),
     };
 
     println!("{output}");
@@ -53,6 +111,74 @@ fn try_main() -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+/// Synchronises, and then follows the events stream of the relay to
+/// synchronise again as soon as the index changes. If the stream is
+/// lost, we reconnect and synchronise to catch up. Failures are
+/// retried, so this keeps running until it is stopped.
+fn watch(
+    server: uri::Https,
+    fqdn: Fqdn,
+    fetch_mapper: FetchMapper,
+    output: Option<RsyncOutput>,
+) -> anyhow::Result<()> {
+    let mut state = loop {
+        match ErikSyncState::create(server.clone(), fqdn.clone(), fetch_mapper.clone()) {
+            Ok(state) => break state,
+            Err(e) => eprintln!("Cannot synchronise: {e:#}"),
+        }
+        thread::sleep(WATCH_RECONNECT_DELAY);
+    };
+    let client = state.client().clone();
+    let fqdn = state.fqdn().clone();
+
+    // Returns whether the output is up to date. A failed write is
+    // tried again after the next update, even if nothing changed.
+    let synced = |state: &ErikSyncState| {
+        println!("Synchronised {} objects", state.elements().len());
+        match output.as_ref().map(|output| output.write(&state.objects())) {
+            Some(Err(e)) => {
+                eprintln!("Cannot write rsync tree: {e:#}");
+                false
+            }
+            _ => true,
+        }
+    };
+    let mut written = synced(&state);
+
+    loop {
+        let followed = client.follow_events(&fqdn, |event| {
+            match event {
+                IndexEvent::Update(update) => println!(
+                    "New index {} at {}, changed partitions: {:?}",
+                    update.hash,
+                    update.index_time.to_rfc3339(),
+                    update.changed_partitions
+                ),
+                IndexEvent::Removal(removal) => {
+                    // Keep what we have, the index may come back.
+                    println!("Index for {} was removed", removal.fqdn);
+                    return Ok(());
+                }
+            }
+            if state.update()? || !written {
+                written = synced(&state);
+            }
+            Ok(())
+        });
+        match followed {
+            Ok(()) => eprintln!("Events stream ended, reconnecting"),
+            Err(e) => eprintln!("Events stream failed: {e:#}"),
+        }
+
+        thread::sleep(WATCH_RECONNECT_DELAY);
+        match state.update() {
+            Ok(changed) if changed || !written => written = synced(&state),
+            Ok(_) => {}
+            Err(e) => eprintln!("Cannot synchronise: {e:#}"),
+        }
+    }
+}
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "basic")]
 struct Opt {
@@ -62,6 +188,21 @@ struct Opt {
     #[structopt(short, long)]
     fqdn: Fqdn,
 
+    /// Use plain HTTP to reach the server. Meant for testing with a
+    /// relay on localhost.
+    #[structopt(long)]
+    http: bool,
+
+    /// Accept any TLS certificate from the server. Meant for testing
+    /// with a relay that uses a self-signed certificate.
+    #[structopt(long)]
+    insecure: bool,
+
+    /// Also trust the CA certificates in this PEM file, e.g. a local
+    /// test CA. Can be given more than once.
+    #[structopt(long = "ca-cert", number_of_values = 1)]
+    ca_certs: Vec<PathBuf>,
+
     #[structopt(subcommand)] // Note that we mark a field as a subcommand
     mode: Mode,
 }
@@ -70,7 +211,32 @@ struct Opt {
 enum Mode {
     Index,
     Partition {
-        #[structopt(short, long)]
-        hash: rrdp::Hash,
+        #[structopt(short, long, required_unless = "ni", conflicts_with = "ni")]
+        hash: Option<rrdp::Hash>,
+
+        /// The ni URI of the partition, e.g. `ni:///sha-256;<b64url>`. If
+        /// it has an authority, the partition is fetched from there.
+        #[structopt(long)]
+        ni: Option<NamedInformation>,
+    },
+    /// Synchronise all objects for the FQDN and write them as an
+    /// rsync tree in `<rsync-dir>/current`.
+    Sync {
+        #[structopt(long, parse(from_os_str))]
+        rsync_dir: PathBuf,
+
+        /// Include the host in paths: `<rsync-dir>/current/<host>/<module>/..`
+        #[structopt(long)]
+        rsync_include_host: bool,
+    },
+    /// Synchronise, and synchronise again whenever the relay announces
+    /// a new index. Optionally write the objects as an rsync tree.
+    Watch {
+        #[structopt(long, parse(from_os_str))]
+        rsync_dir: Option<PathBuf>,
+
+        /// Include the host in paths: `<rsync-dir>/current/<host>/<module>/..`
+        #[structopt(long)]
+        rsync_include_host: bool,
     },
 }
@@ -0,0 +1,106 @@
+// Write a static Erik relay tree that can be served by any web server.
+
+use std::{path::PathBuf, thread, time::Duration};
+
+use anyhow::anyhow;
+use rpki::uri;
+use structopt::StructOpt;
+
+use epic::{
+    erik::relay::RelayContent,
+    fetch::{
+        pool::{DEFAULT_REFRESH_SECONDS, RepositoryPool, RepositorySource},
+        retrieval::FetchMapper,
+    },
+    output::erik::{ErikTreeConfig, ErikTreeOutput},
+};
+
+fn main() {
+    if let Err(e) = try_main() {
+        eprintln!("Error: {e}");
+        ::std::process::exit(1);
+    }
+}
+
+fn try_main() -> Result<(), anyhow::Error> {
+    let opts = Opt::from_args();
+
+    let mut pool = RepositoryPool::empty();
+    for notify in opts.rrdp {
+        pool.add(
+            RepositorySource::Rrdp(notify),
+            FetchMapper::empty(),
+            DEFAULT_REFRESH_SECONDS,
+        )?;
+    }
+    for dir in opts.source_dir {
+        pool.add(
+            RepositorySource::RsyncDir(dir),
+            FetchMapper::empty(),
+            DEFAULT_REFRESH_SECONDS,
+        )?;
+    }
+
+    let state_path = opts
+        .state
+        .unwrap_or_else(|| opts.output_dir.join(".erik-tree-state.json"));
+    let mut output = ErikTreeOutput::create(ErikTreeConfig {
+        base_dir: opts.output_dir,
+        state_path,
+        cleanup_after: opts.cleanup_after,
+    })?;
+
+    loop {
+        let written = pool.update_due() && output.update(&RelayContent::from_pool(&pool))?;
+        if written {
+            println!(
+                "Updated Erik tree in {}",
+                output.config().base_dir.display()
+            );
+        }
+
+        match opts.interval {
+            Some(seconds) => thread::sleep(Duration::from_secs(seconds)),
+            None if written => return Ok(()),
+            // Failed fetches are only logged, so this tells scripts
+            // that something may be wrong.
+            None => {
+                return Err(anyhow!(
+                    "Nothing was written to {}",
+                    output.config().base_dir.display()
+                ));
+            }
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "erik_tree")]
+struct Opt {
+    /// The RRDP notification URI of a repository to include.
+    #[structopt(long)]
+    rrdp: Vec<uri::Https>,
+
+    /// A local rsync-style directory tree to include.
+    #[structopt(long, parse(from_os_str))]
+    source_dir: Vec<PathBuf>,
+
+    /// The directory to write the tree to.
+    #[structopt(short, long, parse(from_os_str))]
+    output_dir: PathBuf,
+
+    /// The file where state is kept between runs. Defaults to a
+    /// hidden file in the output directory.
+    #[structopt(long, parse(from_os_str))]
+    state: Option<PathBuf>,
+
+    /// Seconds to keep unreferenced files before removing them.
+    #[structopt(long, default_value = "600")]
+    cleanup_after: i64,
+
+    /// Keep running, and check for updates every this many seconds.
+    /// Without this, the tree is updated once and we exit with an
+    /// error if nothing was written.
+    #[structopt(long)]
+    interval: Option<u64>,
+}
@@ -0,0 +1,785 @@
+//! The configuration of the epic server.
+//!
+//! The configuration is read from an optional TOML file, or a JSON file
+//! if its name ends in `.json`. Everything that is not in the file has
+//! a default, and options given on the command line override both.
+//!
+//! Relative paths of the files that epic keeps its own state in are
+//! resolved under the state directory.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::{Context, anyhow};
+use log::LevelFilter;
+use rpki::uri;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::{
+    admin::{self, RepositoryConfig},
+    erik::media_type::{DEFAULT_ERIK_MEDIA_TYPE, check_media_type},
+    fetch::{
+        discovery::DiscoveryLimits,
+        pool::ManifestPolicy,
+        retrieval::{FetchMode, Fqdn},
+    },
+    util,
+};
+
+/// The default address to listen on.
+pub const DEFAULT_LISTEN: &str = "[::]:3000";
+
+/// The default file to log to, if logging to a file.
+pub const DEFAULT_LOG_FILE: &str = "epic.log";
+
+/// The default file in which the state of the RRDP output is kept.
+pub const DEFAULT_RRDP_STATE_FILE: &str = "rrdp-state.json";
+
+/// Where log messages go.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTarget {
+    #[default]
+    Stderr,
+    Stdout,
+
+    /// The `log_file`.
+    File,
+
+    /// The local syslog daemon.
+    Syslog,
+}
+
+impl FromStr for LogTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stderr" => Ok(LogTarget::Stderr),
+            "stdout" => Ok(LogTarget::Stdout),
+            "file" => Ok(LogTarget::File),
+            "syslog" => Ok(LogTarget::Syslog),
+            _ => Err(anyhow!(
+                "invalid log target: {s}, expected stderr, stdout, file or syslog"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LogTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogTarget::Stderr => write!(f, "stderr"),
+            LogTarget::Stdout => write!(f, "stdout"),
+            LogTarget::File => write!(f, "file"),
+            LogTarget::Syslog => write!(f, "syslog"),
+        }
+    }
+}
+
+/// The configuration of the epic server.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The addresses to listen on for plain HTTP.
+    pub listen: Vec<String>,
+
+    /// The addresses to listen on for HTTPS.
+    pub tls_listen: Vec<String>,
+
+    /// The PEM file with the certificate chain for HTTPS, starting
+    /// with the server certificate. It is reloaded on SIGHUP.
+    pub tls_cert_file: Option<PathBuf>,
+
+    /// The PEM file with the private key for HTTPS.
+    pub tls_key_file: Option<PathBuf>,
+
+    /// The directory in which state files with a relative path are
+    /// kept.
+    pub state_dir: PathBuf,
+
+    /// The most detailed level logged for epic itself. Other crates
+    /// log at most warnings.
+    pub log_level: LevelFilter,
+
+    pub log_target: LogTarget,
+
+    /// The file to log to, if `log_target` is `file`.
+    pub log_file: PathBuf,
+
+    /// How TLS certificates are checked when fetching.
+    pub fetch_mode: FetchMode,
+
+    /// PEM files with CA certificates that are trusted when fetching,
+    /// besides those of the system.
+    pub ca_certs: Vec<PathBuf>,
+
+    /// The media type of Erik indexes and partitions.
+    pub erik_media_type: String,
+
+    /// The RRDP repositories to fetch when the repositories file does
+    /// not exist yet. Without any, and without `tal_files`, the test
+    /// repository is used.
+    pub repositories: Vec<RepositoryConfig>,
+
+    /// The file in which the RRDP repositories are kept, so that
+    /// changes made through the admin API persist.
+    pub repositories_file: PathBuf,
+
+    /// Also fetch the RRDP repositories that are found by crawling the
+    /// RPKI from the trust anchors in these TAL files.
+    pub tal_files: Vec<PathBuf>,
+
+    /// Limits to the repositories found from `tal_files`.
+    pub discovery: DiscoveryLimits,
+
+    /// Which manifests are used in the Erik indexes.
+    pub manifest_policy: ManifestPolicy,
+
+    /// Enable the admin API, with the bearer token in this file.
+    pub admin_token_file: Option<PathBuf>,
+
+    /// Mirror the content of this upstream Erik relay, instead of
+    /// fetching RRDP repositories. With `proxy_cache_dir` content is
+    /// proxied instead.
+    pub upstream: Option<uri::Https>,
+
+    /// The index scopes to mirror from the upstream relay.
+    pub upstream_fqdns: Vec<Fqdn>,
+
+    /// Reach the upstream relay over plain HTTP.
+    pub upstream_http: bool,
+
+    /// Seconds between polls of the upstream index.
+    pub upstream_refresh: i64,
+
+    /// Lazily proxy the upstream relay, keeping fetched objects in
+    /// this directory.
+    pub proxy_cache_dir: Option<PathBuf>,
+
+    /// The maximum size of the proxy cache, in MiB.
+    pub proxy_cache_size: u64,
+
+    /// Seconds to serve a proxied index before fetching it again.
+    pub proxy_index_ttl: i64,
+
+    /// Enable the RFC 8181 publication server, keeping its state in
+    /// this directory.
+    pub publication_dir: Option<PathBuf>,
+
+    /// The base URI of the RFC 8181 service, as told to publishers.
+    pub publication_service_uri: uri::Https,
+
+    /// The base rsync URI under which publishers get their own
+    /// directory.
+    pub publication_sia_base: uri::Rsync,
+
+    /// The RRDP notification URI to tell publishers about, if any.
+    pub publication_rrdp_uri: Option<uri::Https>,
+
+    /// Also re-publish all content as an RRDP repository in this
+    /// directory.
+    pub rrdp_dir: Option<PathBuf>,
+
+    /// The public base URI of the RRDP repository.
+    pub rrdp_base_uri: uri::Https,
+
+    /// The file where the state of the RRDP repository is kept.
+    pub rrdp_state: PathBuf,
+
+    /// Also write all content as an rsync tree in `<rsync_dir>/current`.
+    pub rsync_dir: Option<PathBuf>,
+
+    /// Include the host in rsync paths.
+    pub rsync_include_host: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: vec![DEFAULT_LISTEN.to_string()],
+            tls_listen: vec![],
+            tls_cert_file: None,
+            tls_key_file: None,
+            state_dir: PathBuf::from("."),
+            log_level: LevelFilter::Warn,
+            log_target: LogTarget::default(),
+            log_file: PathBuf::from(DEFAULT_LOG_FILE),
+            fetch_mode: FetchMode::default(),
+            ca_certs: vec![],
+            erik_media_type: DEFAULT_ERIK_MEDIA_TYPE.to_string(),
+            repositories: vec![],
+            repositories_file: PathBuf::from(admin::DEFAULT_STORE_FILE),
+            tal_files: vec![],
+            discovery: DiscoveryLimits::default(),
+            manifest_policy: ManifestPolicy::default(),
+            admin_token_file: None,
+            upstream: None,
+            upstream_fqdns: vec![],
+            upstream_http: false,
+            upstream_refresh: 60,
+            proxy_cache_dir: None,
+            proxy_cache_size: 256,
+            proxy_index_ttl: 10,
+            publication_dir: None,
+            publication_service_uri: uri::Https::from_str("https://localhost:3000/rfc8181/")
+                .unwrap(),
+            publication_sia_base: uri::Rsync::from_str("rsync://localhost/repo/").unwrap(),
+            publication_rrdp_uri: None,
+            rrdp_dir: None,
+            rrdp_base_uri: uri::Https::from_str("https://localhost:3000/rrdp/").unwrap(),
+            rrdp_state: PathBuf::from(DEFAULT_RRDP_STATE_FILE),
+            rsync_dir: None,
+            rsync_include_host: false,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the configuration from a TOML file, or a JSON file if its
+    /// extension is `json`.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let bytes = util::read_file(path)?;
+        let text = std::str::from_utf8(bytes.as_ref())
+            .with_context(|| format!("Config file {} is not UTF-8", path.display()))?;
+        if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(text)
+                .with_context(|| format!("Cannot parse config file {}", path.display()))
+        } else {
+            toml::from_str(text)
+                .with_context(|| format!("Cannot parse config file {}", path.display()))
+        }
+    }
+
+    /// Returns whether changing to the new configuration needs a
+    /// restart. Only the log level, the manifest policy and the
+    /// repositories are applied when the configuration is reloaded.
+    pub fn needs_restart(&self, new: &Config) -> bool {
+        let reloaded = Config {
+            log_level: self.log_level,
+            manifest_policy: self.manifest_policy,
+            repositories: self.repositories.clone(),
+            ..new.clone()
+        };
+        &reloaded != self
+    }
+
+    /// Returns an error if the configuration cannot work.
+    fn check(&self) -> anyhow::Result<()> {
+        if self.listen.is_empty() && self.tls_listen.is_empty() {
+            return Err(anyhow!("Configure at least one address to listen on"));
+        }
+        if !self.tls_listen.is_empty()
+            && (self.tls_cert_file.is_none() || self.tls_key_file.is_none())
+        {
+            return Err(anyhow!(
+                "Configure tls_cert_file and tls_key_file to listen for HTTPS"
+            ));
+        }
+        if self.upstream.is_some() && !self.tal_files.is_empty() {
+            return Err(anyhow!(
+                "Repositories are discovered from TAL files, this cannot be used with an upstream"
+            ));
+        }
+        check_media_type(&self.erik_media_type)?;
+        admin::RepositoryStore::check_refresh(self.upstream_refresh)
+            .context("Invalid upstream_refresh")?;
+        for repository in &self.repositories {
+            admin::RepositoryStore::check_refresh(repository.refresh)
+                .with_context(|| format!("Invalid repository {}", repository.notify))?;
+        }
+        Ok(())
+    }
+}
+
+/// The options of the epic server. These override the config file.
+#[derive(Clone, Debug, Default, StructOpt)]
+pub struct ConfigArgs {
+    /// Read the configuration from this TOML file, or JSON if its
+    /// name ends in `.json`.
+    #[structopt(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// An address to listen on for plain HTTP. Can be given more than
+    /// once.
+    #[structopt(long, number_of_values = 1)]
+    pub listen: Vec<String>,
+
+    /// An address to listen on for HTTPS. Can be given more than once.
+    #[structopt(long, number_of_values = 1)]
+    pub tls_listen: Vec<String>,
+
+    /// The PEM file with the certificate chain for HTTPS, starting with
+    /// the server certificate. It is reloaded on SIGHUP.
+    #[structopt(long)]
+    pub tls_cert_file: Option<PathBuf>,
+
+    /// The PEM file with the private key for HTTPS.
+    #[structopt(long)]
+    pub tls_key_file: Option<PathBuf>,
+
+    /// The directory in which state files with a relative path are
+    /// kept.
+    #[structopt(long)]
+    pub state_dir: Option<PathBuf>,
+
+    /// The most detailed level logged: off, error, warn, info, debug
+    /// or trace.
+    #[structopt(long)]
+    pub log_level: Option<LevelFilter>,
+
+    /// Where to log: stderr, stdout, file or syslog.
+    #[structopt(long)]
+    pub log_target: Option<LogTarget>,
+
+    /// The file to log to, with `--log-target file`.
+    #[structopt(long)]
+    pub log_file: Option<PathBuf>,
+
+    /// Accept any TLS certificate when fetching. Meant for testing
+    /// with servers that use self-signed certificates.
+    #[structopt(long)]
+    pub insecure: bool,
+
+    /// Also trust the CA certificates in this PEM file when fetching.
+    /// Can be given more than once.
+    #[structopt(long = "ca-cert", number_of_values = 1)]
+    pub ca_certs: Vec<PathBuf>,
+
+    /// The media type of Erik indexes and partitions.
+    #[structopt(long)]
+    pub erik_media_type: Option<String>,
+
+    /// The file in which the RRDP repositories to fetch are kept.
+    #[structopt(long)]
+    pub repositories: Option<PathBuf>,
+
+    /// Also fetch the RRDP repositories that are found by crawling the
+    /// RPKI from the trust anchor in this TAL file. Can be given more
+    /// than once.
+    #[structopt(long = "tal", number_of_values = 1)]
+    pub tal_files: Vec<PathBuf>,
+
+    /// Leave manifests that are past their next update out of the
+    /// indexes.
+    #[structopt(long)]
+    pub reject_stale_manifests: bool,
+
+    /// Leave manifests that do not strictly follow the RPKI profiles
+    /// out of the indexes.
+    #[structopt(long)]
+    pub strict_manifests: bool,
+
+    /// Enable the admin API, with the bearer token in this file.
+    #[structopt(long)]
+    pub admin_token_file: Option<PathBuf>,
+
+    /// Mirror the content of this upstream Erik relay, instead of
+    /// fetching RRDP repositories. With `--proxy-cache-dir` content
+    /// is proxied instead.
+    #[structopt(long)]
+    pub upstream: Option<uri::Https>,
+
+    /// The index scopes to mirror from the upstream relay.
+    #[structopt(long = "upstream-fqdn", number_of_values = 1)]
+    pub upstream_fqdns: Vec<Fqdn>,
+
+    /// Reach the upstream relay over plain HTTP. Meant for testing
+    /// with relays on localhost.
+    #[structopt(long)]
+    pub upstream_http: bool,
+
+    /// Seconds between polls of the upstream index.
+    #[structopt(long)]
+    pub upstream_refresh: Option<i64>,
+
+    /// Lazily proxy the upstream relay, keeping fetched objects in
+    /// this directory.
+    #[structopt(long)]
+    pub proxy_cache_dir: Option<PathBuf>,
+
+    /// The maximum size of the proxy cache, in MiB.
+    #[structopt(long)]
+    pub proxy_cache_size: Option<u64>,
+
+    /// Seconds to serve a proxied index before fetching it again.
+    #[structopt(long)]
+    pub proxy_index_ttl: Option<i64>,
+
+    /// Enable the RFC 8181 publication server, keeping its state in
+    /// this directory. Publishers are configured by placing their
+    /// RFC 8183 publisher request XML in its `publishers` directory.
+    #[structopt(long)]
+    pub publication_dir: Option<PathBuf>,
+
+    /// The base URI of the RFC 8181 service, as told to publishers.
+    #[structopt(long)]
+    pub publication_service_uri: Option<uri::Https>,
+
+    /// The base rsync URI under which publishers get their own
+    /// directory.
+    #[structopt(long)]
+    pub publication_sia_base: Option<uri::Rsync>,
+
+    /// The RRDP notification URI to tell publishers about, if any.
+    #[structopt(long)]
+    pub publication_rrdp_uri: Option<uri::Https>,
+
+    /// Also re-publish all content as an RRDP repository in this
+    /// directory. It is served under `/rrdp/`.
+    #[structopt(long)]
+    pub rrdp_dir: Option<PathBuf>,
+
+    /// The public base URI of the RRDP repository.
+    #[structopt(long)]
+    pub rrdp_base_uri: Option<uri::Https>,
+
+    /// The file where the state of the RRDP repository is kept.
+    #[structopt(long)]
+    pub rrdp_state: Option<PathBuf>,
+
+    /// Also write all content as an rsync tree in `<rsync-dir>/current`.
+    #[structopt(long)]
+    pub rsync_dir: Option<PathBuf>,
+
+    /// Include the host in rsync paths: `<rsync-dir>/current/<host>/<module>/..`
+    #[structopt(long)]
+    pub rsync_include_host: bool,
+}
+
+impl ConfigArgs {
+    /// Overrides the configuration with the options that were given.
+    fn apply(self, config: &mut Config) {
+        fn set<T>(field: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+        fn set_some<T>(field: &mut Option<T>, value: Option<T>) {
+            if value.is_some() {
+                *field = value;
+            }
+        }
+
+        if !self.listen.is_empty() {
+            config.listen = self.listen;
+        }
+        if !self.tls_listen.is_empty() {
+            config.tls_listen = self.tls_listen;
+        }
+        set_some(&mut config.tls_cert_file, self.tls_cert_file);
+        set_some(&mut config.tls_key_file, self.tls_key_file);
+        set(&mut config.state_dir, self.state_dir);
+        set(&mut config.log_level, self.log_level);
+        set(&mut config.log_target, self.log_target);
+        set(&mut config.log_file, self.log_file);
+        if self.insecure {
+            config.fetch_mode = FetchMode::Insecure;
+        }
+        if !self.ca_certs.is_empty() {
+            config.ca_certs = self.ca_certs;
+        }
+        set(&mut config.erik_media_type, self.erik_media_type);
+        set(&mut config.repositories_file, self.repositories);
+        if !self.tal_files.is_empty() {
+            config.tal_files = self.tal_files;
+        }
+        config.manifest_policy.reject_stale |= self.reject_stale_manifests;
+        config.manifest_policy.strict |= self.strict_manifests;
+        set_some(&mut config.admin_token_file, self.admin_token_file);
+        set_some(&mut config.upstream, self.upstream);
+        if !self.upstream_fqdns.is_empty() {
+            config.upstream_fqdns = self.upstream_fqdns;
+        }
+        config.upstream_http |= self.upstream_http;
+        set(&mut config.upstream_refresh, self.upstream_refresh);
+        set_some(&mut config.proxy_cache_dir, self.proxy_cache_dir);
+        set(&mut config.proxy_cache_size, self.proxy_cache_size);
+        set(&mut config.proxy_index_ttl, self.proxy_index_ttl);
+        set_some(&mut config.publication_dir, self.publication_dir);
+        set(
+            &mut config.publication_service_uri,
+            self.publication_service_uri,
+        );
+        set(&mut config.publication_sia_base, self.publication_sia_base);
+        set_some(&mut config.publication_rrdp_uri, self.publication_rrdp_uri);
+        set_some(&mut config.rrdp_dir, self.rrdp_dir);
+        set(&mut config.rrdp_base_uri, self.rrdp_base_uri);
+        set(&mut config.rrdp_state, self.rrdp_state);
+        set_some(&mut config.rsync_dir, self.rsync_dir);
+        config.rsync_include_host |= self.rsync_include_host;
+    }
+}
+
+/// Returns the configuration from the config file, if any, with the
+/// options applied, and initializes logging.
+pub fn configure(args: ConfigArgs) -> anyhow::Result<Config> {
+    let config = post_configure(args)?;
+    initialize_logging(&config)?;
+    Ok(config)
+}
+
+/// Returns the configuration from the config file, if any, with the
+/// options applied. Relative paths of state files are resolved under
+/// the state directory.
+pub fn post_configure(args: ConfigArgs) -> anyhow::Result<Config> {
+    let mut config = match &args.config {
+        Some(path) => Config::from_file(path)?,
+        None => Config::default(),
+    };
+    args.apply(&mut config);
+
+    config.repositories_file = config.state_dir.join(&config.repositories_file);
+    config.rrdp_state = config.state_dir.join(&config.rrdp_state);
+    config.log_file = config.state_dir.join(&config.log_file);
+
+    config.check()?;
+    Ok(config)
+}
+
+/// The most detailed level logged for epic itself. It can be changed
+/// after logging was initialized.
+static LOG_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Warn as usize);
+
+fn log_level() -> LevelFilter {
+    LevelFilter::iter()
+        .nth(LOG_LEVEL.load(Ordering::Relaxed))
+        .unwrap_or(LevelFilter::Trace)
+}
+
+/// Sets the most detailed level logged for epic itself. Other crates
+/// log at most warnings.
+pub fn set_log_level(level: LevelFilter) {
+    LOG_LEVEL.store(level as usize, Ordering::Relaxed);
+    log::set_max_level(level);
+}
+
+/// Sends log messages to the configured target, up to the configured
+/// level. The target cannot be changed later.
+pub fn initialize_logging(config: &Config) -> anyhow::Result<()> {
+    let log_with_time =
+        |out: fern::FormatCallback, message: &fmt::Arguments, record: &log::Record| {
+            if log_level() == LevelFilter::Trace {
+                log_with_target(out, message, record)
+            } else {
+                log_without_target(out, message, record)
+            }
+        };
+
+    let dispatch = fern::Dispatch::new().filter(|metadata| {
+        let target = metadata.target();
+        let level = match target == "epic" || target.starts_with("epic::") {
+            true => log_level(),
+            false => log_level().min(LevelFilter::Warn),
+        };
+        metadata.level() <= level
+    });
+    let dispatch = match config.log_target {
+        LogTarget::Stderr => dispatch.format(log_with_time).chain(std::io::stderr()),
+        LogTarget::Stdout => dispatch.format(log_with_time).chain(std::io::stdout()),
+        LogTarget::File => {
+            let file = fern::log_file(&config.log_file)
+                .with_context(|| format!("Cannot open log file {}", config.log_file.display()))?;
+            dispatch.format(log_with_time).chain(file)
+        }
+        LogTarget::Syslog => {
+            let formatter = syslog::Formatter3164 {
+                facility: syslog::Facility::LOG_DAEMON,
+                hostname: None,
+                process: "epic".into(),
+                pid: std::process::id(),
+            };
+            let logger = syslog::unix(formatter).map_err(|e| anyhow!("Cannot use syslog: {e}"))?;
+            dispatch.format(log_for_syslog).chain(logger)
+        }
+    };
+
+    // This only fails if logging was initialized already, which may
+    // happen when running tests in parallel.
+    let _ = dispatch.apply();
+    set_log_level(config.log_level);
+    Ok(())
+}
+
+fn log_without_target(out: fern::FormatCallback, message: &fmt::Arguments, record: &log::Record) {
+    out.finish(format_args!(
+        "{} {}: {}",
+        chrono::Local::now().format("%Y/%m/%d %H:%M:%S"),
+        record.level(),
+        message,
+    ))
+}
+
+fn log_with_target(out: fern::FormatCallback, message: &fmt::Arguments, record: &log::Record) {
+    out.finish(format_args!(
+        "{} {} [{}] {}",
+        chrono::Local::now().format("%Y/%m/%d %H:%M:%S"),
+        record.level(),
+        record.target(),
+        message,
+    ))
+}
+
+/// Syslog adds the time and level itself.
+fn log_for_syslog(out: fern::FormatCallback, message: &fmt::Arguments, record: &log::Record) {
+    out.finish(format_args!("[{}] {}", record.target(), message))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        fetch::discovery::DEFAULT_MAX_REPOSITORIES,
+        util::{https, test_with_dir},
+    };
+
+    use super::*;
+
+    #[test]
+    fn config_from_toml_and_json() {
+        test_with_dir("config_from_toml_and_json", |dir| {
+            let toml = r#"
+                listen = ["127.0.0.1:3000", "[::1]:3000"]
+                state_dir = "/var/lib/epic"
+                log_level = "debug"
+                log_target = "syslog"
+                fetch_mode = "insecure"
+                rrdp_state = "/tmp/rrdp-state.json"
+                tal_files = ["/etc/epic/tals/test.tal"]
+
+                [discovery]
+                max_depth = 8
+
+                [manifest_policy]
+                reject_stale = true
+
+                [[repositories]]
+                notify = "https://example.org/rrdp/notification.xml"
+                refresh = 120
+
+                [repositories.disk_mappings]
+                "example.org" = "test-resources/example/"
+            "#;
+            let toml_path = dir.join("epic.toml");
+            util::write_file(&toml_path, toml.as_bytes()).unwrap();
+
+            let args = ConfigArgs {
+                config: Some(toml_path),
+                ..Default::default()
+            };
+            let config = post_configure(args).unwrap();
+            assert_eq!(2, config.listen.len());
+            assert_eq!(LevelFilter::Debug, config.log_level);
+            assert_eq!(LogTarget::Syslog, config.log_target);
+            assert_eq!(FetchMode::Insecure, config.fetch_mode);
+            assert!(config.manifest_policy.reject_stale);
+            assert!(!config.manifest_policy.strict);
+            assert_eq!(
+                PathBuf::from("/var/lib/epic/repositories.json"),
+                config.repositories_file
+            );
+            assert_eq!(PathBuf::from("/tmp/rrdp-state.json"), config.rrdp_state);
+            assert_eq!(1, config.tal_files.len());
+            assert_eq!(8, config.discovery.max_depth);
+            assert_eq!(DEFAULT_MAX_REPOSITORIES, config.discovery.max_repositories);
+
+            let repository = &config.repositories[0];
+            assert_eq!(
+                https("https://example.org/rrdp/notification.xml"),
+                repository.notify
+            );
+            assert_eq!(120, repository.refresh);
+            assert_eq!(
+                Some(&PathBuf::from("test-resources/example/")),
+                repository
+                    .disk_mappings
+                    .get(&Fqdn::from(&repository.notify))
+            );
+
+            // The same configuration as JSON.
+            let json_path = dir.join("epic.json");
+            let json = serde_json::to_vec(&config).unwrap();
+            util::write_file(&json_path, &json).unwrap();
+            assert_eq!(config, Config::from_file(&json_path).unwrap());
+
+            // Unknown and invalid settings are errors.
+            let bad_path = dir.join("bad.toml");
+            util::write_file(&bad_path, b"listen_on = [\"[::]:3000\"]").unwrap();
+            assert!(Config::from_file(&bad_path).is_err());
+            util::write_file(&bad_path, b"listen = []").unwrap();
+            let args = ConfigArgs {
+                config: Some(bad_path.clone()),
+                ..Default::default()
+            };
+            assert!(post_configure(args).is_err());
+
+            // Listening for HTTPS only needs a certificate and key.
+            let args = ConfigArgs {
+                config: Some(bad_path),
+                tls_listen: vec!["[::]:3443".into()],
+                tls_cert_file: Some("localhost.crt".into()),
+                ..Default::default()
+            };
+            assert!(post_configure(args.clone()).is_err());
+            let args = ConfigArgs {
+                tls_key_file: Some("localhost.key".into()),
+                ..args
+            };
+            assert!(post_configure(args).unwrap().listen.is_empty());
+        })
+    }
+
+    #[test]
+    fn options_override_config_file() {
+        test_with_dir("options_override_config_file", |dir| {
+            let path = dir.join("epic.toml");
+            let toml = "listen = [\"[::]:8080\"]\nlog_level = \"info\"\nupstream_refresh = 30\n";
+            util::write_file(&path, toml.as_bytes()).unwrap();
+
+            let args = ConfigArgs::from_iter_safe([
+                "epic",
+                "--config",
+                path.to_str().unwrap(),
+                "--listen",
+                "127.0.0.1:3001",
+                "--state-dir",
+                "state",
+                "--log-level",
+                "trace",
+                "--insecure",
+                "--strict-manifests",
+                "--repositories",
+                "/etc/epic/repositories.json",
+            ])
+            .unwrap();
+            let config = post_configure(args).unwrap();
+            assert_eq!(vec!["127.0.0.1:3001".to_string()], config.listen);
+            assert_eq!(LevelFilter::Trace, config.log_level);
+            assert_eq!(FetchMode::Insecure, config.fetch_mode);
+            assert!(config.manifest_policy.strict);
+            assert_eq!(30, config.upstream_refresh);
+            assert_eq!(
+                PathBuf::from("/etc/epic/repositories.json"),
+                config.repositories_file
+            );
+            assert_eq!(PathBuf::from("state/rrdp-state.json"), config.rrdp_state);
+
+            // Only some changes can be applied without a restart.
+            let mut reloaded = config.clone();
+            reloaded.log_level = LevelFilter::Info;
+            reloaded.manifest_policy.reject_stale = true;
+            assert!(!config.needs_restart(&reloaded));
+            reloaded.listen.push("127.0.0.1:3002".into());
+            assert!(config.needs_restart(&reloaded));
+
+            let defaults = post_configure(ConfigArgs::default()).unwrap();
+            assert_eq!(vec![DEFAULT_LISTEN.to_string()], defaults.listen);
+            assert_eq!(LevelFilter::Warn, defaults.log_level);
+            assert_eq!(FetchMode::Strict, defaults.fetch_mode);
+        })
+    }
+}
@@ -0,0 +1,520 @@
+//! A small HTML dashboard for the relay, served by epic.
+//!
+//! The pages are rendered on the server from the latest status of the
+//! pool and the content that is served. They use no scripts and no
+//! external assets, so that they work without access to the internet.
+//!
+//! The overview shows the repositories and indexes, and warns about
+//! manifests that are near or past their next update. From there, the
+//! index browser goes from partitions to manifest references, to the
+//! files listed on manifests, to the objects themselves.
+
+use std::fmt::Write as _;
+
+use rpki::{
+    repository::{Manifest, x509::Time},
+    rrdp::Hash,
+};
+
+use crate::{
+    erik::{
+        asn1::{ErikPartition, ManifestRef},
+        media_type::ObjectKind,
+        ni::NamedInformation,
+        relay::RelayContent,
+    },
+    fetch::{pool::PoolStatus, retrieval::Fqdn},
+    status::{IndexReport, ObjectReport, RelayStatus},
+};
+
+/// The content type of the pages.
+pub const CONTENT_TYPE: &str = "text/html; charset=utf-8";
+
+/// The path under which the index browser is found.
+pub const PATH: &str = "/ui";
+
+/// Manifests are reported as near their next update when it is less
+/// than this many seconds away.
+pub const NEAR_NEXT_UPDATE_SECONDS: i64 = 3600;
+
+const STYLE: &str = "\
+body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; margin-bottom: 2em; }
+th, td { border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: left; }
+th { background: #eee; }
+.ok { color: #176f2c; }
+.warn { color: #8a5a00; }
+.error { color: #b00020; }
+nav { margin-bottom: 1em; }
+";
+
+/// How close a manifest is to its next update.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Freshness {
+    Fresh,
+    NearNextUpdate,
+    Stale,
+}
+
+impl Freshness {
+    pub fn new(next_update: Time, now: Time) -> Self {
+        let remaining = next_update.timestamp() - now.timestamp();
+        if remaining < 0 {
+            Freshness::Stale
+        } else if remaining < NEAR_NEXT_UPDATE_SECONDS {
+            Freshness::NearNextUpdate
+        } else {
+            Freshness::Fresh
+        }
+    }
+
+    fn cell(self) -> String {
+        match self {
+            Freshness::Fresh => r#"<span class="ok">fresh</span>"#,
+            Freshness::NearNextUpdate => r#"<span class="warn">near next update</span>"#,
+            Freshness::Stale => r#"<span class="error">stale</span>"#,
+        }
+        .to_string()
+    }
+}
+
+/// Renders the overview of repositories, indexes and manifests that
+/// need attention.
+pub fn overview(
+    status: &RelayStatus,
+    pool: &PoolStatus,
+    content: &RelayContent,
+    now: Time,
+) -> String {
+    let mut page = Page::new("Relay");
+    page.paragraph(if status.ready {
+        r#"<span class="ok">Ready</span>, serving content."#
+    } else {
+        r#"<span class="error">Not ready</span>, no repository was updated yet."#
+    });
+
+    page.heading("Repositories");
+    let failures = |source: &str| {
+        pool.repositories
+            .iter()
+            .find(|repository| repository.source.to_string() == source)
+            .map_or(0, |repository| repository.failures)
+    };
+    page.table(
+        &[
+            "Source",
+            "Session",
+            "Serial",
+            "Objects",
+            "Last update",
+            "Status",
+        ],
+        status.repositories.iter().map(|repository| {
+            let state = match &repository.last_error {
+                Some(e) => format!(
+                    r#"<span class="error">failed {} time(s): {}</span>"#,
+                    failures(&repository.source),
+                    escape(e)
+                ),
+                None if repository.last_update.is_some() => r#"<span class="ok">ok</span>"#.into(),
+                None => r#"<span class="warn">not updated yet</span>"#.into(),
+            };
+            vec![
+                escape(&repository.source),
+                optional(repository.session),
+                optional(repository.serial),
+                repository.elements.to_string(),
+                optional(repository.last_update.map(time)),
+                state,
+            ]
+        }),
+    );
+
+    page.heading("Indexes");
+    page.table(
+        &["Scope", "Index time", "Partitions", "Hash"],
+        status.indexes.iter().map(|index| {
+            vec![
+                link(&index_path(&index.fqdn), &index.fqdn.to_string()),
+                time(index.index_time),
+                index.partitions.to_string(),
+                hash_cell(&index.hash),
+            ]
+        }),
+    );
+
+    page.heading("Manifests near or past their next update");
+    let warnings: Vec<_> = content
+        .next_updates()
+        .iter()
+        .map(|(mft_ref, next_update)| (mft_ref, *next_update, Freshness::new(*next_update, now)))
+        .take_while(|(_, _, freshness)| *freshness != Freshness::Fresh)
+        .collect();
+    if warnings.is_empty() {
+        page.paragraph(r#"<span class="ok">All manifests are fresh.</span>"#);
+    } else {
+        page.table(
+            &["Manifest", "Next update", "Freshness"],
+            warnings.iter().map(|(mft_ref, next_update, freshness)| {
+                vec![
+                    link(&manifest_path(&mft_ref.hash), mft_ref.locations.as_str()),
+                    time(*next_update),
+                    freshness.cell(),
+                ]
+            }),
+        );
+    }
+    page.finish()
+}
+
+/// Renders the partitions of the index for a scope.
+pub fn index(content: &RelayContent, fqdn: &Fqdn) -> Option<String> {
+    let summary = content
+        .index_summaries()
+        .find(|(scope, _)| *scope == fqdn)?
+        .1;
+    let report = IndexReport::all(content)
+        .into_iter()
+        .find(|index| &index.fqdn == fqdn)?;
+
+    let mut page = Page::new(&format!("Index {fqdn}"));
+    page.paragraph(&format!(
+        "Index time {}, hash <code>{}</code>.",
+        time(report.index_time),
+        report.hash
+    ));
+    let mut partitions: Vec<_> = summary.partitions().collect();
+    partitions.sort_by_key(|(key, _)| **key);
+    page.heading("Partitions");
+    page.table(
+        &["Key", "Partition time", "Manifests", "Hash"],
+        partitions.into_iter().map(|(key, hash)| {
+            let partition = content
+                .partition(hash)
+                .and_then(|bytes| ErikPartition::decode(bytes.as_ref()).ok());
+            vec![
+                key.to_string(),
+                optional(partition.as_ref().map(|p| time(p.partition_time))),
+                optional(partition.as_ref().map(|p| p.manifest_refs.len())),
+                link(&partition_path(hash), &hash.to_string()),
+            ]
+        }),
+    );
+    Some(page.finish())
+}
+
+/// Renders the manifest references in a partition.
+pub fn partition(content: &RelayContent, hash: &Hash, now: Time) -> Option<String> {
+    let partition = ErikPartition::decode(content.partition(hash)?.as_ref()).ok()?;
+    let mut mft_refs: Vec<_> = partition.manifest_refs.into_iter().collect();
+    mft_refs.sort_by(|a, b| a.locations.as_str().cmp(b.locations.as_str()));
+
+    let mut page = Page::new(&format!("Partition {hash}"));
+    page.paragraph(&format!(
+        "Partition time {}. {}",
+        time(partition.partition_time),
+        link(&format!("/api/v1/partitions/{hash}"), "JSON")
+    ));
+    page.heading("Manifests");
+    page.table(
+        &[
+            "Manifest",
+            "Number",
+            "This update",
+            "Next update",
+            "Freshness",
+        ],
+        mft_refs.iter().map(|mft_ref| {
+            let next_update = next_update(content, mft_ref);
+            vec![
+                link(&manifest_path(&mft_ref.hash), mft_ref.locations.as_str()),
+                mft_ref.manifest_number.to_string(),
+                time(mft_ref.this_update),
+                optional(next_update.map(time)),
+                optional(next_update.map(|next| Freshness::new(next, now).cell())),
+            ]
+        }),
+    );
+    Some(page.finish())
+}
+
+/// Renders the files listed on a manifest.
+pub fn manifest(content: &RelayContent, hash: &Hash, now: Time) -> Option<String> {
+    let element = content.element(hash)?;
+    let manifest = Manifest::decode(element.data().as_ref(), false).ok()?;
+    let next_update = manifest.next_update();
+
+    let mut page = Page::new(&format!("Manifest {}", element.uri()));
+    page.paragraph(&format!(
+        "Number {}, this update {}, next update {}: {}. {}",
+        manifest.manifest_number(),
+        time(manifest.this_update()),
+        time(next_update),
+        Freshness::new(next_update, now).cell(),
+        link(&object_path(hash), "Object details")
+    ));
+    page.heading("Files");
+    page.table(
+        &["File", "Hash", "Served"],
+        manifest.content().iter().map(|item| {
+            let file = escape(&String::from_utf8_lossy(item.file()));
+            match Hash::try_from(item.hash().as_ref()) {
+                Ok(hash) if content.object(&hash).is_some() => vec![
+                    file,
+                    link(&object_path(&hash), &hash.to_string()),
+                    r#"<span class="ok">yes</span>"#.into(),
+                ],
+                Ok(hash) => vec![
+                    file,
+                    hash_cell(&hash),
+                    r#"<span class="error">missing</span>"#.into(),
+                ],
+                Err(_) => vec![
+                    file,
+                    "-".into(),
+                    r#"<span class="error">invalid hash</span>"#.into(),
+                ],
+            }
+        }),
+    );
+    Some(page.finish())
+}
+
+/// Renders what we know about an object.
+pub fn object(content: &RelayContent, hash: &Hash, erik_media_type: &str) -> Option<String> {
+    let report = ObjectReport::new(content, hash, erik_media_type)?;
+    let ni = NamedInformation::new(*hash);
+
+    let mut page = Page::new(&format!("Object {hash}"));
+    let mut rows = vec![
+        vec!["Kind".into(), format!("{:?}", report.kind)],
+        vec!["ni".into(), escape(&report.ni)],
+        vec!["Size".into(), format!("{} bytes", report.size)],
+        vec!["Media type".into(), escape(&report.media_type)],
+        vec![
+            "URI".into(),
+            optional(report.uri.as_ref().map(|uri| escape(uri.as_str()))),
+        ],
+        vec![
+            "Download".into(),
+            link(&format!("/{}", ni.well_known_path()), &ni.value()),
+        ],
+        vec![
+            "JSON".into(),
+            link(&format!("/api/v1/objects/{hash}"), "details"),
+        ],
+    ];
+    match report.kind {
+        ObjectKind::Manifest => rows.push(vec!["Files".into(), link(&manifest_path(hash), "list")]),
+        ObjectKind::ErikPartition => rows.push(vec![
+            "Manifests".into(),
+            link(&partition_path(hash), "list"),
+        ]),
+        _ => {}
+    }
+    page.table(&["Property", "Value"], rows.into_iter());
+    Some(page.finish())
+}
+
+/// Renders a page saying that something was not found.
+pub fn not_found(message: &str) -> String {
+    let mut page = Page::new("Not found");
+    page.paragraph(&escape(message));
+    page.finish()
+}
+
+fn index_path(fqdn: &Fqdn) -> String {
+    format!("{PATH}/indexes/{fqdn}")
+}
+
+fn partition_path(hash: &Hash) -> String {
+    format!("{PATH}/partitions/{hash}")
+}
+
+fn manifest_path(hash: &Hash) -> String {
+    format!("{PATH}/manifests/{hash}")
+}
+
+fn object_path(hash: &Hash) -> String {
+    format!("{PATH}/objects/{hash}")
+}
+
+/// Returns the next update of a referenced manifest, if we serve it.
+fn next_update(content: &RelayContent, mft_ref: &ManifestRef) -> Option<Time> {
+    let element = content.element(&mft_ref.hash)?;
+    Manifest::decode(element.data().as_ref(), false)
+        .ok()
+        .map(|manifest| manifest.next_update())
+}
+
+fn time(time: Time) -> String {
+    time.format("%Y-%m-%d %H:%M:%S UTC").to_string()
+}
+
+fn optional(value: Option<impl ToString>) -> String {
+    value.map_or_else(|| "-".to_string(), |value| value.to_string())
+}
+
+fn hash_cell(hash: &Hash) -> String {
+    format!("<code>{hash}</code>")
+}
+
+fn link(href: &str, text: &str) -> String {
+    format!(r#"<a href="{}">{}</a>"#, escape(href), escape(text))
+}
+
+/// Escapes text for use in HTML content and attribute values.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Writes a page. Cells and paragraphs are HTML, so any text in them
+/// must be escaped by the caller.
+struct Page {
+    out: String,
+}
+
+impl Page {
+    fn new(title: &str) -> Self {
+        let title = escape(title);
+        let mut out = String::new();
+        write!(
+            out,
+            "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>EPIC: {title}</title>\n<style>\n{STYLE}</style>\n</head>\n<body>\n\
+             <nav><a href=\"/\">Overview</a> | <a href=\"/status\">Status</a> | \
+             <a href=\"/metrics\">Metrics</a></nav>\n<h1>{title}</h1>\n"
+        )
+        .unwrap();
+        Page { out }
+    }
+
+    fn heading(&mut self, text: &str) {
+        writeln!(self.out, "<h2>{}</h2>", escape(text)).unwrap();
+    }
+
+    fn paragraph(&mut self, html: &str) {
+        writeln!(self.out, "<p>{html}</p>").unwrap();
+    }
+
+    fn table(&mut self, headers: &[&str], rows: impl Iterator<Item = Vec<String>>) {
+        self.out.push_str("<table>\n<tr>");
+        for header in headers {
+            write!(self.out, "<th>{}</th>", escape(header)).unwrap();
+        }
+        self.out.push_str("</tr>\n");
+        for row in rows {
+            self.out.push_str("<tr>");
+            for cell in row {
+                write!(self.out, "<td>{cell}</td>").unwrap();
+            }
+            self.out.push_str("</tr>\n");
+        }
+        self.out.push_str("</table>\n");
+    }
+
+    fn finish(mut self) -> String {
+        self.out.push_str("</body>\n</html>\n");
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        fetch::{
+            pool::{DEFAULT_REFRESH_SECONDS, RepositoryPool},
+            retrieval::FetchMapper,
+        },
+        util::https,
+    };
+
+    use super::*;
+
+    #[test]
+    fn browse_content() {
+        let notify = https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+        let mut mapper = FetchMapper::empty();
+        mapper.add_disk_mapper(
+            (&notify).into(),
+            PathBuf::from("test-resources/rrdp-rev2656/"),
+        );
+        let mut pool = RepositoryPool::empty();
+        pool.add(notify.into(), mapper, DEFAULT_REFRESH_SECONDS)
+            .unwrap();
+        pool.update_due();
+        let content = RelayContent::from_pool(&pool);
+        let pool_status = pool.status();
+        let status = RelayStatus::new(true, &pool_status, &content);
+
+        // The test repository is from 2021, so everything is stale now.
+        let page = overview(&status, &pool_status, &content, Time::now());
+        assert!(page.contains(r#"<a href="/ui/indexes/krill-ui-dev.do.nlnetlabs.nl">"#));
+        assert_eq!(
+            7,
+            page.matches(r#"<span class="error">stale</span>"#).count()
+        );
+
+        let (_, next) = content.next_updates()[0];
+        let page = overview(
+            &status,
+            &pool_status,
+            &content,
+            next - chrono::TimeDelta::days(1),
+        );
+        assert!(page.contains("All manifests are fresh."));
+
+        let fqdn = "krill-ui-dev.do.nlnetlabs.nl".parse().unwrap();
+        let page = index(&content, &fqdn).unwrap();
+        let (_, summary) = content.index_summaries().next().unwrap();
+        for (_, hash) in summary.partitions() {
+            assert!(page.contains(&partition_path(hash)));
+        }
+        assert!(index(&content, &"example.com".parse().unwrap()).is_none());
+
+        let (_, partition_hash) = summary.partitions().next().unwrap();
+        let page = partition(&content, partition_hash, Time::now()).unwrap();
+        let partition_mft =
+            ErikPartition::decode(content.partition(partition_hash).unwrap().as_ref())
+                .unwrap()
+                .manifest_refs
+                .into_iter()
+                .next()
+                .unwrap();
+        assert!(page.contains(&manifest_path(&partition_mft.hash)));
+
+        let page = manifest(&content, &partition_mft.hash, Time::now()).unwrap();
+        let decoded =
+            Manifest::decode(content.object(&partition_mft.hash).unwrap().as_ref(), false).unwrap();
+        assert_eq!(
+            decoded.content().len(),
+            page.matches(r#"<span class="ok">yes</span>"#).count()
+        );
+
+        let page = object(&content, &partition_mft.hash, "application/rpki-erik").unwrap();
+        assert!(page.contains("application/rpki-manifest"));
+        assert!(page.contains(&manifest_path(&partition_mft.hash)));
+    }
+
+    #[test]
+    fn escape_html() {
+        assert_eq!(
+            "&lt;a href=&quot;x&quot;&gt;&amp;&#39;&lt;/a&gt;",
+            escape(r#"<a href="x">&'</a>"#)
+        );
+        assert!(not_found("<script>").contains("&lt;script&gt;"));
+    }
+}
@@ -67,6 +67,10 @@ impl ErikIndex {
         Mode::Der.decode(source.into_source(), Self::take_from)
     }
 
+    pub fn partitions(&self) -> &[ErikPartitionRef] {
+        &self.partitions
+    }
+
     fn take_from<S: decode::Source>(
         cons: &mut decode::Constructed<S>,
     ) -> Result<Self, DecodeError<S::Error>> {
@@ -126,7 +130,7 @@ impl From<&erik::state::ResolvedErikIndex> for ErikIndex {
         partitions.sort();
 
         ErikIndex {
-            index_scope: Ia5String::from_string(index.index_scope.clone()).unwrap(),
+            index_scope: Ia5String::from_string(index.index_scope.to_string()).unwrap(),
             index_time: index.index_time,
             partitions,
         }
@@ -149,6 +153,14 @@ impl ErikPartitionRef {
         ErikPartitionRef { hash, size }
     }
 
+    pub fn hash(&self) -> Hash {
+        self.hash
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
     pub fn encode(&self) -> impl encode::Values {
         encode::sequence((self.hash.as_slice().encode(), self.size.encode()))
     }
@@ -521,10 +533,9 @@ mod tests {
 
     fn test_index_from_content() -> erik::state::ResolvedErikIndex {
         let repo_content = RepoContent::create_test().unwrap();
-        erik::state::ResolvedErikIndex::from_content(
-            "krill-ui-dev.do.nlnetlabs.nl".to_string(),
-            &repo_content,
-        )
-        .unwrap()
+        erik::state::ResolvedErikIndex::from_content(&repo_content)
+            .into_values()
+            .next()
+            .unwrap()
     }
 }
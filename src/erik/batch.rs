@@ -0,0 +1,138 @@
+//! The framing used to retrieve many objects in one request.
+//!
+//! A request body is the concatenation of the 32 byte SHA-256 hashes
+//! of the wanted objects.
+//!
+//! The response has an entry for each requested hash, in the order of
+//! the request. Each entry starts with the hash and a status byte:
+//!
+//! - `0x00` the object follows, as a 4 byte big-endian length and the
+//!   bytes of the object
+//! - `0x01` the object was not found, nothing follows
+//!
+//! Relays advertise the endpoint in a `Link` header with
+//! `rel="erik-batch"` on their index responses.
+
+use anyhow::anyhow;
+use bytes::{BufMut, Bytes, BytesMut};
+use rpki::rrdp::Hash;
+
+/// The content type of batch requests and responses.
+pub const CONTENT_TYPE: &str = "application/x-erik-batch";
+
+/// The link relation used to advertise the batch endpoint.
+pub const LINK_REL: &str = "erik-batch";
+
+/// The path of the batch endpoint in epic.
+pub const PATH: &str = ".well-known/erik/objects";
+
+/// The maximum number of hashes in a single request.
+pub const MAX_HASHES: usize = 1000;
+
+const HASH_LEN: usize = 32;
+const FOUND: u8 = 0;
+const NOT_FOUND: u8 = 1;
+
+/// Encodes a request for the given hashes.
+pub fn encode_request(hashes: &[Hash]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(hashes.len() * HASH_LEN);
+    for hash in hashes {
+        buf.extend_from_slice(hash.as_slice());
+    }
+    buf
+}
+
+/// Decodes a request. Fails if the body is not a whole number of
+/// hashes, or has more than MAX_HASHES.
+pub fn decode_request(body: &[u8]) -> anyhow::Result<Vec<Hash>> {
+    if !body.len().is_multiple_of(HASH_LEN) {
+        return Err(anyhow!("batch request is not a list of SHA-256 hashes"));
+    }
+    if body.len() / HASH_LEN > MAX_HASHES {
+        return Err(anyhow!("batch request has more than {MAX_HASHES} hashes"));
+    }
+    body.chunks(HASH_LEN)
+        .map(|chunk| Hash::try_from(chunk).map_err(|_| anyhow!("invalid hash")))
+        .collect()
+}
+
+/// Encodes a single response entry, as its header and, if found, the
+/// object that follows it. The object is not copied, so a response
+/// can be sent straight from the objects we keep. Fails if the object
+/// is too large for its length to fit.
+pub fn encode_entry(hash: &Hash, data: Option<&Bytes>) -> anyhow::Result<(Bytes, Option<Bytes>)> {
+    let mut buf = BytesMut::with_capacity(HASH_LEN + 5);
+    buf.put_slice(hash.as_slice());
+    match data {
+        Some(data) => {
+            let len = u32::try_from(data.len())
+                .map_err(|_| anyhow!("object {hash} is too large for a batch response"))?;
+            buf.put_u8(FOUND);
+            buf.put_u32(len);
+        }
+        None => buf.put_u8(NOT_FOUND),
+    }
+    Ok((buf.freeze(), data.cloned()))
+}
+
+/// Decodes a response into the hashes and, if found, their data.
+///
+/// The data is not checked against the hash here.
+pub fn decode_response(mut body: Bytes) -> anyhow::Result<Vec<(Hash, Option<Bytes>)>> {
+    let mut entries = vec![];
+    while !body.is_empty() {
+        if body.len() < HASH_LEN + 1 {
+            return Err(anyhow!("truncated batch response entry"));
+        }
+        let hash = Hash::try_from(&body[..HASH_LEN]).map_err(|_| anyhow!("invalid hash"))?;
+        let status = body[HASH_LEN];
+        body = body.slice(HASH_LEN + 1..);
+
+        match status {
+            FOUND => {
+                if body.len() < 4 {
+                    return Err(anyhow!("truncated batch response entry for {hash}"));
+                }
+                let len = u32::from_be_bytes([body[0], body[1], body[2], body[3]]) as usize;
+                if body.len() < 4 + len {
+                    return Err(anyhow!("truncated batch response data for {hash}"));
+                }
+                entries.push((hash, Some(body.slice(4..4 + len))));
+                body = body.slice(4 + len..);
+            }
+            NOT_FOUND => entries.push((hash, None)),
+            _ => return Err(anyhow!("invalid status {status} in batch response")),
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_and_decode() {
+        let data = Bytes::from_static(b"some object");
+        let found = Hash::from_data(&data);
+        let missing = Hash::from_data(b"missing");
+
+        let request = encode_request(&[found, missing]);
+        assert_eq!(vec![found, missing], decode_request(&request).unwrap());
+        assert!(decode_request(&request[1..]).is_err());
+
+        let (head, object) = encode_entry(&found, Some(&data)).unwrap();
+        assert_eq!(Some(&data), object.as_ref());
+        let mut response = BytesMut::new();
+        response.put(head);
+        response.put(data.clone());
+        response.put(encode_entry(&missing, None).unwrap().0);
+        let response = response.freeze();
+
+        assert_eq!(
+            vec![(found, Some(data)), (missing, None)],
+            decode_response(response.clone()).unwrap()
+        );
+        assert!(decode_response(response.slice(..response.len() - 20)).is_err());
+    }
+}
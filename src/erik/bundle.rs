@@ -0,0 +1,90 @@
+//! Partition bundles: a partition, together with all the manifests it
+//! refers to and all files on those manifests, as a single tar archive.
+//!
+//! Each object is a file named `sha-256/<base64url hash>`. A bundle is
+//! addressed by the hash of its partition, so it never changes. Relays
+//! advertise the endpoint in a `Link` header with `rel="erik-bundle"`
+//! on their index responses.
+
+use std::{collections::HashMap, io::Read};
+
+use anyhow::{Context, anyhow};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use bytes::Bytes;
+use rpki::rrdp::Hash;
+
+/// The content type of bundles.
+pub const CONTENT_TYPE: &str = "application/x-tar";
+
+/// The link relation used to advertise the bundle endpoint.
+pub const LINK_REL: &str = "erik-bundle";
+
+/// The path of the bundle endpoint in epic. Bundles are found at
+/// `<PATH>/<base64url partition hash>`.
+pub const PATH: &str = ".well-known/erik/bundle";
+
+const ENTRY_DIR: &str = "sha-256/";
+
+/// Writes the objects as a tar archive.
+pub fn write_bundle<'a>(
+    objects: impl IntoIterator<Item = (&'a Hash, &'a Bytes)>,
+) -> anyhow::Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(vec![]);
+    for (hash, data) in objects {
+        let mut header = tar::Header::new_ustar();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(0);
+        let name = format!("{ENTRY_DIR}{}", URL_SAFE_NO_PAD.encode(hash.as_slice()));
+        builder
+            .append_data(&mut header, name, data.as_ref())
+            .context("Cannot add object to bundle")?;
+    }
+    builder.into_inner().context("Cannot finish bundle")
+}
+
+/// Reads the objects from a tar archive, and checks that each object
+/// matches the hash in its name.
+pub fn read_bundle(data: &[u8]) -> anyhow::Result<HashMap<Hash, Bytes>> {
+    let mut objects = HashMap::new();
+    let mut archive = tar::Archive::new(data);
+    for entry in archive.entries().context("Cannot read bundle")? {
+        let mut entry = entry.context("Cannot read bundle entry")?;
+        let path = entry.path()?.to_string_lossy().to_string();
+        let hash = path
+            .strip_prefix(ENTRY_DIR)
+            .and_then(|name| URL_SAFE_NO_PAD.decode(name).ok())
+            .and_then(|bytes| Hash::try_from(bytes.as_slice()).ok())
+            .ok_or_else(|| anyhow!("Unexpected entry in bundle: {path}"))?;
+
+        // The size comes from the archive, so it is only trusted as
+        // far as there is data for it.
+        let size = usize::try_from(entry.size()).unwrap_or(usize::MAX);
+        let mut bytes = Vec::with_capacity(size.min(data.len()));
+        entry.read_to_end(&mut bytes)?;
+        if !hash.matches(&bytes) {
+            return Err(anyhow!("Object in bundle does not match its hash {hash}"));
+        }
+        objects.insert(hash, Bytes::from(bytes));
+    }
+    Ok(objects)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_and_read_bundle() {
+        let data = Bytes::from_static(b"some object");
+        let hash = Hash::from_data(&data);
+
+        let bundle = write_bundle([(&hash, &data)]).unwrap();
+        let objects = read_bundle(&bundle).unwrap();
+        assert_eq!(Some(&data), objects.get(&hash));
+
+        let other = Hash::from_data(b"other");
+        let bundle = write_bundle([(&other, &data)]).unwrap();
+        assert!(read_bundle(&bundle).is_err());
+    }
+}
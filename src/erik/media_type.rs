@@ -0,0 +1,175 @@
+//! The kinds of objects served by a relay, and their media types.
+//!
+//! Repository objects get the media types registered for them. Erik
+//! indexes and partitions do not have a registered media type yet, so
+//! the one used for them can be configured.
+
+use anyhow::anyhow;
+use rpki::{
+    repository::{Cert, Crl, sigobj::SignedObject},
+    uri,
+};
+use serde::Serialize;
+
+use crate::erik::asn1::{ErikIndex, ErikPartition};
+
+/// The default media type of Erik indexes and partitions.
+pub const DEFAULT_ERIK_MEDIA_TYPE: &str = "application/rpki-erik";
+
+/// The media type of objects of an unknown kind.
+pub const UNKNOWN_MEDIA_TYPE: &str = "application/octet-stream";
+
+/// The `id-ct` arc under which RPKI signed object content types are
+/// registered: 1.2.840.113549.1.9.16.1
+const ID_CT: &[u8] = &[42, 134, 72, 134, 247, 13, 1, 9, 16, 1];
+
+/// The kind of a served object.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ObjectKind {
+    ErikIndex,
+    ErikPartition,
+    Manifest,
+    Certificate,
+    Crl,
+    Roa,
+    Aspa,
+    Ghostbusters,
+    SignedChecklist,
+    SignedTal,
+    Unknown,
+}
+
+impl ObjectKind {
+    /// The kinds of repository objects, with their file extension and
+    /// the last arc of the eContentType if they are signed objects.
+    const REPOSITORY_KINDS: [(ObjectKind, &'static str, Option<u8>); 8] = [
+        (ObjectKind::Manifest, ".mft", Some(26)),
+        (ObjectKind::Certificate, ".cer", None),
+        (ObjectKind::Crl, ".crl", None),
+        (ObjectKind::Roa, ".roa", Some(24)),
+        (ObjectKind::Aspa, ".asa", Some(49)),
+        (ObjectKind::Ghostbusters, ".gbr", Some(35)),
+        (ObjectKind::SignedChecklist, ".sig", Some(48)),
+        (ObjectKind::SignedTal, ".tak", Some(50)),
+    ];
+
+    /// Derives the kind of a repository object from the extension of
+    /// its rsync URI.
+    pub fn from_uri(uri: &uri::Rsync) -> Self {
+        Self::REPOSITORY_KINDS
+            .iter()
+            .find(|(_, extension, _)| uri.ends_with(extension))
+            .map(|(kind, _, _)| *kind)
+            .unwrap_or(ObjectKind::Unknown)
+    }
+
+    /// Derives the kind of an object from its content. This decodes
+    /// the object, so prefer `from_uri` when the URI is known.
+    pub fn from_content(data: &[u8]) -> Self {
+        if let Ok(signed) = SignedObject::decode(data, false) {
+            let content_type = signed.content_type().as_ref();
+            return content_type
+                .strip_prefix(ID_CT)
+                .and_then(|arc| {
+                    Self::REPOSITORY_KINDS
+                        .iter()
+                        .find(|(_, _, ct)| ct.is_some_and(|ct| arc == [ct]))
+                })
+                .map(|(kind, _, _)| *kind)
+                .unwrap_or(ObjectKind::Unknown);
+        }
+        if Cert::decode(data).is_ok() {
+            ObjectKind::Certificate
+        } else if Crl::decode(data).is_ok() {
+            ObjectKind::Crl
+        } else if ErikPartition::decode(data).is_ok() {
+            ObjectKind::ErikPartition
+        } else if ErikIndex::decode(data).is_ok() {
+            ObjectKind::ErikIndex
+        } else {
+            ObjectKind::Unknown
+        }
+    }
+
+    /// Returns the media type for objects of this kind, using the
+    /// given media type for Erik objects.
+    pub fn media_type(self, erik_media_type: &str) -> &str {
+        match self {
+            ObjectKind::ErikIndex | ObjectKind::ErikPartition => erik_media_type,
+            ObjectKind::Manifest => "application/rpki-manifest",
+            ObjectKind::Certificate => "application/pkix-cert",
+            ObjectKind::Crl => "application/pkix-crl",
+            ObjectKind::Roa => "application/rpki-roa",
+            ObjectKind::Aspa => "application/rpki-aspa",
+            ObjectKind::Ghostbusters => "application/rpki-ghostbusters",
+            ObjectKind::SignedChecklist => "application/rpki-checklist",
+            ObjectKind::SignedTal => "application/rpki-signed-tal",
+            ObjectKind::Unknown => UNKNOWN_MEDIA_TYPE,
+        }
+    }
+}
+
+/// Checks that a media type is of the form `type/subtype`, with only
+/// the characters that RFC 6838 allows.
+pub fn check_media_type(media_type: &str) -> anyhow::Result<&str> {
+    let valid_name = |name: &str| {
+        !name.is_empty()
+            && name.len() <= 127
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c))
+    };
+    match media_type.split_once('/') {
+        Some((type_, subtype)) if valid_name(type_) && valid_name(subtype) => Ok(media_type),
+        _ => Err(anyhow!("invalid media type: {media_type}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        erik::relay::RelayContent,
+        fetch::{
+            pool::{DEFAULT_REFRESH_SECONDS, RepositoryPool},
+            retrieval::FetchMapper,
+        },
+        util::https,
+    };
+
+    use super::*;
+
+    #[test]
+    fn kind_from_uri_matches_content() {
+        let notify = https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+        let mut mapper = FetchMapper::empty();
+        mapper.add_disk_mapper(
+            (&notify).into(),
+            PathBuf::from("test-resources/rrdp-rev2656/"),
+        );
+        let mut pool = RepositoryPool::empty();
+        pool.add(notify.into(), mapper, DEFAULT_REFRESH_SECONDS)
+            .unwrap();
+        pool.update_due();
+
+        for element in pool.elements().values() {
+            let kind = ObjectKind::from_uri(element.uri());
+            assert_ne!(ObjectKind::Unknown, kind, "{}", element.uri());
+            assert_eq!(kind, ObjectKind::from_content(element.data()));
+        }
+
+        let content = RelayContent::from_pool(&pool);
+        let (_, index) = content.indexes().next().unwrap();
+        assert_eq!(ObjectKind::ErikIndex, ObjectKind::from_content(index));
+        assert_eq!(
+            ObjectKind::Unknown,
+            ObjectKind::from_content(b"not an object")
+        );
+        assert_eq!(
+            "application/rpki-erik",
+            ObjectKind::ErikIndex.media_type(DEFAULT_ERIK_MEDIA_TYPE)
+        );
+    }
+}
@@ -2,4 +2,9 @@
 //!
 
 pub mod asn1;
+pub mod batch;
+pub mod bundle;
+pub mod media_type;
+pub mod ni;
+pub mod relay;
 pub mod state;
@@ -0,0 +1,326 @@
+//! Named Information (ni) names, as defined in RFC 6920.
+//!
+//! Erik relays serve objects by their ni name, using the `.well-known`
+//! HTTP mapping of section 4 of the RFC:
+//!
+//! `ni://<authority>/sha-256;<value>?ct=<type>` maps to
+//! `https://<authority>/.well-known/ni/sha-256/<value>?ct=<type>`
+//!
+//! Only `sha-256` is supported. Other algorithms in the registry are
+//! recognised, so that we can say why they are rejected.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::anyhow;
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use rpki::{rrdp::Hash, uri};
+
+use crate::erik::media_type::check_media_type;
+
+/// The path under which objects are served by their ni name.
+pub const WELL_KNOWN_PATH: &str = ".well-known/ni";
+
+/// The hash algorithms in the IANA Named Information Hash Algorithm
+/// Registry.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha256Truncated(usize),
+    Sha384,
+    Sha512,
+    Sha3_224,
+    Sha3_256,
+    Sha3_384,
+    Sha3_512,
+}
+
+impl HashAlgorithm {
+    /// The registered suite IDs and names, with the length of the
+    /// values in bytes.
+    const REGISTRY: [(u8, &'static str, HashAlgorithm, usize); 12] = [
+        (1, "sha-256", HashAlgorithm::Sha256, 32),
+        (2, "sha-256-128", HashAlgorithm::Sha256Truncated(16), 16),
+        (3, "sha-256-120", HashAlgorithm::Sha256Truncated(15), 15),
+        (4, "sha-256-96", HashAlgorithm::Sha256Truncated(12), 12),
+        (5, "sha-256-64", HashAlgorithm::Sha256Truncated(8), 8),
+        (6, "sha-256-32", HashAlgorithm::Sha256Truncated(4), 4),
+        (7, "sha-384", HashAlgorithm::Sha384, 48),
+        (8, "sha-512", HashAlgorithm::Sha512, 64),
+        (9, "sha3-224", HashAlgorithm::Sha3_224, 28),
+        (10, "sha3-256", HashAlgorithm::Sha3_256, 32),
+        (11, "sha3-384", HashAlgorithm::Sha3_384, 48),
+        (12, "sha3-512", HashAlgorithm::Sha3_512, 64),
+    ];
+
+    /// The registered name of the algorithm.
+    pub fn name(self) -> &'static str {
+        Self::REGISTRY
+            .iter()
+            .find(|(_, _, alg, _)| *alg == self)
+            .map(|(_, name, _, _)| *name)
+            .unwrap_or("unknown")
+    }
+
+    /// The length of a value in bytes.
+    pub fn value_len(self) -> usize {
+        Self::REGISTRY
+            .iter()
+            .find(|(_, _, alg, _)| *alg == self)
+            .map(|(_, _, _, len)| *len)
+            .unwrap_or(0)
+    }
+
+    /// Returns Ok if names with this algorithm can be used, or an
+    /// error that explains why not.
+    pub fn check_supported(self) -> anyhow::Result<()> {
+        match self {
+            HashAlgorithm::Sha256 => Ok(()),
+            HashAlgorithm::Sha256Truncated(_) => Err(anyhow!(
+                "truncated hashing algorithm {} is not supported: \
+                 objects must be named by their full SHA-256 hash",
+                self.name()
+            )),
+            _ => Err(anyhow!(
+                "unsupported hashing algorithm: {}, only sha-256 is supported",
+                self.name()
+            )),
+        }
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = anyhow::Error;
+
+    /// Parses the algorithm by its name, ignoring case. The registry
+    /// also allows the suite ID to be used instead.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_ascii_lowercase();
+        Self::REGISTRY
+            .iter()
+            .find(|(id, name, _, _)| *name == lower || id.to_string() == lower)
+            .map(|(_, _, alg, _)| *alg)
+            .ok_or_else(|| anyhow!("unknown hashing algorithm: {s}"))
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// The ni name of an object, named by its SHA-256 hash.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NamedInformation {
+    /// The authority that can be asked for the object, if any.
+    authority: Option<String>,
+
+    hash: Hash,
+
+    /// A hint for the content type of the object.
+    content_type: Option<String>,
+}
+
+impl NamedInformation {
+    pub fn new(hash: Hash) -> Self {
+        NamedInformation {
+            authority: None,
+            hash,
+            content_type: None,
+        }
+    }
+
+    pub fn with_authority(mut self, authority: impl Into<String>) -> Self {
+        self.authority = Some(authority.into());
+        self
+    }
+
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    /// Parses the algorithm and value as they appear in the
+    /// `.well-known` path, with the value of the `ct` query
+    /// parameter, if any.
+    pub fn from_well_known(
+        alg: &str,
+        value: &str,
+        content_type: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let alg = HashAlgorithm::from_str(alg)?;
+        alg.check_supported()?;
+        let bytes = URL_SAFE_NO_PAD
+            .decode(value.as_bytes())
+            .map_err(|_| anyhow!("invalid hash value, expected unpadded base64url: {value}"))?;
+        if bytes.len() != alg.value_len() {
+            return Err(anyhow!(
+                "invalid hash value for {alg}: expected {} bytes, got {}",
+                alg.value_len(),
+                bytes.len()
+            ));
+        }
+        let hash = Hash::try_from(bytes.as_slice()).map_err(|_| anyhow!("invalid hash value"))?;
+
+        let mut ni = NamedInformation::new(hash);
+        if let Some(content_type) = content_type {
+            ni = ni.with_content_type(check_media_type(content_type)?);
+        }
+        Ok(ni)
+    }
+
+    pub fn authority(&self) -> Option<&str> {
+        self.authority.as_deref()
+    }
+
+    pub fn hash(&self) -> Hash {
+        self.hash
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    /// The hash value as unpadded base64url.
+    pub fn value(&self) -> String {
+        URL_SAFE_NO_PAD.encode(self.hash.as_slice())
+    }
+
+    /// The path of the object under the `.well-known` HTTP mapping,
+    /// without the content type hint.
+    pub fn well_known_path(&self) -> String {
+        format!(
+            "{WELL_KNOWN_PATH}/{}/{}",
+            HashAlgorithm::Sha256,
+            self.value()
+        )
+    }
+
+    /// The URI of the object at the given relay. The content type hint
+    /// is not included, because `uri::Https` cannot have a query.
+    pub fn well_known_uri(&self, server: &uri::Https) -> anyhow::Result<uri::Https> {
+        Ok(server.join(self.well_known_path().as_bytes())?)
+    }
+
+    /// The URI of the object at its authority, if it has one.
+    pub fn authority_uri(&self) -> Option<anyhow::Result<uri::Https>> {
+        self.authority.as_ref().map(|authority| {
+            let server = uri::Https::from_string(format!("https://{authority}/"))?;
+            self.well_known_uri(&server)
+        })
+    }
+}
+
+impl FromStr for NamedInformation {
+    type Err = anyhow::Error;
+
+    /// Parses an ni URI: `ni://[authority]/alg;value[?ct=type]`. The
+    /// scheme is case-insensitive.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .get(..5)
+            .filter(|scheme| scheme.eq_ignore_ascii_case("ni://"))
+            .map(|_| &s[5..])
+            .ok_or_else(|| anyhow!("not an ni URI: {s}"))?;
+
+        let (authority, rest) = rest
+            .split_once('/')
+            .ok_or_else(|| anyhow!("ni URI lacks a path: {s}"))?;
+        let (name, query) = match rest.split_once('?') {
+            Some((name, query)) => (name, Some(query)),
+            None => (rest, None),
+        };
+        let (alg, value) = name
+            .split_once(';')
+            .ok_or_else(|| anyhow!("ni URI lacks 'alg;value': {s}"))?;
+
+        let mut content_type = None;
+        for param in query.into_iter().flat_map(|query| query.split('&')) {
+            // Other parameters are allowed, but we do not use them.
+            if let Some(("ct", value)) = param.split_once('=') {
+                content_type = Some(value);
+            }
+        }
+
+        let mut ni = Self::from_well_known(alg, value, content_type)?;
+        if !authority.is_empty() {
+            ni = ni.with_authority(authority);
+        }
+        Ok(ni)
+    }
+}
+
+impl fmt::Display for NamedInformation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ni://{}/{};{}",
+            self.authority.as_deref().unwrap_or_default(),
+            HashAlgorithm::Sha256,
+            self.value()
+        )?;
+        if let Some(content_type) = &self.content_type {
+            write!(f, "?ct={content_type}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::https;
+
+    use super::*;
+
+    // The example from section 8.1 of RFC 6920, the SHA-256 hash of
+    // "Hello World!".
+    const EXAMPLE: &str = "ni:///sha-256;f4OxZX_x_FO5LcGBSKHWXfwtSx-j1ncoSt3SABJtkGk";
+
+    #[test]
+    fn parse_and_display() {
+        let ni = NamedInformation::from_str(EXAMPLE).unwrap();
+        assert_eq!(Hash::from_data(b"Hello World!"), ni.hash());
+        assert_eq!(None, ni.authority());
+        assert_eq!(EXAMPLE, ni.to_string());
+
+        let full =
+            "ni://example.com/sha-256;f4OxZX_x_FO5LcGBSKHWXfwtSx-j1ncoSt3SABJtkGk?ct=text/plain";
+        let ni = NamedInformation::from_str(full).unwrap();
+        assert_eq!(Some("example.com"), ni.authority());
+        assert_eq!(Some("text/plain"), ni.content_type());
+        assert_eq!(full, ni.to_string());
+        assert_eq!(
+            https(
+                "https://example.com/.well-known/ni/sha-256/f4OxZX_x_FO5LcGBSKHWXfwtSx-j1ncoSt3SABJtkGk"
+            ),
+            ni.authority_uri().unwrap().unwrap()
+        );
+    }
+
+    #[test]
+    fn reject_unsupported_names() {
+        let value = "f4OxZX_x_FO5LcGBSKHWXfwtSx-j1ncoSt3SABJtkGk";
+
+        // Suite IDs may be used for the algorithm
+        assert!(NamedInformation::from_well_known("1", value, None).is_ok());
+
+        let truncated = NamedInformation::from_well_known("sha-256-32", "f4OxZQ", None);
+        assert!(truncated.unwrap_err().to_string().contains("truncated"));
+        let truncated = NamedInformation::from_well_known("sha-256-128", value, None);
+        assert!(truncated.unwrap_err().to_string().contains("truncated"));
+
+        let sha512 = NamedInformation::from_well_known("sha-512", value, None);
+        assert!(sha512.unwrap_err().to_string().contains("unsupported"));
+
+        let short = NamedInformation::from_well_known("sha-256", "f4OxZQ", None);
+        assert!(short.unwrap_err().to_string().contains("32 bytes"));
+
+        let unknown = NamedInformation::from_well_known("md5", value, None);
+        assert!(unknown.unwrap_err().to_string().contains("md5"));
+
+        let padded = format!("{value}=");
+        assert!(NamedInformation::from_well_known("sha-256", &padded, None).is_err());
+        assert!(NamedInformation::from_well_known("sha-256", value, Some("text")).is_err());
+        assert!(NamedInformation::from_str("http://example.com/sha-256;abc").is_err());
+    }
+}
@@ -0,0 +1,481 @@
+//! The content served by an Erik relay.
+
+use std::{collections::HashMap, sync::Arc};
+
+use bytes::Bytes;
+use rpki::{
+    crypto::KeyIdentifier,
+    dep::bcder::{Mode, encode::Values},
+    repository::{Manifest, x509::Time},
+    rrdp::Hash,
+    uri,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    erik::{
+        asn1::{ErikIndex, ErikPartition, ErikPartitionEncoder, ManifestRef},
+        media_type::ObjectKind,
+        state::{ErikPartitionKey, ResolvedErikIndex},
+    },
+    fetch::{pool::RepositoryPool, retrieval::Fqdn, rrdp::RepoContentElement},
+};
+
+/// A snapshot of everything a relay serves: the encoded Erik index
+/// for each index scope, the encoded partitions, and all repository
+/// objects by their hash.
+///
+/// This is immutable once built. Updates result in a new snapshot
+/// that replaces the current one.
+#[derive(Clone, Debug, Default)]
+pub struct RelayContent {
+    elements: HashMap<Hash, Arc<RepoContentElement>>,
+
+    /// The hash of the current object at each URI.
+    object_hashes: HashMap<uri::Rsync, Hash>,
+
+    indexes: HashMap<Fqdn, Bytes>,
+    partitions: HashMap<Hash, Bytes>,
+    summaries: HashMap<Fqdn, IndexSummary>,
+
+    /// The referenced manifests that we serve, with their next update,
+    /// soonest first. Manifests are only decoded for this once, when
+    /// the content is built.
+    next_updates: Vec<(Arc<ManifestRef>, Time)>,
+}
+
+/// What we need to know about an index to tell what changed.
+#[derive(Clone, Debug)]
+pub struct IndexSummary {
+    hash: Hash,
+    index_time: Time,
+    partitions: HashMap<ErikPartitionKey, Hash>,
+}
+
+impl IndexSummary {
+    /// The hash of the encoded index.
+    pub fn hash(&self) -> Hash {
+        self.hash
+    }
+
+    pub fn index_time(&self) -> Time {
+        self.index_time
+    }
+
+    pub fn partition_count(&self) -> usize {
+        self.partitions.len()
+    }
+
+    /// The hashes of the partitions, by their key.
+    pub fn partitions(&self) -> impl Iterator<Item = (&ErikPartitionKey, &Hash)> {
+        self.partitions.iter()
+    }
+}
+
+/// Announces a new index for a scope.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct IndexUpdate {
+    pub fqdn: Fqdn,
+
+    /// The hash of the encoded index.
+    pub hash: Hash,
+
+    pub index_time: Time,
+
+    /// The keys of the partitions that were added, changed, or
+    /// removed since the previous index.
+    pub changed_partitions: Vec<ErikPartitionKey>,
+}
+
+/// Announces that a scope no longer has an index.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct IndexRemoval {
+    pub fqdn: Fqdn,
+}
+
+/// A change to the index for a scope.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum IndexEvent {
+    Update(IndexUpdate),
+    Removal(IndexRemoval),
+}
+
+impl IndexEvent {
+    /// The name of the event in an events stream.
+    pub const UPDATE: &str = "index";
+    pub const REMOVAL: &str = "removed";
+
+    pub fn fqdn(&self) -> &Fqdn {
+        match self {
+            IndexEvent::Update(update) => &update.fqdn,
+            IndexEvent::Removal(removal) => &removal.fqdn,
+        }
+    }
+}
+
+impl RelayContent {
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    /// Builds the content for all index scopes in the given pool.
+    pub fn from_pool(pool: &RepositoryPool) -> Self {
+        Self::build(pool.elements(), pool.object_hashes(), pool.indexes())
+    }
+
+    fn build(
+        elements: HashMap<Hash, Arc<RepoContentElement>>,
+        object_hashes: HashMap<uri::Rsync, Hash>,
+        resolved: HashMap<Fqdn, ResolvedErikIndex>,
+    ) -> Self {
+        let mut indexes = HashMap::new();
+        let mut partitions = HashMap::new();
+        let mut summaries = HashMap::new();
+        let mut next_updates = vec![];
+
+        for (fqdn, index) in resolved {
+            let mut partition_hashes = HashMap::new();
+            for (key, partition) in &index.partitions {
+                for mft_ref in &partition.manifest_refs {
+                    let next_update = elements.get(&mft_ref.hash).and_then(|el| {
+                        Manifest::decode(el.data().as_ref(), false)
+                            .ok()
+                            .map(|manifest| manifest.next_update())
+                    });
+                    if let Some(next_update) = next_update {
+                        next_updates.push((mft_ref.clone(), next_update));
+                    }
+                }
+                let bytes = ErikPartitionEncoder::from(partition)
+                    .to_captured()
+                    .into_bytes();
+                let hash = Hash::from_data(&bytes);
+                partition_hashes.insert(*key, hash);
+                partitions.insert(hash, bytes);
+            }
+
+            let index_bytes = ErikIndex::from(&index)
+                .encode()
+                .to_captured(Mode::Der)
+                .into_bytes();
+            summaries.insert(
+                fqdn.clone(),
+                IndexSummary {
+                    hash: Hash::from_data(&index_bytes),
+                    index_time: index.index_time,
+                    partitions: partition_hashes,
+                },
+            );
+            indexes.insert(fqdn, index_bytes);
+        }
+
+        next_updates.sort_by_key(|(_, next_update)| *next_update);
+
+        RelayContent {
+            elements,
+            object_hashes,
+            indexes,
+            partitions,
+            summaries,
+            next_updates,
+        }
+    }
+
+    /// Returns an update for each index that is new or different
+    /// compared to the previous content, and a removal for each scope
+    /// that no longer has an index.
+    pub fn index_events(&self, previous: &RelayContent) -> Vec<IndexEvent> {
+        let mut events = vec![];
+        for (fqdn, summary) in &self.summaries {
+            let old = previous.summaries.get(fqdn);
+            if old.is_some_and(|old| old.hash == summary.hash) {
+                continue;
+            }
+
+            let mut changed_partitions: Vec<ErikPartitionKey> = summary
+                .partitions
+                .iter()
+                .filter(|(key, hash)| old.and_then(|old| old.partitions.get(key)) != Some(hash))
+                .map(|(key, _)| *key)
+                .chain(old.into_iter().flat_map(|old| {
+                    old.partitions
+                        .keys()
+                        .filter(|key| !summary.partitions.contains_key(key))
+                        .copied()
+                }))
+                .collect();
+            changed_partitions.sort();
+
+            events.push(IndexEvent::Update(IndexUpdate {
+                fqdn: fqdn.clone(),
+                hash: summary.hash,
+                index_time: summary.index_time,
+                changed_partitions,
+            }));
+        }
+        events.extend(
+            previous
+                .summaries
+                .keys()
+                .filter(|fqdn| !self.summaries.contains_key(fqdn))
+                .map(|fqdn| IndexEvent::Removal(IndexRemoval { fqdn: fqdn.clone() })),
+        );
+        events
+    }
+
+    /// Returns the encoded Erik index for the given scope.
+    pub fn index(&self, fqdn: &Fqdn) -> Option<&Bytes> {
+        self.indexes.get(fqdn)
+    }
+
+    /// Returns all encoded Erik indexes by their scope.
+    pub fn indexes(&self) -> impl Iterator<Item = (&Fqdn, &Bytes)> {
+        self.indexes.iter()
+    }
+
+    /// Returns a summary of each index by its scope.
+    pub fn index_summaries(&self) -> impl Iterator<Item = (&Fqdn, &IndexSummary)> {
+        self.summaries.iter()
+    }
+
+    /// Returns the manifest references in all indexes for manifests
+    /// that we serve, with their next update, soonest first.
+    pub fn next_updates(&self) -> &[(Arc<ManifestRef>, Time)] {
+        &self.next_updates
+    }
+
+    /// Returns the encoded partition with the given hash.
+    pub fn partition(&self, hash: &Hash) -> Option<&Bytes> {
+        self.partitions.get(hash)
+    }
+
+    /// Returns the hash of the partition with the given key in the
+    /// index for the given scope.
+    pub fn partition_hash(&self, fqdn: &Fqdn, key: ErikPartitionKey) -> Option<Hash> {
+        self.summaries.get(fqdn)?.partitions.get(&key).copied()
+    }
+
+    /// Returns the manifest references for the given AKI, from all
+    /// indexes. The AKI tells which partition they are in.
+    pub fn manifest_refs(&self, aki: &KeyIdentifier) -> Vec<Arc<ManifestRef>> {
+        let key = ErikPartitionKey::from(aki);
+        self.summaries
+            .values()
+            .filter_map(|summary| summary.partitions.get(&key))
+            .filter_map(|hash| self.partitions.get(hash))
+            .filter_map(|bytes| ErikPartition::decode(bytes.as_ref()).ok())
+            .flat_map(|partition| partition.manifest_refs)
+            .filter(|mft_ref| mft_ref.aki == *aki)
+            .collect()
+    }
+
+    /// Returns the repository object with the given hash.
+    pub fn element(&self, hash: &Hash) -> Option<&Arc<RepoContentElement>> {
+        self.elements.get(hash)
+    }
+
+    /// Returns the current repository object published at the given
+    /// URI, with its hash.
+    pub fn element_by_uri(&self, uri: &uri::Rsync) -> Option<(&Hash, &Arc<RepoContentElement>)> {
+        let hash = self.object_hashes.get(uri)?;
+        self.elements.get_key_value(hash)
+    }
+
+    /// Returns all objects, repository objects and Erik partitions,
+    /// by their hash.
+    pub fn objects(&self) -> impl Iterator<Item = (&Hash, &Bytes)> {
+        self.elements
+            .iter()
+            .map(|(hash, el)| (hash, el.data()))
+            .chain(self.partitions.iter())
+    }
+
+    /// Returns the partition with the given hash, together with all
+    /// the manifests it refers to and the files on those manifests.
+    ///
+    /// Returns None if there is no such partition, or if we do not
+    /// have everything it refers to. A bundle never changes, so it
+    /// must be complete.
+    pub fn bundle(&self, partition_hash: &Hash) -> Option<Vec<(Hash, Bytes)>> {
+        let partition_bytes = self.partitions.get(partition_hash)?;
+        let partition = ErikPartition::decode(partition_bytes.as_ref()).ok()?;
+
+        let mut objects = vec![(*partition_hash, partition_bytes.clone())];
+        for mft_ref in &partition.manifest_refs {
+            let mft = self.elements.get(&mft_ref.hash)?;
+            objects.push((mft_ref.hash, mft.data().clone()));
+
+            let manifest = Manifest::decode(mft.data().as_ref(), false).ok()?;
+            for item in manifest.content().iter() {
+                let hash = Hash::try_from(item.hash().as_ref()).ok()?;
+                objects.push((hash, self.elements.get(&hash)?.data().clone()));
+            }
+        }
+        Some(objects)
+    }
+
+    /// Returns an object by its hash. This can be a repository
+    /// object or an Erik partition.
+    pub fn object(&self, hash: &Hash) -> Option<&Bytes> {
+        self.elements
+            .get(hash)
+            .map(|el| el.data())
+            .or_else(|| self.partitions.get(hash))
+    }
+
+    /// Returns the kind of an object by its hash. Repository objects
+    /// are known by the extension of their URI, and by their content
+    /// if that does not tell.
+    pub fn object_kind(&self, hash: &Hash) -> Option<ObjectKind> {
+        if let Some(el) = self.elements.get(hash) {
+            return Some(match ObjectKind::from_uri(el.uri()) {
+                ObjectKind::Unknown => ObjectKind::from_content(el.data()),
+                kind => kind,
+            });
+        }
+        self.partitions
+            .contains_key(hash)
+            .then_some(ObjectKind::ErikPartition)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        fetch::{pool::DEFAULT_REFRESH_SECONDS, retrieval::FetchMapper},
+        util::{TestRrdpSource, https, rsync, test_with_dir},
+    };
+
+    use super::*;
+
+    fn test_content() -> RelayContent {
+        let notify = https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+        let mut mapper = FetchMapper::empty();
+        mapper.add_disk_mapper(
+            (&notify).into(),
+            PathBuf::from("test-resources/rrdp-rev2656/"),
+        );
+        let mut pool = RepositoryPool::empty();
+        pool.add(notify.into(), mapper, DEFAULT_REFRESH_SECONDS)
+            .unwrap();
+        pool.update_due();
+        RelayContent::from_pool(&pool)
+    }
+
+    #[test]
+    fn index_events_since_previous() {
+        let content = test_content();
+
+        let events = content.index_events(&RelayContent::empty());
+        assert_eq!(1, events.len());
+        let IndexEvent::Update(update) = &events[0] else {
+            panic!("expected an update, got {:?}", events[0]);
+        };
+        let (fqdn, bytes) = content.indexes().next().unwrap();
+        assert_eq!(fqdn, &update.fqdn);
+        assert_eq!(Hash::from_data(bytes), update.hash);
+        assert_eq!(
+            content.summaries[fqdn].partitions.len(),
+            update.changed_partitions.len()
+        );
+
+        assert!(content.index_events(&content).is_empty());
+
+        let events = RelayContent::empty().index_events(&content);
+        assert_eq!(
+            vec![IndexEvent::Removal(IndexRemoval { fqdn: fqdn.clone() })],
+            events
+        );
+    }
+
+    #[test]
+    fn bundle_for_partition() {
+        let content = test_content();
+        let (partition_hash, partition_bytes) = content.partitions.iter().next().unwrap();
+        let partition = ErikPartition::decode(partition_bytes.as_ref()).unwrap();
+
+        let bundle = content.bundle(partition_hash).unwrap();
+        assert_eq!(partition_hash, &bundle[0].0);
+        for mft_ref in &partition.manifest_refs {
+            assert!(bundle.iter().any(|(hash, _)| hash == &mft_ref.hash));
+        }
+        // There are files on the manifests too
+        assert!(bundle.len() > partition.manifest_refs.len() + 1);
+        for (hash, data) in &bundle {
+            assert!(hash.matches(data.as_ref()));
+        }
+
+        assert!(content.bundle(&Hash::from_data(b"unknown")).is_none());
+
+        // Without a file on one of the manifests there is no bundle.
+        let mut incomplete = content.clone();
+        let file_hash = bundle.last().unwrap().0;
+        incomplete.elements.remove(&file_hash);
+        assert!(incomplete.bundle(partition_hash).is_none());
+    }
+
+    #[test]
+    fn kinds_of_objects() {
+        let content = test_content();
+        let (partition_hash, partition_bytes) = content.partitions.iter().next().unwrap();
+        let partition = ErikPartition::decode(partition_bytes.as_ref()).unwrap();
+
+        assert_eq!(
+            Some(ObjectKind::ErikPartition),
+            content.object_kind(partition_hash)
+        );
+        assert_eq!(
+            Some(ObjectKind::Manifest),
+            content.object_kind(&partition.manifest_refs.iter().next().unwrap().hash)
+        );
+        assert_eq!(None, content.object_kind(&Hash::from_data(b"unknown")));
+    }
+
+    #[test]
+    fn look_up_content() {
+        let content = test_content();
+        let (fqdn, summary) = content.index_summaries().next().unwrap();
+        let (key, hash) = summary.partitions.iter().next().unwrap();
+        assert_eq!(Some(*hash), content.partition_hash(fqdn, *key));
+
+        let partition = ErikPartition::decode(content.partition(hash).unwrap().as_ref()).unwrap();
+        let mft_ref = partition.manifest_refs.iter().next().unwrap();
+        assert_eq!(vec![mft_ref.clone()], content.manifest_refs(&mft_ref.aki));
+
+        let (found, element) = content.element_by_uri(&mft_ref.locations).unwrap();
+        assert_eq!(&mft_ref.hash, found);
+        assert_eq!(element.uri(), content.element(found).unwrap().uri());
+
+        let manifest = Manifest::decode(element.data().as_ref(), false).unwrap();
+        let next_updates = content.next_updates();
+        assert!(next_updates.contains(&(mft_ref.clone(), manifest.next_update())));
+        assert!(next_updates.is_sorted_by_key(|(_, next_update)| *next_update));
+    }
+
+    #[test]
+    fn element_by_uri_is_current() {
+        test_with_dir("relay_element_by_uri", |dir| {
+            let uri = "rsync://example.net/repo/object.roa";
+            let mut source = TestRrdpSource::create(dir, &[(uri, "old")]);
+            let notify = source.notify();
+            let mut pool = RepositoryPool::empty();
+            pool.add(
+                notify.clone().into(),
+                source.fetch_mapper(),
+                DEFAULT_REFRESH_SECONDS,
+            )
+            .unwrap();
+            pool.update_due();
+
+            source.change(&[(uri, Some("new"))]);
+            assert!(pool.update(&notify.into()).unwrap());
+
+            let content = RelayContent::from_pool(&pool);
+            let (hash, element) = content.element_by_uri(&rsync(uri)).unwrap();
+            assert_eq!(&Hash::from_data(b"new"), hash);
+            assert_eq!(b"new", element.data().as_ref());
+        });
+    }
+}
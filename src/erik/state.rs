@@ -1,9 +1,10 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, num::ParseIntError, str::FromStr, sync::Arc};
 
-use rpki::repository::x509::Time;
+use rpki::{crypto::KeyIdentifier, repository::x509::Time};
+use serde::{Deserialize, Serialize};
 
 use crate::erik::asn1;
-use crate::fetch::rrdp::RepoContent;
+use crate::fetch::{retrieval::Fqdn, rrdp::RepoContent};
 
 /// The Erik Partition key is used to determine
 /// which partition should be used for a ManifestRef
@@ -13,12 +14,32 @@ use crate::fetch::rrdp::RepoContent;
 /// much easier to take the first full byte from the
 /// authority key identifier, rather than the first
 /// 10 bits.
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
 pub struct ErikPartitionKey(u8);
 
 impl From<&asn1::ManifestRef> for ErikPartitionKey {
     fn from(mft_ref: &asn1::ManifestRef) -> Self {
-        Self(mft_ref.aki.as_slice()[0])
+        Self::from(&mft_ref.aki)
+    }
+}
+
+impl From<&KeyIdentifier> for ErikPartitionKey {
+    fn from(aki: &KeyIdentifier) -> Self {
+        Self(aki.as_slice()[0])
+    }
+}
+
+impl FromStr for ErikPartitionKey {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        u8::from_str(s).map(Self)
+    }
+}
+
+impl fmt::Display for ErikPartitionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
     }
 }
 
@@ -26,19 +47,34 @@ impl From<&asn1::ManifestRef> for ErikPartitionKey {
 #[derive(Clone, Debug)]
 pub struct ResolvedErikIndex {
     // version [0]
-    pub index_scope: String, // FQDN, perhaps we should use a strong type
+    pub index_scope: Fqdn,
     pub index_time: Time,
     // hashAlg RSA-256
     pub partitions: HashMap<ErikPartitionKey, asn1::ErikPartition>,
 }
 
 impl ResolvedErikIndex {
-    /// Creates and ErikIndex from the given content.
-    pub fn from_content(index_scope: String, content: &RepoContent) -> Option<Self> {
-        let mut partitions: HashMap<ErikPartitionKey, asn1::ErikPartition> = HashMap::new();
+    /// Creates an ErikIndex for each FQDN found in the given content.
+    pub fn from_content(content: &RepoContent) -> HashMap<Fqdn, Self> {
+        Self::from_manifests(content.manifests().values())
+    }
 
-        for mft_ref in content.manifests().values() {
+    /// Creates an ErikIndex for each FQDN found in the given manifest
+    /// references.
+    ///
+    /// The draft scopes an index by the FQDN where the objects are
+    /// published, so manifest references are grouped by the host of
+    /// their locations.
+    pub fn from_manifests<'a>(
+        manifests: impl IntoIterator<Item = &'a Arc<asn1::ManifestRef>>,
+    ) -> HashMap<Fqdn, Self> {
+        let mut scoped: HashMap<Fqdn, HashMap<ErikPartitionKey, asn1::ErikPartition>> =
+            HashMap::new();
+
+        for mft_ref in manifests {
+            let index_scope = Fqdn::from(&mft_ref.locations);
             let partition_key = ErikPartitionKey::from(mft_ref.as_ref());
+            let partitions = scoped.entry(index_scope).or_default();
 
             if let Some(partition) = partitions.get_mut(&partition_key) {
                 partition.add_manifest_ref(mft_ref.clone());
@@ -50,6 +86,19 @@ impl ResolvedErikIndex {
             }
         }
 
+        scoped
+            .into_iter()
+            .flat_map(|(index_scope, partitions)| {
+                Self::from_partitions(index_scope.clone(), partitions)
+                    .map(|index| (index_scope, index))
+            })
+            .collect()
+    }
+
+    fn from_partitions(
+        index_scope: Fqdn,
+        partitions: HashMap<ErikPartitionKey, asn1::ErikPartition>,
+    ) -> Option<Self> {
         // If partitions is empty we return None, otherwise we find the
         // most recent partition time among partitions and return Some
         // ErikIndex using that valid as its index_time.
@@ -80,4 +129,23 @@ mod tests {
 
         let _manifest_ref = asn1::ManifestRef::try_from(&manifest).unwrap();
     }
+
+    #[test]
+    fn index_scopes_from_manifest_locations() {
+        let content = RepoContent::create_test().unwrap();
+        let indexes = ResolvedErikIndex::from_content(&content);
+
+        let fqdn: Fqdn = "krill-ui-dev.do.nlnetlabs.nl".parse().unwrap();
+        assert_eq!(1, indexes.len());
+
+        let index = indexes.get(&fqdn).unwrap();
+        assert_eq!(fqdn, index.index_scope);
+
+        let manifest_count: usize = index
+            .partitions
+            .values()
+            .map(|p| p.manifest_refs.len())
+            .sum();
+        assert_eq!(content.manifests().len(), manifest_count);
+    }
 }
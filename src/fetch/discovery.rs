@@ -0,0 +1,388 @@
+//! Discover RRDP repositories by crawling the RPKI from TAL files.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, anyhow};
+use log::{debug, info, warn};
+use rpki::{
+    repository::{Cert, tal::Tal, tal::TalUri},
+    uri,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    fetch::{
+        pool::{RepositoryPool, RepositorySource},
+        retrieval::FetchMapper,
+    },
+    util::Time,
+};
+
+/// The default maximum number of repositories to discover.
+pub const DEFAULT_MAX_REPOSITORIES: usize = 1000;
+
+/// The default maximum depth of the crawl, where depth 0 are the
+/// trust anchor certificates.
+pub const DEFAULT_MAX_DEPTH: usize = 32;
+
+/// Limits to stop a crawl from running away.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryLimits {
+    /// The maximum number of repositories in the pool. Discovery
+    /// will not add repositories beyond this.
+    pub max_repositories: usize,
+
+    /// The maximum depth of the crawl below the trust anchors.
+    pub max_depth: usize,
+}
+
+impl Default for DiscoveryLimits {
+    fn default() -> Self {
+        DiscoveryLimits {
+            max_repositories: DEFAULT_MAX_REPOSITORIES,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+}
+
+/// Discovers RRDP repositories starting from TAL files.
+///
+/// The trust anchor certificates are fetched using their HTTPS URIs.
+/// From there, the CA certificates are followed down the tree: the
+/// children of a CA are the CA certificates published under its
+/// `caRepository`, in whichever repository of the pool they are. The
+/// `rpkiNotify` of every CA that is found is added to the pool, so
+/// that its children can be found once it was fetched.
+///
+/// As the content of the pool changes all the time, `discover` should
+/// be called after each update of the pool.
+///
+/// Note that this does NOT validate anything. It only follows the
+/// SIAs of everything that looks like a CA certificate.
+#[derive(Clone, Debug)]
+pub struct Discovery {
+    tal_files: Vec<PathBuf>,
+    fetch_mapper: FetchMapper,
+    limits: DiscoveryLimits,
+    refresh: i64,
+
+    /// The trust anchor certificates that were fetched, by TAL file.
+    trust_anchors: HashMap<PathBuf, Cert>,
+
+    /// When to try again to fetch missing trust anchor certificates.
+    next_fetch: Time,
+}
+
+impl Discovery {
+    pub fn new(
+        tal_files: Vec<PathBuf>,
+        fetch_mapper: FetchMapper,
+        limits: DiscoveryLimits,
+        refresh: i64,
+    ) -> Self {
+        Discovery {
+            tal_files,
+            fetch_mapper,
+            limits,
+            refresh,
+            trust_anchors: HashMap::new(),
+            next_fetch: Time::now(),
+        }
+    }
+
+    /// Fetches the trust anchor certificates that we do not have yet.
+    /// Failures are logged, and retried after the refresh time.
+    ///
+    /// Returns true if any certificate was fetched.
+    pub fn fetch_trust_anchors(&mut self) -> bool {
+        if self.trust_anchors.len() == self.tal_files.len() || self.next_fetch > Time::now() {
+            return false;
+        }
+        let mut fetched = false;
+        for tal_file in &self.tal_files {
+            if self.trust_anchors.contains_key(tal_file) {
+                continue;
+            }
+            match self.fetch_trust_anchor(tal_file) {
+                Ok(cert) => {
+                    self.trust_anchors.insert(tal_file.clone(), cert);
+                    fetched = true;
+                }
+                Err(e) => warn!("Skipping TAL {}: {e:#}", tal_file.display()),
+            }
+        }
+        self.next_fetch = Time::seconds_from_now(self.refresh);
+        fetched
+    }
+
+    /// Walks the CA certificates in the pool down from the trust
+    /// anchors, and adds the repositories of those CAs that are not in
+    /// the pool yet. Nothing is fetched here.
+    ///
+    /// Returns the notification URIs that were added.
+    pub fn discover(&self, pool: &mut RepositoryPool) -> Vec<uri::Https> {
+        let elements = pool.elements();
+
+        // The CA certificates in the pool, by the directory they are
+        // published in.
+        let mut published: HashMap<&str, Vec<Cert>> = HashMap::new();
+        for element in elements.values() {
+            let uri = element.uri().as_str();
+            if !uri.ends_with(".cer") {
+                continue;
+            }
+            if let Ok(cert) = Cert::decode(element.data().as_ref())
+                && cert.is_ca()
+            {
+                let dir = &uri[..=uri.rfind('/').unwrap_or(0)];
+                published.entry(dir).or_default().push(cert);
+            }
+        }
+
+        let mut seen: HashSet<&str> = HashSet::new();
+        let mut added = vec![];
+        let mut current: Vec<&Cert> = self.trust_anchors.values().collect();
+
+        for depth in 0..=self.limits.max_depth {
+            if current.is_empty() {
+                break;
+            }
+            debug!("Discovery at depth {depth}: {} CAs", current.len());
+
+            let mut next = vec![];
+            for cert in current {
+                if let Some(notify) = cert.rpki_notify() {
+                    let source = RepositorySource::Rrdp(notify.clone());
+                    if !pool.contains(&source) {
+                        if pool.len() >= self.limits.max_repositories {
+                            warn!(
+                                "Reached limit of {} repositories, stopping discovery",
+                                self.limits.max_repositories
+                            );
+                            return added;
+                        }
+                        if pool
+                            .add(source, self.fetch_mapper.clone(), self.refresh)
+                            .is_ok()
+                        {
+                            info!("Discovered repository {notify}");
+                            added.push(notify.clone());
+                        }
+                    }
+                }
+
+                // CAs may share a publication point with their parent,
+                // or loop. Each one is only walked once.
+                let Some(ca_repository) = cert.ca_repository() else {
+                    continue;
+                };
+                if seen.insert(ca_repository.as_str())
+                    && let Some(children) = published.get(ca_repository.as_str())
+                {
+                    next.extend(children);
+                }
+            }
+            current = next;
+        }
+
+        added
+    }
+
+    /// Reads the TAL and fetches the TA certificate from the first
+    /// HTTPS URI that works.
+    fn fetch_trust_anchor(&self, tal_file: &Path) -> anyhow::Result<Cert> {
+        let mut file = File::open(tal_file)
+            .with_context(|| format!("Cannot open TAL file {}", tal_file.display()))?;
+        let tal = Tal::read(tal_file, &mut file)
+            .map_err(|e| anyhow!("Cannot parse TAL file {}: {e}", tal_file.display()))?;
+
+        for tal_uri in tal.uris() {
+            let TalUri::Https(uri) = tal_uri else {
+                continue;
+            };
+
+            match self.fetch_ta_cert(uri, &tal) {
+                Ok(cert) => return Ok(cert),
+                Err(e) => warn!("Cannot get TA certificate from {uri}: {e:#}"),
+            }
+        }
+
+        Err(anyhow!("No usable HTTPS URI for TA certificate"))
+    }
+
+    fn fetch_ta_cert(&self, uri: &uri::Https, tal: &Tal) -> anyhow::Result<Cert> {
+        let bytes = self
+            .fetch_mapper
+            .resolve(uri.clone())
+            .fetch(None)?
+            .try_into_data()?;
+        let cert = Cert::decode(bytes.as_ref())?;
+
+        if cert.subject_public_key_info() != tal.key_info() {
+            return Err(anyhow!("TA certificate key does not match the TAL"));
+        }
+
+        Ok(cert)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rpki::{
+        crypto::{PublicKeyFormat, Signer, softsigner::OpenSslSigner},
+        repository::{
+            cert::{KeyUsage, Overclaim, TbsCert},
+            x509::{Serial, Validity},
+        },
+    };
+
+    use crate::{
+        fetch::pool::DEFAULT_REFRESH_SECONDS,
+        util::{self, https, test_with_dir},
+    };
+
+    use super::*;
+
+    /// Where the CA of the test TA certificate publishes.
+    const TA_CA_REPOSITORY: &str = "rsync://krill-ui-dev.do.nlnetlabs.nl/repo/Acme-Corp-Intl/0/";
+
+    fn test_discovery(limits: DiscoveryLimits) -> Discovery {
+        let mut mapper = FetchMapper::empty();
+        mapper.add_disk_mapper(
+            (&https("https://discovery.example.net/ta.cer")).into(),
+            PathBuf::from("test-resources/discovery/"),
+        );
+        mapper.add_disk_mapper(
+            (&https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml")).into(),
+            PathBuf::from("test-resources/rrdp-rev2656/"),
+        );
+
+        let mut discovery = Discovery::new(
+            vec![PathBuf::from("test-resources/discovery/test.tal")],
+            mapper,
+            limits,
+            DEFAULT_REFRESH_SECONDS,
+        );
+        assert!(discovery.fetch_trust_anchors());
+        assert!(!discovery.fetch_trust_anchors());
+        discovery
+    }
+
+    /// Returns a CA certificate with the given SIAs.
+    fn ca_cert(ca_repository: &str, notify: &str) -> Vec<u8> {
+        let signer = OpenSslSigner::new();
+        let key = signer.create_key(PublicKeyFormat::Rsa).unwrap();
+        let public_key = signer.get_key_info(&key).unwrap();
+        let mut tbs = TbsCert::new(
+            Serial::from(1u64),
+            public_key.to_subject_name(),
+            Validity::from_secs(3600),
+            None,
+            public_key,
+            KeyUsage::Ca,
+            Overclaim::Refuse,
+        );
+        tbs.set_basic_ca(Some(true));
+        tbs.set_ca_repository(Some(uri::Rsync::from_str(ca_repository).unwrap()));
+        tbs.set_rpki_manifest(Some(
+            uri::Rsync::from_string(format!("{ca_repository}child.mft")).unwrap(),
+        ));
+        tbs.set_rpki_notify(Some(https(notify)));
+        tbs.set_v4_resources_inherit();
+        tbs.set_v6_resources_inherit();
+        tbs.set_as_resources_inherit();
+        tbs.into_cert(&signer, &key)
+            .unwrap()
+            .to_captured()
+            .into_bytes()
+            .to_vec()
+    }
+
+    #[test]
+    fn discover_from_tal() {
+        test_with_dir("discover_from_tal", |dir| {
+            let discovery = test_discovery(DiscoveryLimits::default());
+            let mut pool = RepositoryPool::empty();
+
+            let notify = https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            assert_eq!(vec![notify.clone()], discovery.discover(&mut pool));
+            pool.update_due();
+            assert!(pool.state(&notify.into()).is_some());
+
+            // Nothing new is published under the TA yet.
+            assert!(discovery.discover(&mut pool).is_empty());
+
+            // Now a child CA shows up, in a repository that discovery
+            // walks but did not add. It shares the publication point
+            // with its parent.
+            let child = https("https://child.example.net/rrdp/notification.xml");
+            let published = dir
+                .join("krill-ui-dev.do.nlnetlabs.nl/repo/Acme-Corp-Intl/0")
+                .join("child.cer");
+            util::write_file(&published, &ca_cert(TA_CA_REPOSITORY, child.as_str())).unwrap();
+            pool.add(
+                RepositorySource::RsyncDir(dir.clone()),
+                FetchMapper::empty(),
+                DEFAULT_REFRESH_SECONDS,
+            )
+            .unwrap();
+            pool.update_due();
+
+            assert_eq!(vec![child.clone()], discovery.discover(&mut pool));
+            assert!(discovery.discover(&mut pool).is_empty());
+            assert_eq!(3, pool.len());
+        })
+    }
+
+    #[test]
+    fn discover_respects_limits() {
+        let discovery = test_discovery(DiscoveryLimits {
+            max_repositories: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+        });
+
+        let mut pool = RepositoryPool::empty();
+        assert!(discovery.discover(&mut pool).is_empty());
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn discover_stops_at_max_depth() {
+        test_with_dir("discover_stops_at_max_depth", |dir| {
+            let discovery = test_discovery(DiscoveryLimits {
+                max_repositories: DEFAULT_MAX_REPOSITORIES,
+                max_depth: 0,
+            });
+
+            let published = dir.join("krill-ui-dev.do.nlnetlabs.nl/repo/Acme-Corp-Intl/0");
+            util::write_file(
+                &published.join("child.cer"),
+                &ca_cert(
+                    "rsync://child.example.net/repo/child/",
+                    "https://child.example.net/rrdp/notification.xml",
+                ),
+            )
+            .unwrap();
+
+            let mut pool = RepositoryPool::empty();
+            pool.add(
+                RepositorySource::RsyncDir(dir.clone()),
+                FetchMapper::empty(),
+                DEFAULT_REFRESH_SECONDS,
+            )
+            .unwrap();
+            pool.update_due();
+
+            let notify = https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            assert_eq!(vec![notify], discovery.discover(&mut pool));
+            assert!(discovery.discover(&mut pool).is_empty());
+        })
+    }
+}
@@ -0,0 +1,752 @@
+//! Synchronise repository content from an Erik relay.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{BufRead, BufReader},
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::{Context, anyhow};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use bytes::Bytes;
+use log::{debug, info, warn};
+use rpki::{crypto::KeyIdentifier, repository::Manifest, rrdp::Hash, uri};
+
+use crate::{
+    erik::{
+        asn1::{ErikIndex, ErikPartition, ManifestRef},
+        batch, bundle,
+        ni::NamedInformation,
+        relay::IndexEvent,
+    },
+    fetch::{
+        retrieval::{FetchMapper, FetchResponse, Fqdn},
+        rrdp::RepoContentElement,
+    },
+};
+
+/// Retrieves files from an Erik relay.
+///
+/// Objects and partitions are retrieved by their hash, and checked
+/// against it.
+#[derive(Clone, Debug)]
+pub struct ErikRelayClient {
+    /// The base URI of the relay.
+    server: uri::Https,
+
+    /// The mapper used to retrieve Erik files.
+    fetch_mapper: FetchMapper,
+}
+
+impl ErikRelayClient {
+    pub fn new(server: uri::Https, fetch_mapper: FetchMapper) -> Self {
+        ErikRelayClient {
+            server,
+            fetch_mapper,
+        }
+    }
+
+    /// The base URI of the relay.
+    pub fn server(&self) -> &uri::Https {
+        &self.server
+    }
+
+    /// Fetches the encoded index for the given scope, unless it still
+    /// has the given ETag.
+    pub fn fetch_index(&self, fqdn: &Fqdn, etag: Option<&String>) -> anyhow::Result<FetchResponse> {
+        let uri = self
+            .server
+            .join(".well-known/erik/index/".as_ref())?
+            .join(fqdn.as_bytes())?;
+        self.fetch_mapper
+            .resolve(uri)
+            .fetch(etag)
+            .with_context(|| format!("Cannot get Erik index for {fqdn}"))
+    }
+
+    /// Fetches the object with the given hash, and checks that it
+    /// matches.
+    pub fn fetch_object(&self, hash: Hash) -> anyhow::Result<Bytes> {
+        let uri = NamedInformation::new(hash).well_known_uri(&self.server)?;
+        let bytes = self
+            .fetch_mapper
+            .resolve(uri)
+            .fetch(None)?
+            .try_into_data()
+            .with_context(|| format!("Cannot get object {hash}"))?;
+
+        if !hash.matches(bytes.as_ref()) {
+            return Err(anyhow!(
+                "Object retrieved for {hash} does not match its hash"
+            ));
+        }
+        Ok(bytes)
+    }
+
+    /// Fetches the objects with the given hashes, and checks that they
+    /// match. A batch request is used if the relay has a batch endpoint,
+    /// otherwise each object is fetched by itself.
+    pub fn fetch_objects(
+        &self,
+        hashes: &[Hash],
+        batch_uri: Option<&uri::Https>,
+    ) -> anyhow::Result<HashMap<Hash, Bytes>> {
+        let Some(batch_uri) = batch_uri else {
+            return hashes
+                .iter()
+                .map(|hash| self.fetch_object(*hash).map(|bytes| (*hash, bytes)))
+                .collect();
+        };
+
+        let mut objects = HashMap::new();
+        for chunk in hashes.chunks(batch::MAX_HASHES) {
+            debug!("Fetching {} objects from {batch_uri}", chunk.len());
+            let body = self
+                .fetch_mapper
+                .resolve(batch_uri.clone())
+                .post(batch::CONTENT_TYPE, batch::encode_request(chunk))?;
+            for (hash, data) in batch::decode_response(body)? {
+                let data = data.ok_or_else(|| anyhow!("Cannot get object {hash}"))?;
+                if !hash.matches(data.as_ref()) {
+                    return Err(anyhow!(
+                        "Object retrieved for {hash} does not match its hash"
+                    ));
+                }
+                objects.insert(hash, data);
+            }
+        }
+
+        if let Some(missing) = hashes.iter().find(|hash| !objects.contains_key(hash)) {
+            return Err(anyhow!("Batch response is missing object {missing}"));
+        }
+        Ok(objects)
+    }
+
+    /// Fetches the bundle for a partition, and returns the objects in
+    /// it. All objects are checked against their hash, and the bundle
+    /// must contain the partition itself.
+    pub fn fetch_bundle(
+        &self,
+        bundle_uri: &uri::Https,
+        partition: Hash,
+    ) -> anyhow::Result<HashMap<Hash, Bytes>> {
+        let uri = bundle_uri.join(URL_SAFE_NO_PAD.encode(partition.as_slice()).as_bytes())?;
+        debug!("Fetching bundle {uri}");
+        let data = self
+            .fetch_mapper
+            .resolve(uri)
+            .fetch(None)?
+            .try_into_data()
+            .with_context(|| format!("Cannot get bundle for partition {partition}"))?;
+
+        let objects = bundle::read_bundle(data.as_ref())?;
+        if !objects.contains_key(&partition) {
+            return Err(anyhow!(
+                "Bundle for partition {partition} lacks the partition"
+            ));
+        }
+        Ok(objects)
+    }
+
+    /// Returns the target of the link with the given relation in a
+    /// Link header, if any.
+    pub fn link_target(&self, link: &str, rel: &str) -> Option<uri::Https> {
+        link.split(',').find_map(|link_value| {
+            let mut parts = link_value.split(';').map(str::trim);
+            let target = parts.next()?.strip_prefix('<')?.strip_suffix('>')?;
+            let matches = parts.any(|param| {
+                param
+                    .strip_prefix("rel=")
+                    .is_some_and(|value| value.trim_matches('"') == rel)
+            });
+            if !matches {
+                return None;
+            }
+
+            if let Some(path) = target.strip_prefix('/') {
+                let root = format!("https://{}/", self.server.authority());
+                uri::Https::from_string(root)
+                    .ok()?
+                    .join(path.as_bytes())
+                    .ok()
+            } else if target.starts_with("https://") {
+                uri::Https::from_str(target).ok()
+            } else {
+                self.server.join(target.as_bytes()).ok()
+            }
+        })
+    }
+
+    /// Follows the events stream of the relay, and calls `op` for
+    /// each index update or removal for the scope. Returns when the
+    /// stream ends, or when `op` fails.
+    pub fn follow_events<F>(&self, fqdn: &Fqdn, op: F) -> anyhow::Result<()>
+    where
+        F: FnMut(IndexEvent) -> anyhow::Result<()>,
+    {
+        let uri = self
+            .server
+            .join("events/".as_ref())?
+            .join(fqdn.as_bytes())?;
+        let response = self
+            .fetch_mapper
+            .resolve(uri)
+            .open_stream("text/event-stream")?;
+        read_index_events(BufReader::new(response), op)
+    }
+}
+
+/// Reads Server-Sent Events, and calls `op` for each `index` and
+/// `removed` event.
+///
+/// Only the parts of the format that the relay uses are supported:
+/// `event` and `data` fields. Comments, like keep-alives, and other
+/// fields are ignored.
+fn read_index_events<F>(reader: impl BufRead, mut op: F) -> anyhow::Result<()>
+where
+    F: FnMut(IndexEvent) -> anyhow::Result<()>,
+{
+    let mut event = String::new();
+    let mut data = String::new();
+
+    for line in reader.lines() {
+        let line = line.context("Cannot read events stream")?;
+        if line.is_empty() {
+            let parsed = match event.as_str() {
+                _ if data.is_empty() => None,
+                IndexEvent::UPDATE => Some(serde_json::from_str(&data).map(IndexEvent::Update)),
+                IndexEvent::REMOVAL => Some(serde_json::from_str(&data).map(IndexEvent::Removal)),
+                _ => None,
+            };
+            if let Some(parsed) = parsed {
+                op(parsed.with_context(|| format!("Invalid {event} event: {data}"))?)?;
+            }
+            event.clear();
+            data.clear();
+        } else if let Some(value) = line.strip_prefix("event:") {
+            event = value.trim_start().to_string();
+        } else if let Some(value) = line.strip_prefix("data:") {
+            if !data.is_empty() {
+                data.push('\n');
+            }
+            data.push_str(value.strip_prefix(' ').unwrap_or(value));
+        }
+    }
+    Ok(())
+}
+
+/// The current content for an index scope at an Erik relay.
+///
+/// Updates start from the index. Only partitions that changed are
+/// fetched, and only objects that we do not have yet are fetched.
+/// Everything is retrieved by hash, so content is verified against
+/// the hash that referred to it.
+#[derive(Debug)]
+pub struct ErikSyncState {
+    /// The relay to synchronise from.
+    client: ErikRelayClient,
+
+    /// The index scope to synchronise.
+    fqdn: Fqdn,
+
+    /// The ETag of the index that we have, if the relay gave one.
+    index_etag: Option<String>,
+
+    /// The batch endpoint of the relay, if it advertises one.
+    batch_uri: Option<uri::Https>,
+
+    /// The bundle endpoint of the relay, if it advertises one.
+    bundle_uri: Option<uri::Https>,
+
+    /// The current partitions by their hash.
+    partitions: HashMap<Hash, ErikPartition>,
+
+    /// All current elements by their SHA256 hash.
+    elements: HashMap<Hash, Arc<RepoContentElement>>,
+
+    /// All current manifest references by their AKI.
+    manifests: HashMap<KeyIdentifier, Arc<ManifestRef>>,
+}
+
+impl ErikSyncState {
+    /// Creates the state by doing a full synchronisation.
+    pub fn create(
+        server: uri::Https,
+        fqdn: Fqdn,
+        fetch_mapper: FetchMapper,
+    ) -> anyhow::Result<Self> {
+        let mut state = ErikSyncState {
+            client: ErikRelayClient::new(server, fetch_mapper),
+            fqdn,
+            index_etag: None,
+            batch_uri: None,
+            bundle_uri: None,
+            partitions: HashMap::new(),
+            elements: HashMap::new(),
+            manifests: HashMap::new(),
+        };
+        state.update()?;
+        Ok(state)
+    }
+
+    /// Updates the state to the current index at the relay.
+    ///
+    /// Returns Ok(true) if the content changed.
+    pub fn update(&mut self) -> anyhow::Result<bool> {
+        let (bytes, etag) = match self
+            .client
+            .fetch_index(&self.fqdn, self.index_etag.as_ref())?
+        {
+            FetchResponse::Data { bytes, etag, link } => {
+                let link = link.unwrap_or_default();
+                self.batch_uri = self.client.link_target(&link, batch::LINK_REL);
+                self.bundle_uri = self.client.link_target(&link, bundle::LINK_REL);
+                (bytes, etag)
+            }
+            FetchResponse::UnModified => {
+                debug!(
+                    "Erik index for {} at {} is not modified",
+                    self.fqdn,
+                    self.client.server()
+                );
+                return Ok(false);
+            }
+        };
+        let index = ErikIndex::decode(bytes.as_ref())
+            .map_err(|e| anyhow!("Cannot decode Erik index for {}: {e}", self.fqdn))?;
+
+        // An index should not list a partition more than once, but we
+        // cannot rely on that.
+        let hashes: HashSet<Hash> = index.partitions().iter().map(|p| p.hash()).collect();
+
+        let unchanged = hashes.len() == self.partitions.len()
+            && hashes.iter().all(|hash| self.partitions.contains_key(hash));
+        if unchanged {
+            self.index_etag = etag;
+            debug!(
+                "Erik index for {} at {} is unchanged",
+                self.fqdn,
+                self.client.server()
+            );
+            return Ok(false);
+        }
+
+        let missing: Vec<Hash> = hashes
+            .iter()
+            .filter(|hash| !self.partitions.contains_key(hash))
+            .copied()
+            .collect();
+        let mut fetched = HashMap::new();
+        if self.elements.is_empty()
+            && let Some(bundle_uri) = self.bundle_uri.as_ref()
+        {
+            // On a cold start bundles get us everything in a few
+            // requests. Later, most objects in a changed partition are
+            // unchanged, so we only fetch the ones we need. Whatever a
+            // bundle did not get us is fetched by itself below.
+            for hash in &missing {
+                match self.client.fetch_bundle(bundle_uri, *hash) {
+                    Ok(objects) => fetched.extend(objects),
+                    Err(e) => warn!("Cannot use bundle, fetching objects instead: {e:#}"),
+                }
+            }
+        }
+        let missing: Vec<Hash> = missing
+            .into_iter()
+            .filter(|hash| !fetched.contains_key(hash))
+            .collect();
+        fetched.extend(self.fetch_objects(&missing)?);
+
+        let mut partitions = HashMap::new();
+        for hash in hashes {
+            let partition = match self.partitions.remove(&hash) {
+                Some(partition) => partition,
+                None => {
+                    let data = fetched
+                        .get(&hash)
+                        .ok_or_else(|| anyhow!("Erik partition {hash} was not fetched"))?;
+                    ErikPartition::decode(data.as_ref())
+                        .map_err(|e| anyhow!("Cannot decode Erik partition {hash}: {e}"))?
+                }
+            };
+            partitions.insert(hash, partition);
+        }
+
+        let mut manifests: HashMap<KeyIdentifier, Arc<ManifestRef>> = HashMap::new();
+        for mft_ref in partitions.values().flat_map(|p| p.manifest_refs.iter()) {
+            match manifests.get(&mft_ref.aki) {
+                Some(existing) if existing.manifest_number >= mft_ref.manifest_number => {}
+                _ => {
+                    manifests.insert(mft_ref.aki, mft_ref.clone());
+                }
+            }
+        }
+
+        let elements = self.retrieve_manifest_content(&manifests, fetched)?;
+
+        info!(
+            "Synchronised {} objects for {} from {}",
+            elements.len(),
+            self.fqdn,
+            self.client.server()
+        );
+
+        self.index_etag = etag;
+        self.partitions = partitions;
+        self.manifests = manifests;
+        self.elements = elements;
+
+        Ok(true)
+    }
+
+    /// The relay that is synchronised from.
+    pub fn client(&self) -> &ErikRelayClient {
+        &self.client
+    }
+
+    /// The index scope that is synchronised.
+    pub fn fqdn(&self) -> &Fqdn {
+        &self.fqdn
+    }
+
+    /// All current elements by their SHA256 hash.
+    pub fn elements(&self) -> &HashMap<Hash, Arc<RepoContentElement>> {
+        &self.elements
+    }
+
+    /// All current manifest references by their AKI.
+    pub fn manifests(&self) -> &HashMap<KeyIdentifier, Arc<ManifestRef>> {
+        &self.manifests
+    }
+
+    /// The current object at each URI, as listed by the manifests.
+    pub fn objects(&self) -> HashMap<uri::Rsync, Arc<RepoContentElement>> {
+        self.elements
+            .values()
+            .map(|element| (element.uri().clone(), element.clone()))
+            .collect()
+    }
+
+    /// Returns the elements for the manifests and all the files they
+    /// list. Only data that we do not have yet, and that was not
+    /// fetched already, is fetched.
+    fn retrieve_manifest_content(
+        &self,
+        manifests: &HashMap<KeyIdentifier, Arc<ManifestRef>>,
+        mut fetched: HashMap<Hash, Bytes>,
+    ) -> anyhow::Result<HashMap<Hash, Arc<RepoContentElement>>> {
+        let missing: Vec<Hash> = manifests
+            .values()
+            .map(|mft_ref| mft_ref.hash)
+            .filter(|hash| !self.elements.contains_key(hash) && !fetched.contains_key(hash))
+            .collect();
+        fetched.extend(self.fetch_objects(&missing)?);
+
+        let mut listed = vec![];
+        for mft_ref in manifests.values() {
+            let mft_uri = &mft_ref.locations;
+            let mft_bytes = self.element_data(mft_ref.hash, &fetched);
+            let manifest = Manifest::decode(mft_bytes.as_ref(), false)
+                .map_err(|e| anyhow!("Cannot decode manifest at {mft_uri}: {e}"))?;
+            listed.push((mft_uri.clone(), mft_ref.hash));
+
+            let base_uri = mft_uri
+                .parent()
+                .ok_or_else(|| anyhow!("Manifest uri {mft_uri} has no parent"))?;
+            for (uri, mft_hash) in manifest.content().iter_uris(&base_uri) {
+                let hash = Hash::try_from(mft_hash.as_slice())
+                    .map_err(|_| anyhow!("Unsupported hash for {uri} on manifest {mft_uri}"))?;
+                listed.push((uri, hash));
+            }
+        }
+
+        let missing: HashSet<Hash> = listed
+            .iter()
+            .map(|(_, hash)| *hash)
+            .filter(|hash| !self.elements.contains_key(hash) && !fetched.contains_key(hash))
+            .collect();
+        let missing: Vec<Hash> = missing.into_iter().collect();
+        fetched.extend(self.fetch_objects(&missing)?);
+
+        Ok(listed
+            .into_iter()
+            .map(|(uri, hash)| {
+                let data = self.element_data(hash, &fetched);
+                (hash, Arc::new(RepoContentElement::new(uri, data)))
+            })
+            .collect())
+    }
+
+    /// Returns the data for the given hash, which we either had or
+    /// just fetched.
+    fn element_data(&self, hash: Hash, fetched: &HashMap<Hash, Bytes>) -> Bytes {
+        match self.elements.get(&hash) {
+            Some(el) => el.data().clone(),
+            None => fetched[&hash].clone(),
+        }
+    }
+
+    fn fetch_objects(&self, hashes: &[Hash]) -> anyhow::Result<HashMap<Hash, Bytes>> {
+        self.client.fetch_objects(hashes, self.batch_uri.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::{Path, PathBuf},
+        sync::Mutex,
+    };
+
+    use crate::{
+        erik::relay::RelayContent,
+        fetch::pool::{DEFAULT_REFRESH_SECONDS, RepositoryPool},
+        util::{self, https, test_with_dir},
+    };
+
+    use super::*;
+
+    /// Returns the relay content for the test RRDP repository.
+    fn test_relay_content() -> RelayContent {
+        let notify = https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+        let mut mapper = FetchMapper::empty();
+        mapper.add_disk_mapper(
+            (&notify).into(),
+            PathBuf::from("test-resources/rrdp-rev2656/"),
+        );
+
+        let mut pool = RepositoryPool::empty();
+        pool.add(notify.into(), mapper, DEFAULT_REFRESH_SECONDS)
+            .unwrap();
+        pool.update_due();
+        RelayContent::from_pool(&pool)
+    }
+
+    /// Writes the relay content for the test RRDP repository as files
+    /// under `base_dir`, using the paths of the Erik relay URIs.
+    fn write_test_relay(base_dir: &Path) -> RelayContent {
+        let content = test_relay_content();
+        for (fqdn, bytes) in content.indexes() {
+            let path = base_dir.join(format!(".well-known/erik/index/{fqdn}"));
+            util::write_file(&path, bytes).unwrap();
+        }
+        for (hash, bytes) in content.objects() {
+            let path = base_dir.join(NamedInformation::new(*hash).well_known_path());
+            util::write_file(&path, bytes).unwrap();
+        }
+
+        content
+    }
+
+    #[test]
+    fn sync_from_relay() {
+        test_with_dir("erik_sync_from_relay", |dir| {
+            let content = write_test_relay(&dir);
+            let fqdn = content.indexes().next().unwrap().0.clone();
+
+            let server = https("https://relay.example.net/");
+            let mut mapper = FetchMapper::empty();
+            mapper.add_disk_mapper((&server).into(), dir.clone());
+
+            let mut state = ErikSyncState::create(server, fqdn, mapper).unwrap();
+            assert!(!state.elements().is_empty());
+            assert!(!state.manifests().is_empty());
+            for (hash, el) in state.elements() {
+                assert_eq!(content.object(hash), Some(el.data()));
+            }
+
+            assert!(!state.update().unwrap());
+        });
+    }
+
+    /// How the test relay serves bundles.
+    #[derive(Clone, Copy, PartialEq)]
+    enum Bundles {
+        None,
+        Served,
+        Broken,
+    }
+
+    /// Serves the relay content for the test RRDP repository over
+    /// HTTP, like epic does, for `server`. Returns the content, the
+    /// mapper to reach the relay, and the requests made so far.
+    fn serve_test_relay(
+        server: &uri::Https,
+        bundles: Bundles,
+    ) -> (RelayContent, FetchMapper, Arc<Mutex<Vec<String>>>) {
+        let content = Arc::new(test_relay_content());
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        let served = content.clone();
+        let logged = requests.clone();
+        let base_url = util::test_http_server(move |method, path, body| {
+            logged.lock().unwrap().push(format!("{method} {path}"));
+            let not_found = (404, vec![], vec![]);
+
+            if method == "POST" && path == format!("/{}", batch::PATH) {
+                let Ok(hashes) = batch::decode_request(body) else {
+                    return (400, vec![], vec![]);
+                };
+                let mut response = vec![];
+                for hash in hashes {
+                    let (head, data) = batch::encode_entry(&hash, served.object(&hash)).unwrap();
+                    response.extend_from_slice(&head);
+                    response.extend_from_slice(&data.unwrap_or_default());
+                }
+                return (200, vec![], response);
+            }
+
+            if let Some(fqdn) = path.strip_prefix("/.well-known/erik/index/") {
+                let Ok(fqdn) = fqdn.parse::<Fqdn>();
+                let Some(index) = served.index(&fqdn) else {
+                    return not_found;
+                };
+                let mut link = format!("</{}>; rel=\"{}\"", batch::PATH, batch::LINK_REL);
+                if bundles != Bundles::None {
+                    link.push_str(&format!(
+                        ", </{}/>; rel=\"{}\"",
+                        bundle::PATH,
+                        bundle::LINK_REL
+                    ));
+                }
+                return (200, vec![("Link", link)], index.to_vec());
+            }
+
+            if let Some(hash) = path.strip_prefix(&format!("/{}/", bundle::PATH)) {
+                let objects = URL_SAFE_NO_PAD
+                    .decode(hash)
+                    .ok()
+                    .and_then(|hash| Hash::try_from(hash.as_slice()).ok())
+                    .and_then(|hash| served.bundle(&hash));
+                return match (bundles, objects) {
+                    (Bundles::Served, Some(objects)) => {
+                        let data = bundle::write_bundle(objects.iter().map(|(h, d)| (h, d)));
+                        (200, vec![], data.unwrap())
+                    }
+                    (Bundles::Broken, Some(_)) => (200, vec![], b"not a bundle".to_vec()),
+                    _ => not_found,
+                };
+            }
+
+            served
+                .objects()
+                .find(|(hash, _)| {
+                    path == format!("/{}", NamedInformation::new(**hash).well_known_path())
+                })
+                .map(|(_, data)| (200, vec![], data.to_vec()))
+                .unwrap_or(not_found)
+        });
+
+        let mut mapper = FetchMapper::empty();
+        mapper.add_http_mapper(server.into(), base_url);
+        (Arc::unwrap_or_clone(content), mapper, requests)
+    }
+
+    #[test]
+    fn sync_with_batch() {
+        let server = https("https://relay.example.net/");
+        let (content, mapper, requests) = serve_test_relay(&server, Bundles::None);
+        let fqdn = content.indexes().next().unwrap().0.clone();
+
+        let state = ErikSyncState::create(server, fqdn, mapper).unwrap();
+        assert!(!state.elements().is_empty());
+        for (hash, el) in state.elements() {
+            assert_eq!(content.object(hash), Some(el.data()));
+        }
+
+        // Everything came in batches, not one object at a time.
+        let requests = requests.lock().unwrap();
+        assert!(requests.iter().any(|r| r.starts_with("POST ")));
+        assert!(!requests.iter().any(|r| r.contains("/.well-known/ni/")));
+    }
+
+    #[test]
+    fn sync_with_bundles() {
+        let server = https("https://relay.example.net/");
+        let (content, mapper, requests) = serve_test_relay(&server, Bundles::Served);
+        let fqdn = content.indexes().next().unwrap().0.clone();
+
+        let state = ErikSyncState::create(server, fqdn, mapper).unwrap();
+        assert!(!state.elements().is_empty());
+        for (hash, el) in state.elements() {
+            assert_eq!(content.object(hash), Some(el.data()));
+        }
+
+        // The bundles had everything.
+        let requests = requests.lock().unwrap();
+        assert!(requests.iter().any(|r| r.contains(bundle::PATH)));
+        assert!(!requests.iter().any(|r| r.starts_with("POST ")));
+    }
+
+    #[test]
+    fn sync_without_usable_bundles() {
+        let server = https("https://relay.example.net/");
+        let (content, mapper, requests) = serve_test_relay(&server, Bundles::Broken);
+        let fqdn = content.indexes().next().unwrap().0.clone();
+
+        let state = ErikSyncState::create(server, fqdn, mapper).unwrap();
+        assert!(!state.elements().is_empty());
+        for (hash, el) in state.elements() {
+            assert_eq!(content.object(hash), Some(el.data()));
+        }
+
+        // The objects were fetched instead.
+        let requests = requests.lock().unwrap();
+        assert!(requests.iter().any(|r| r.contains(bundle::PATH)));
+        assert!(requests.iter().any(|r| r.starts_with("POST ")));
+    }
+
+    #[test]
+    fn read_events_stream() {
+        let update = r#"{"fqdn":"example.net","hash":"0000000000000000000000000000000000000000000000000000000000000000","index_time":"2025-01-01T00:00:00Z","changed_partitions":[1,2]}"#;
+        let stream = format!(
+            ": keep-alive\n\nevent: other\ndata: ignored\n\nevent: index\ndata: {update}\n\n\
+             event: removed\ndata: {{\"fqdn\":\"example.org\"}}\n\n"
+        );
+
+        let mut events = vec![];
+        read_index_events(stream.as_bytes(), |event| {
+            events.push(event);
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(2, events.len());
+        let IndexEvent::Update(update) = &events[0] else {
+            panic!("expected an update, got {:?}", events[0]);
+        };
+        assert_eq!("example.net", update.fqdn.as_str());
+        assert_eq!(2, update.changed_partitions.len());
+        assert!(
+            matches!(&events[1], IndexEvent::Removal(removal) if removal.fqdn.as_str() == "example.org")
+        );
+    }
+
+    #[test]
+    fn link_target_from_header() {
+        let client = ErikRelayClient::new(
+            https("https://relay.example.net/base/"),
+            FetchMapper::empty(),
+        );
+
+        assert_eq!(
+            Some(https("https://relay.example.net/.well-known/erik/objects")),
+            client.link_target(
+                r#"</.well-known/erik/objects>; rel="erik-batch""#,
+                batch::LINK_REL
+            )
+        );
+        assert_eq!(
+            Some(https("https://relay.example.net/base/objects")),
+            client.link_target(
+                r#"<other>; rel="next", <objects>; rel=erik-batch"#,
+                batch::LINK_REL
+            )
+        );
+        assert_eq!(
+            None,
+            client.link_target(r#"</objects>; rel="next""#, batch::LINK_REL)
+        );
+    }
+}
@@ -1,2 +1,8 @@
+pub mod discovery;
+pub mod erik;
+pub mod pool;
+pub mod proxy;
 pub mod retrieval;
 pub mod rrdp;
+pub mod rsync_dir;
+pub mod watch;
@@ -0,0 +1,1228 @@
+//! Manage a pool of repositories and aggregate their content.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet, hash_map::Entry},
+    fmt,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use log::{debug, info, warn};
+use rpki::{crypto::KeyIdentifier, repository::Manifest, rrdp::Hash, uri};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    erik::{asn1::ManifestRef, state::ResolvedErikIndex},
+    fetch::{
+        erik::ErikSyncState,
+        retrieval::{FetchMapper, Fqdn},
+        rrdp::{RepoContentElement, RrdpState, RrdpUpdateOutcome, RrdpUpdateStats},
+        rsync_dir::RsyncDirState,
+        watch::{DEFAULT_DEBOUNCE, WatchDirState},
+    },
+    publication::PublicationServer,
+    util::Time,
+};
+
+/// The default number of seconds between updates of a repository.
+pub const DEFAULT_REFRESH_SECONDS: i64 = 60;
+
+/// The maximum number of seconds between updates of a repository.
+pub const MAX_REFRESH_SECONDS: i64 = 86400;
+
+/// The maximum number of seconds we back off after repeated failures.
+pub const MAX_BACKOFF_SECONDS: i64 = 3600;
+
+/// Where the content of a repository in the pool comes from. This
+/// also identifies the repository in the pool.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum RepositorySource {
+    /// An RRDP repository, by its notify URI.
+    Rrdp(uri::Https),
+
+    /// A local rsync-style directory tree.
+    RsyncDir(PathBuf),
+
+    /// A local rsync-style directory tree that is watched for changes.
+    WatchDir(PathBuf),
+
+    /// Content published to our own RFC 8181 publication server, by
+    /// the state directory of the server.
+    Publication(PathBuf),
+
+    /// An upstream Erik relay, by its base URI and the index scope
+    /// that is mirrored.
+    Erik { server: uri::Https, fqdn: Fqdn },
+}
+
+impl fmt::Display for RepositorySource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RepositorySource::Rrdp(notify) => write!(f, "{notify}"),
+            RepositorySource::RsyncDir(path) => write!(f, "dir: {}", path.display()),
+            RepositorySource::WatchDir(path) => write!(f, "watched dir: {}", path.display()),
+            RepositorySource::Publication(path) => {
+                write!(f, "publication server: {}", path.display())
+            }
+            RepositorySource::Erik { server, fqdn } => write!(f, "erik: {fqdn} at {server}"),
+        }
+    }
+}
+
+impl From<uri::Https> for RepositorySource {
+    fn from(notify: uri::Https) -> Self {
+        RepositorySource::Rrdp(notify)
+    }
+}
+
+/// The current state of a repository in the pool.
+#[derive(Debug)]
+pub enum RepositoryState {
+    Rrdp(RrdpState),
+    RsyncDir(RsyncDirState),
+    WatchDir(WatchDirState),
+    Publication(Box<PublicationServer>),
+    Erik(ErikSyncState),
+}
+
+impl RepositoryState {
+    fn create(source: &RepositorySource, fetch_mapper: &FetchMapper) -> anyhow::Result<Self> {
+        match source {
+            RepositorySource::Rrdp(notify) => {
+                RrdpState::create(notify.clone(), fetch_mapper.clone()).map(RepositoryState::Rrdp)
+            }
+            RepositorySource::RsyncDir(base_dir) => {
+                RsyncDirState::create(base_dir.clone()).map(RepositoryState::RsyncDir)
+            }
+            RepositorySource::WatchDir(base_dir) => {
+                WatchDirState::create(base_dir.clone(), DEFAULT_DEBOUNCE)
+                    .map(RepositoryState::WatchDir)
+            }
+            RepositorySource::Publication(_) => Err(anyhow!(
+                "Publication server {source} must be added with its state"
+            )),
+            RepositorySource::Erik { server, fqdn } => {
+                ErikSyncState::create(server.clone(), fqdn.clone(), fetch_mapper.clone())
+                    .map(RepositoryState::Erik)
+            }
+        }
+    }
+
+    /// Returns true if changes are known to be waiting, regardless of
+    /// the update schedule. Only watched sources know this.
+    fn has_pending_changes(&self) -> bool {
+        match self {
+            RepositoryState::WatchDir(state) => state.has_pending_changes(),
+            RepositoryState::Publication(server) => server.has_pending_changes(),
+            _ => false,
+        }
+    }
+
+    fn update(&mut self) -> anyhow::Result<bool> {
+        match self {
+            RepositoryState::Rrdp(state) => state.update(),
+            RepositoryState::RsyncDir(state) => state.update(),
+            RepositoryState::WatchDir(state) => state.update(),
+            RepositoryState::Publication(server) => server.update(),
+            RepositoryState::Erik(state) => state.update(),
+        }
+    }
+
+    /// All current elements by their SHA256 hash.
+    pub fn elements(&self) -> &HashMap<Hash, Arc<RepoContentElement>> {
+        match self {
+            RepositoryState::Rrdp(state) => state.elements(),
+            RepositoryState::RsyncDir(state) => state.elements(),
+            RepositoryState::WatchDir(state) => state.elements(),
+            RepositoryState::Publication(server) => server.elements(),
+            RepositoryState::Erik(state) => state.elements(),
+        }
+    }
+
+    /// All current manifest references by their AKI.
+    pub fn manifests(&self) -> &HashMap<KeyIdentifier, Arc<ManifestRef>> {
+        match self {
+            RepositoryState::Rrdp(state) => state.manifests(),
+            RepositoryState::RsyncDir(state) => state.manifests(),
+            RepositoryState::WatchDir(state) => state.manifests(),
+            RepositoryState::Publication(server) => server.manifests(),
+            RepositoryState::Erik(state) => state.manifests(),
+        }
+    }
+}
+
+/// A repository in the pool, together with its update schedule
+/// and failure tracking.
+#[derive(Debug)]
+pub struct PoolRepository {
+    /// Where the content comes from.
+    source: RepositorySource,
+
+    /// The mapper used to retrieve RRDP or Erik files.
+    fetch_mapper: FetchMapper,
+
+    /// The current state. None until the first successful update,
+    /// and while an update is running outside of the pool.
+    state: Option<RepositoryState>,
+
+    /// Whether the state is out for an update.
+    updating: bool,
+
+    /// Whether another update was asked for while updating.
+    update_requested: bool,
+
+    /// Seconds between regular updates.
+    refresh: i64,
+
+    /// When this repository should be updated next.
+    next_update: Time,
+
+    /// When this repository was last updated successfully.
+    last_update: Option<Time>,
+
+    /// The number of consecutive failed updates.
+    failures: u32,
+
+    /// The error of the last failed update, if any.
+    last_error: Option<String>,
+
+    /// How updates went, for RRDP repositories.
+    rrdp_stats: RrdpUpdateStats,
+}
+
+impl PoolRepository {
+    fn new(source: RepositorySource, fetch_mapper: FetchMapper, refresh: i64) -> Self {
+        PoolRepository {
+            source,
+            fetch_mapper,
+            state: None,
+            updating: false,
+            update_requested: false,
+            refresh,
+            next_update: Time::now(),
+            last_update: None,
+            failures: 0,
+            last_error: None,
+            rrdp_stats: RrdpUpdateStats::default(),
+        }
+    }
+
+    pub fn source(&self) -> &RepositorySource {
+        &self.source
+    }
+
+    pub fn state(&self) -> Option<&RepositoryState> {
+        self.state.as_ref()
+    }
+
+    pub fn refresh(&self) -> i64 {
+        self.refresh
+    }
+
+    pub fn next_update(&self) -> Time {
+        self.next_update
+    }
+
+    pub fn last_update(&self) -> Option<Time> {
+        self.last_update
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+
+    fn is_due(&self, now: Time) -> bool {
+        if self.updating {
+            return false;
+        }
+        self.next_update <= now || self.state.as_ref().is_some_and(|s| s.has_pending_changes())
+    }
+
+    /// Updates the repository, or creates its state if we never
+    /// got it before.
+    ///
+    /// Returns Ok(true) if the content changed.
+    fn update(&mut self) -> anyhow::Result<bool> {
+        match self.state.as_mut() {
+            Some(state) => state.update(),
+            None => {
+                let state = RepositoryState::create(&self.source, &self.fetch_mapper)?;
+                self.state = Some(state);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Updates the repository and reschedules it.
+    fn update_and_reschedule(&mut self) -> anyhow::Result<bool> {
+        let start = Instant::now();
+        let result = self.update();
+        self.record_outcome(&result, start.elapsed());
+        self.reschedule(result)
+    }
+
+    /// Takes back the state after an update outside of the pool, and
+    /// reschedules. Returns true if the content changed.
+    fn finish_update(&mut self, update: RepositoryUpdate) -> bool {
+        self.updating = false;
+        self.state = update.state;
+        // The mapper may have been changed in the meantime.
+        if let Some(RepositoryState::Rrdp(state)) = self.state.as_mut() {
+            state.set_fetch_mapper(self.fetch_mapper.clone());
+        }
+        let requested = std::mem::take(&mut self.update_requested);
+        let Some(result) = update.result else {
+            // Never ran, so it is still due.
+            return false;
+        };
+        self.record_outcome(&result, update.duration);
+        let changed = self.reschedule(result).unwrap_or(false);
+        if requested {
+            self.next_update = Time::now();
+        }
+        changed
+    }
+
+    /// Counts how an update went, for RRDP repositories. Failing to
+    /// create the state counts as an error too.
+    fn record_outcome(&mut self, result: &anyhow::Result<bool>, duration: Duration) {
+        if !matches!(self.source, RepositorySource::Rrdp(_)) {
+            return;
+        }
+        let outcome = match (result, self.state.as_ref()) {
+            (Ok(_), Some(RepositoryState::Rrdp(state))) => state.outcome(),
+            _ => RrdpUpdateOutcome::Error,
+        };
+        self.rrdp_stats.record(outcome, duration);
+    }
+
+    /// Reschedules after an update. Failures result in an exponential
+    /// back-off, capped at MAX_BACKOFF_SECONDS.
+    fn reschedule(&mut self, result: anyhow::Result<bool>) -> anyhow::Result<bool> {
+        match result {
+            Ok(changed) => {
+                self.failures = 0;
+                self.last_error = None;
+                self.last_update = Some(Time::now());
+                self.next_update = Time::seconds_from_now(self.refresh);
+                Ok(changed)
+            }
+            Err(e) => {
+                warn!("Failed to update repository {}: {e:#}", self.source);
+                self.failures += 1;
+                self.last_error = Some(format!("{e:#}"));
+
+                let backoff = self
+                    .refresh
+                    .saturating_mul(1 << self.failures.min(16))
+                    .min(MAX_BACKOFF_SECONDS);
+                self.next_update = Time::seconds_from_now(backoff);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// An update of a single repository that runs without access to the
+/// pool, so that the pool need not be locked while fetching.
+#[derive(Debug)]
+pub struct RepositoryUpdate {
+    source: RepositorySource,
+    fetch_mapper: FetchMapper,
+    state: Option<RepositoryState>,
+
+    /// The outcome, once it ran.
+    result: Option<anyhow::Result<bool>>,
+
+    /// How long it took to run.
+    duration: Duration,
+}
+
+impl RepositoryUpdate {
+    pub fn source(&self) -> &RepositorySource {
+        &self.source
+    }
+
+    /// Updates the state, or creates it if we never got it before.
+    pub fn run(&mut self) {
+        debug!("Updating repository {}", self.source);
+        let start = Instant::now();
+        let result = match self.state.as_mut() {
+            Some(state) => state.update(),
+            None => RepositoryState::create(&self.source, &self.fetch_mapper).map(|state| {
+                self.state = Some(state);
+                true
+            }),
+        };
+        self.result = Some(result);
+        self.duration = start.elapsed();
+    }
+}
+
+/// A snapshot of the status of a repository in the pool.
+#[derive(Clone, Debug)]
+pub struct RepositoryStatus {
+    pub source: RepositorySource,
+
+    /// The RRDP session and serial, for RRDP repositories.
+    pub session: Option<Uuid>,
+    pub serial: Option<u64>,
+
+    pub last_update: Option<Time>,
+    pub last_error: Option<String>,
+
+    /// The number of consecutive failed updates.
+    pub failures: u32,
+
+    /// The number of current elements.
+    pub elements: usize,
+
+    /// How updates went, for RRDP repositories.
+    pub rrdp_stats: Option<RrdpUpdateStats>,
+}
+
+/// The number of manifests found in the pool, by what was decided
+/// about them.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ManifestCounts {
+    /// Manifests that are used in the Erik indexes.
+    pub used: usize,
+
+    /// Valid manifests that are not used, because a manifest for the
+    /// same key with a higher manifest number is used.
+    pub superseded: usize,
+
+    /// Manifests that cannot be decoded.
+    pub invalid: usize,
+
+    /// Manifests that are not used because of the manifest policy.
+    pub rejected: usize,
+}
+
+/// Which manifests are used in the Erik indexes. By default, all
+/// manifests that can be decoded are used.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ManifestPolicy {
+    /// Leave out manifests that are past their next update.
+    pub reject_stale: bool,
+
+    /// Leave out manifests that do not strictly follow the RPKI
+    /// profiles.
+    pub strict: bool,
+}
+
+impl ManifestPolicy {
+    /// Returns whether the policy looks at manifests at all.
+    fn is_open(&self) -> bool {
+        !self.reject_stale && !self.strict
+    }
+
+    /// Decodes a manifest as the policy requires. Returns its next
+    /// update, or None if the policy never accepts it.
+    fn check(&self, data: &[u8]) -> Option<Time> {
+        Manifest::decode(data, self.strict)
+            .ok()
+            .map(|manifest| manifest.next_update().into())
+    }
+
+    /// Returns whether a manifest with the given outcome of `check` is
+    /// accepted at the given time.
+    fn accepts(&self, next_update: Option<Time>, now: Time) -> bool {
+        next_update.is_some_and(|next_update| !self.reject_stale || next_update >= now)
+    }
+}
+
+/// A snapshot of the status of the pool.
+#[derive(Clone, Debug, Default)]
+pub struct PoolStatus {
+    pub repositories: Vec<RepositoryStatus>,
+    pub manifests: ManifestCounts,
+
+    /// The smallest number of seconds between updates of any
+    /// repository, if there are any.
+    pub min_refresh: Option<i64>,
+}
+
+/// A pool of repositories, each with its own update schedule.
+///
+/// The content of all repositories is merged so that it can be
+/// served by a single relay. Objects that appear in more than one
+/// repository are deduplicated by their hash.
+#[derive(Debug, Default)]
+pub struct RepositoryPool {
+    repositories: HashMap<RepositorySource, PoolRepository>,
+
+    /// Which manifests are used in the Erik indexes.
+    manifest_policy: ManifestPolicy,
+
+    /// The outcome of `ManifestPolicy::check` by manifest hash, so
+    /// that each manifest is only decoded once for the policy.
+    manifest_checks: RefCell<HashMap<Hash, Option<Time>>>,
+
+    /// When the first manifest that is used goes stale, if stale
+    /// manifests are rejected. The content changes then.
+    next_stale: Cell<Option<Time>>,
+
+    /// Set when the content changed outside of an update, such as when
+    /// a repository was removed, so that the next `update_due` reports
+    /// the content as changed.
+    pending_change: bool,
+}
+
+impl RepositoryPool {
+    pub fn empty() -> Self {
+        RepositoryPool {
+            repositories: HashMap::new(),
+            manifest_policy: ManifestPolicy::default(),
+            manifest_checks: RefCell::new(HashMap::new()),
+            next_stale: Cell::new(None),
+            pending_change: false,
+        }
+    }
+
+    /// Adds a repository to the pool. It will be updated on the
+    /// next call to `update_due`.
+    pub fn add(
+        &mut self,
+        source: RepositorySource,
+        fetch_mapper: FetchMapper,
+        refresh: i64,
+    ) -> anyhow::Result<()> {
+        match self.repositories.entry(source.clone()) {
+            Entry::Occupied(_) => Err(anyhow!("Repository {source} is already in the pool")),
+            Entry::Vacant(entry) => {
+                info!("Added repository {source} to the pool");
+                entry.insert(PoolRepository::new(source, fetch_mapper, refresh));
+                Ok(())
+            }
+        }
+    }
+
+    /// Adds a publication server to the pool. Its content is picked
+    /// up whenever a publisher changed something.
+    pub fn add_publication(&mut self, server: PublicationServer) -> anyhow::Result<()> {
+        let source = RepositorySource::Publication(server.state_dir().to_path_buf());
+        match self.repositories.entry(source.clone()) {
+            Entry::Occupied(_) => Err(anyhow!("Repository {source} is already in the pool")),
+            Entry::Vacant(entry) => {
+                info!("Added repository {source} to the pool");
+                let mut repository =
+                    PoolRepository::new(source, FetchMapper::empty(), DEFAULT_REFRESH_SECONDS);
+                repository.state = Some(RepositoryState::Publication(Box::new(server)));
+                entry.insert(repository);
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the publication server in the pool, if any.
+    pub fn publication_mut(&mut self) -> Option<&mut PublicationServer> {
+        self.repositories
+            .values_mut()
+            .find_map(|r| match r.state.as_mut() {
+                Some(RepositoryState::Publication(server)) => Some(server.as_mut()),
+                _ => None,
+            })
+    }
+
+    /// Removes a repository from the pool. Returns false if it
+    /// was not present.
+    pub fn remove(&mut self, source: &RepositorySource) -> bool {
+        match self.repositories.remove(source) {
+            Some(repository) => {
+                info!("Removed repository {source} from the pool");
+                self.pending_change |= repository.state.is_some() || repository.updating;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sets the seconds between regular updates of a repository. If
+    /// the next update is further away than that, it is brought
+    /// forward. Returns false if the repository is not in the pool.
+    pub fn set_refresh(&mut self, source: &RepositorySource, refresh: i64) -> bool {
+        let Some(repository) = self.repositories.get_mut(source) else {
+            return false;
+        };
+        repository.refresh = refresh;
+        let next_update = Time::seconds_from_now(refresh);
+        if repository.next_update > next_update {
+            repository.next_update = next_update;
+        }
+        true
+    }
+
+    /// Sets the mapper used to retrieve the files of a repository,
+    /// and schedules an update so that it is used right away. Returns
+    /// false if the repository is not in the pool.
+    pub fn set_fetch_mapper(
+        &mut self,
+        source: &RepositorySource,
+        fetch_mapper: FetchMapper,
+    ) -> bool {
+        let Some(repository) = self.repositories.get_mut(source) else {
+            return false;
+        };
+        if let Some(RepositoryState::Rrdp(state)) = repository.state.as_mut() {
+            state.set_fetch_mapper(fetch_mapper.clone());
+        }
+        repository.fetch_mapper = fetch_mapper;
+        repository.next_update = Time::now();
+        repository.update_requested |= repository.updating;
+        true
+    }
+
+    /// Sets which manifests are used in the Erik indexes. If this is a
+    /// change, the next `update_due` reports the content as changed.
+    pub fn set_manifest_policy(&mut self, policy: ManifestPolicy) {
+        if policy != self.manifest_policy {
+            info!("Using manifest policy {policy:?}");
+            if policy.strict != self.manifest_policy.strict {
+                self.manifest_checks.get_mut().clear();
+            }
+            self.manifest_policy = policy;
+            self.next_stale.set(None);
+            self.pending_change = true;
+        }
+    }
+
+    /// Schedules a repository to be updated on the next call to
+    /// `update_due`. Returns false if it is not in the pool.
+    pub fn schedule_update(&mut self, source: &RepositorySource) -> bool {
+        let Some(repository) = self.repositories.get_mut(source) else {
+            return false;
+        };
+        repository.next_update = Time::now();
+        repository.update_requested |= repository.updating;
+        true
+    }
+
+    pub fn contains(&self, source: &RepositorySource) -> bool {
+        self.repositories.contains_key(source)
+    }
+
+    pub fn len(&self) -> usize {
+        self.repositories.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.repositories.is_empty()
+    }
+
+    pub fn repositories(&self) -> impl Iterator<Item = &PoolRepository> {
+        self.repositories.values()
+    }
+
+    /// Returns the current state of a repository, if it is in the pool
+    /// and was updated successfully at least once.
+    pub fn state(&self, source: &RepositorySource) -> Option<&RepositoryState> {
+        self.repositories.get(source).and_then(|r| r.state())
+    }
+
+    /// Updates a single repository now, regardless of its schedule.
+    ///
+    /// Returns Ok(true) if the content changed.
+    pub fn update(&mut self, source: &RepositorySource) -> anyhow::Result<bool> {
+        let repository = self
+            .repositories
+            .get_mut(source)
+            .ok_or_else(|| anyhow!("Repository {source} is not in the pool"))?;
+        if repository.updating {
+            return Err(anyhow!("Repository {source} is already being updated"));
+        }
+        repository.update_and_reschedule()
+    }
+
+    /// Returns true if any repository is due for an update, or if a
+    /// manifest that is used went stale and must be left out.
+    pub fn has_due(&self) -> bool {
+        let now = Time::now();
+        self.pending_change
+            || self.has_new_stale(now)
+            || self.repositories.values().any(|r| r.is_due(now))
+    }
+
+    fn has_new_stale(&self, now: Time) -> bool {
+        self.next_stale.get().is_some_and(|stale| stale < now)
+    }
+
+    /// Updates all repositories that are due for an update.
+    ///
+    /// Returns true if the content of any repository changed.
+    pub fn update_due(&mut self) -> bool {
+        let mut updates = self.start_updates();
+        for update in &mut updates {
+            update.run();
+        }
+        self.finish_updates(updates)
+    }
+
+    /// Hands out the repositories that are due for an update, so that
+    /// they can be updated without holding on to the pool. The result
+    /// must be passed to `finish_updates`.
+    ///
+    /// Until then, these repositories have no content in the pool and
+    /// are not due. Publication servers are updated right away, as
+    /// publishers need them and there is nothing to fetch.
+    pub fn start_updates(&mut self) -> Vec<RepositoryUpdate> {
+        let now = Time::now();
+        let mut updates = Vec::new();
+        for repository in self.repositories.values_mut() {
+            if !repository.is_due(now) {
+                continue;
+            }
+            if let Some(RepositoryState::Publication(_)) = repository.state {
+                debug!("Updating repository {}", repository.source);
+                self.pending_change |= repository.update_and_reschedule().unwrap_or(false);
+                continue;
+            }
+            repository.updating = true;
+            updates.push(RepositoryUpdate {
+                source: repository.source.clone(),
+                fetch_mapper: repository.fetch_mapper.clone(),
+                state: repository.state.take(),
+                result: None,
+                duration: Duration::ZERO,
+            });
+        }
+        updates
+    }
+
+    /// Takes back the repositories handed out by `start_updates`.
+    /// Repositories that were removed in the meantime stay removed.
+    ///
+    /// Returns true if the content of any repository changed.
+    pub fn finish_updates(&mut self, updates: Vec<RepositoryUpdate>) -> bool {
+        let mut changed = std::mem::take(&mut self.pending_change);
+        for update in updates {
+            // If the repository was removed and added again, it is no
+            // longer the one we updated.
+            if let Some(repository) = self.repositories.get_mut(&update.source)
+                && repository.updating
+            {
+                changed |= repository.finish_update(update);
+            }
+        }
+        // This is found again when the manifests are used next.
+        if self.has_new_stale(Time::now()) {
+            self.next_stale.set(None);
+            changed = true;
+        }
+        if changed {
+            let current: HashSet<Hash> = self
+                .states()
+                .flat_map(|state| state.manifests().values().map(|mft_ref| mft_ref.hash))
+                .collect();
+            self.manifest_checks
+                .get_mut()
+                .retain(|hash, _| current.contains(hash));
+        }
+        changed
+    }
+
+    /// Returns all current elements of all repositories by their hash.
+    pub fn elements(&self) -> HashMap<Hash, Arc<RepoContentElement>> {
+        let mut elements = HashMap::new();
+        for state in self.states() {
+            for (hash, element) in state.elements() {
+                elements.entry(*hash).or_insert_with(|| element.clone());
+            }
+        }
+        elements
+    }
+
+    /// Returns the current object at each URI in all repositories.
+    pub fn objects(&self) -> HashMap<uri::Rsync, Arc<RepoContentElement>> {
+        self.current_objects()
+            .map(|(_, element)| (element.uri().clone(), element.clone()))
+            .collect()
+    }
+
+    /// Returns the hash of the current object at each URI in all
+    /// repositories.
+    pub fn object_hashes(&self) -> HashMap<uri::Rsync, Hash> {
+        self.current_objects()
+            .map(|(hash, element)| (element.uri().clone(), *hash))
+            .collect()
+    }
+
+    /// The current object at each URI, with its hash.
+    ///
+    /// Repositories should not overlap. If they do, the object from
+    /// the first repository by source is used, so that the outcome
+    /// does not change from one call to the next.
+    fn current_objects(&self) -> impl Iterator<Item = (&Hash, &Arc<RepoContentElement>)> {
+        let mut repositories: Vec<&PoolRepository> = self.repositories.values().collect();
+        repositories.sort_by_cached_key(|repository| repository.source.to_string());
+
+        let mut uris = HashSet::new();
+        repositories
+            .into_iter()
+            .flat_map(|repository| repository.state())
+            .flat_map(|state| state.elements().iter())
+            .filter(move |(_, element)| uris.insert(element.uri().clone()))
+    }
+
+    /// Returns the current manifest references of all repositories.
+    ///
+    /// If a manifest for the same AKI is found in more than one
+    /// repository, then the one with the highest manifest number
+    /// is used.
+    pub fn manifests(&self) -> HashMap<KeyIdentifier, Arc<ManifestRef>> {
+        let now = Time::now();
+        let mut manifests: HashMap<KeyIdentifier, Arc<ManifestRef>> = HashMap::new();
+        for state in self.states() {
+            for (aki, mft_ref) in state.manifests() {
+                if !self.accepts(state, mft_ref, now) {
+                    continue;
+                }
+                match manifests.entry(*aki) {
+                    Entry::Occupied(mut existing) => {
+                        if existing.get().manifest_number < mft_ref.manifest_number {
+                            existing.insert(mft_ref.clone());
+                        }
+                    }
+                    Entry::Vacant(entry) => {
+                        entry.insert(mft_ref.clone());
+                    }
+                }
+            }
+        }
+
+        if self.manifest_policy.reject_stale {
+            let checks = self.manifest_checks.borrow();
+            let next_stale = manifests
+                .values()
+                .filter_map(|mft_ref| checks.get(&mft_ref.hash).copied().flatten())
+                .min();
+            self.next_stale.set(next_stale);
+        }
+        manifests
+    }
+
+    /// Returns whether the manifest policy accepts a manifest at the
+    /// given time.
+    fn accepts(&self, state: &RepositoryState, mft_ref: &ManifestRef, now: Time) -> bool {
+        let policy = self.manifest_policy;
+        if policy.is_open() {
+            return true;
+        }
+        let next_update = *self
+            .manifest_checks
+            .borrow_mut()
+            .entry(mft_ref.hash)
+            .or_insert_with(|| {
+                let element = state.elements().get(&mft_ref.hash);
+                element.and_then(|element| policy.check(element.data()))
+            });
+        policy.accepts(next_update, now)
+    }
+
+    /// Returns an ErikIndex for each FQDN found in the merged manifests.
+    pub fn indexes(&self) -> HashMap<Fqdn, ResolvedErikIndex> {
+        ResolvedErikIndex::from_manifests(self.manifests().values())
+    }
+
+    /// Returns a snapshot of the status of all repositories, and of
+    /// the manifests found in them.
+    pub fn status(&self) -> PoolStatus {
+        let repositories = self
+            .repositories
+            .values()
+            .map(|repository| {
+                let rrdp = match repository.state() {
+                    Some(RepositoryState::Rrdp(state)) => Some(state),
+                    _ => None,
+                };
+                let is_rrdp = matches!(repository.source, RepositorySource::Rrdp(_));
+                RepositoryStatus {
+                    source: repository.source.clone(),
+                    session: rrdp.map(|state| state.session_id()),
+                    serial: rrdp.map(|state| state.serial()),
+                    last_update: repository.last_update,
+                    last_error: repository.last_error.clone(),
+                    failures: repository.failures,
+                    elements: repository.state().map_or(0, |state| state.elements().len()),
+                    rrdp_stats: is_rrdp.then(|| repository.rrdp_stats.clone()),
+                }
+            })
+            .collect();
+
+        PoolStatus {
+            repositories,
+            manifests: self.manifest_counts(),
+            min_refresh: self.repositories.values().map(|r| r.refresh).min(),
+        }
+    }
+
+    /// Counts the manifests in all repositories by whether they are
+    /// used. Manifests in more than one repository are counted once.
+    fn manifest_counts(&self) -> ManifestCounts {
+        let now = Time::now();
+        let used: HashSet<Hash> = self.manifests().values().map(|m| m.hash).collect();
+        let rejected: HashSet<Hash> = self
+            .states()
+            .flat_map(|state| {
+                state
+                    .manifests()
+                    .values()
+                    .filter(|mft_ref| !self.accepts(state, mft_ref, now))
+                    .map(|mft_ref| mft_ref.hash)
+            })
+            .collect();
+        let mut seen = HashSet::new();
+        let mut counts = ManifestCounts::default();
+        for state in self.states() {
+            for (hash, element) in state.elements() {
+                if !element.uri().ends_with(".mft") || !seen.insert(*hash) {
+                    continue;
+                }
+                if used.contains(hash) {
+                    counts.used += 1;
+                } else if rejected.contains(hash) {
+                    counts.rejected += 1;
+                } else if element.try_manifest_ref(true).is_ok() {
+                    counts.superseded += 1;
+                } else {
+                    counts.invalid += 1;
+                }
+            }
+        }
+        counts
+    }
+
+    fn states(&self) -> impl Iterator<Item = &RepositoryState> {
+        self.repositories.values().flat_map(|r| r.state())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        erik::relay::RelayContent,
+        output::erik::{ErikTreeConfig, ErikTreeOutput},
+        util::{TestRrdpSource, https, test_with_dir},
+    };
+
+    use super::*;
+
+    #[test]
+    fn pool_merges_and_deduplicates() {
+        let notify = https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+        let mirror = https("https://mirror.example.net/rrdp/notification.xml");
+
+        let mut mapper = FetchMapper::empty();
+        mapper.add_disk_mapper(
+            (&notify).into(),
+            PathBuf::from("test-resources/rrdp-rev2656/"),
+        );
+        mapper.add_disk_mapper(
+            (&mirror).into(),
+            PathBuf::from("test-resources/rrdp-rev2656/"),
+        );
+
+        let mut pool = RepositoryPool::empty();
+        pool.add(
+            notify.clone().into(),
+            mapper.clone(),
+            DEFAULT_REFRESH_SECONDS,
+        )
+        .unwrap();
+        pool.add(mirror.into(), mapper.clone(), DEFAULT_REFRESH_SECONDS)
+            .unwrap();
+        assert!(
+            pool.add(notify.into(), mapper, DEFAULT_REFRESH_SECONDS)
+                .is_err()
+        );
+
+        assert!(pool.update_due());
+
+        // Nothing is due right after an update
+        assert!(!pool.update_due());
+
+        let single = pool.states().next().unwrap();
+        assert_eq!(single.elements().len(), pool.elements().len());
+        assert_eq!(single.elements().len(), pool.objects().len());
+        assert_eq!(single.manifests().len(), pool.manifests().len());
+        assert_eq!(1, pool.indexes().len());
+    }
+
+    #[test]
+    fn changes_while_updating() {
+        let notify = https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+        let mirror = https("https://mirror.example.net/rrdp/notification.xml");
+        let mut mapper = FetchMapper::empty();
+        mapper.add_disk_mapper(
+            (&notify).into(),
+            PathBuf::from("test-resources/rrdp-rev2656/"),
+        );
+        mapper.add_disk_mapper(
+            (&mirror).into(),
+            PathBuf::from("test-resources/rrdp-rev2656/"),
+        );
+        let notify = RepositorySource::from(notify);
+        let mirror = RepositorySource::from(mirror);
+
+        let mut pool = RepositoryPool::empty();
+        pool.add(notify.clone(), mapper.clone(), DEFAULT_REFRESH_SECONDS)
+            .unwrap();
+        pool.add(mirror.clone(), mapper.clone(), DEFAULT_REFRESH_SECONDS)
+            .unwrap();
+
+        let mut updates = pool.start_updates();
+        assert_eq!(2, updates.len());
+        assert!(!pool.has_due());
+        assert!(pool.update(&notify).is_err());
+
+        // Changes to the pool while the updates run are kept.
+        assert!(pool.schedule_update(&notify));
+        assert!(pool.remove(&mirror));
+        for update in &mut updates {
+            update.run();
+        }
+        assert!(pool.finish_updates(updates));
+        assert!(!pool.contains(&mirror));
+        assert!(pool.state(&notify).is_some());
+        assert!(pool.has_due());
+
+        // An update that never ran is still due.
+        let updates = pool.start_updates();
+        assert!(!pool.finish_updates(updates));
+        assert!(pool.has_due());
+        assert!(!pool.update_due());
+        assert!(!pool.has_due());
+    }
+
+    #[test]
+    fn pool_status() {
+        let notify = https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+        let mut mapper = FetchMapper::empty();
+        mapper.add_disk_mapper(
+            (&notify).into(),
+            PathBuf::from("test-resources/rrdp-rev2656/"),
+        );
+
+        let mut pool = RepositoryPool::empty();
+        pool.add(notify.clone().into(), mapper, DEFAULT_REFRESH_SECONDS)
+            .unwrap();
+        assert!(pool.has_due());
+        pool.update_due();
+        assert!(!pool.has_due());
+
+        let status = pool.status();
+        let repository = &status.repositories[0];
+        assert_eq!(RepositorySource::Rrdp(notify), repository.source);
+        assert!(repository.serial.is_some());
+        assert!(repository.last_update.is_some());
+        assert_eq!(pool.elements().len(), repository.elements);
+        let stats = repository.rrdp_stats.as_ref().unwrap();
+        assert_eq!(1, stats.count(RrdpUpdateOutcome::Snapshot));
+
+        let manifests = status.manifests;
+        assert_eq!(pool.manifests().len(), manifests.used);
+        assert_eq!(
+            pool.elements()
+                .values()
+                .filter(|el| el.uri().ends_with(".mft"))
+                .count(),
+            manifests.used + manifests.superseded + manifests.invalid
+        );
+    }
+
+    #[test]
+    fn status_counts_current_objects() {
+        test_with_dir("pool_status_current", |dir| {
+            let updated = "rsync://example.net/repo/updated.roa";
+            let withdrawn = "rsync://example.net/repo/withdrawn.roa";
+            let mut source = TestRrdpSource::create(dir, &[(updated, "old"), (withdrawn, "gone")]);
+            let notify = source.notify();
+            let mut pool = RepositoryPool::empty();
+            pool.add(
+                notify.clone().into(),
+                source.fetch_mapper(),
+                DEFAULT_REFRESH_SECONDS,
+            )
+            .unwrap();
+            pool.update_due();
+            assert_eq!(2, pool.status().repositories[0].elements);
+
+            // Replaced and withdrawn objects are not counted.
+            source.change(&[(updated, Some("new")), (withdrawn, None)]);
+            assert!(pool.update(&notify.into()).unwrap());
+            let status = pool.status();
+            assert_eq!(Some(2), status.repositories[0].serial);
+            assert_eq!(1, status.repositories[0].elements);
+        });
+    }
+
+    #[test]
+    fn pool_changes_at_runtime() {
+        let notify = https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+        let source = RepositorySource::Rrdp(notify.clone());
+
+        // Without a mapping the repository cannot be fetched.
+        let mut pool = RepositoryPool::empty();
+        pool.add(
+            source.clone(),
+            FetchMapper::empty(),
+            DEFAULT_REFRESH_SECONDS,
+        )
+        .unwrap();
+        pool.set_refresh(&source, 1);
+        assert_eq!(1, pool.repositories().next().unwrap().refresh());
+
+        let mut mapper = FetchMapper::empty();
+        mapper.add_disk_mapper(
+            (&notify).into(),
+            PathBuf::from("test-resources/rrdp-rev2656/"),
+        );
+        assert!(pool.set_fetch_mapper(&source, mapper));
+        assert!(pool.update_due());
+        assert!(!pool.has_due());
+
+        assert!(pool.schedule_update(&source));
+        assert!(pool.has_due());
+        assert!(!pool.update_due());
+
+        // The test repository is from 2021, so its manifests are stale.
+        let used = pool.status().manifests.used;
+        pool.set_manifest_policy(ManifestPolicy {
+            reject_stale: true,
+            strict: false,
+        });
+        assert!(pool.update_due());
+        assert!(pool.manifests().is_empty());
+        assert_eq!(used, pool.status().manifests.rejected);
+        pool.set_manifest_policy(ManifestPolicy::default());
+        assert!(pool.update_due());
+        assert_eq!(used, pool.manifests().len());
+
+        // Removing content is a change, once.
+        assert!(pool.remove(&source));
+        assert!(pool.has_due());
+        assert!(pool.update_due());
+        assert!(!pool.has_due());
+        assert!(pool.elements().is_empty());
+        assert!(!pool.schedule_update(&source));
+    }
+
+    #[test]
+    fn stale_manifests_change_content() {
+        let notify = https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+        let mut mapper = FetchMapper::empty();
+        mapper.add_disk_mapper(
+            (&notify).into(),
+            PathBuf::from("test-resources/rrdp-rev2656/"),
+        );
+        let mut pool = RepositoryPool::empty();
+        pool.add(notify.into(), mapper, DEFAULT_REFRESH_SECONDS)
+            .unwrap();
+        pool.set_manifest_policy(ManifestPolicy {
+            reject_stale: true,
+            strict: false,
+        });
+        assert!(pool.update_due());
+
+        // The test repository is from 2021. Pretend that its manifests
+        // go stale in a moment, as they are only decoded once.
+        assert!(pool.manifests().is_empty());
+        let checks = pool.manifest_checks.get_mut();
+        assert!(!checks.is_empty());
+        for next_update in checks.values_mut() {
+            *next_update = Some(Time::seconds_from_now(1));
+        }
+        assert!(!pool.manifests().is_empty());
+        assert!(!pool.has_due());
+
+        std::thread::sleep(std::time::Duration::from_secs(2));
+        assert!(pool.has_due());
+        assert!(pool.update_due());
+        assert!(pool.manifests().is_empty());
+        assert!(!pool.has_due());
+    }
+
+    #[test]
+    fn pool_tracks_failures() {
+        let notify = https("https://localhost.example/rrdp/notification.xml");
+        let mut mapper = FetchMapper::empty();
+        mapper.add_disk_mapper((&notify).into(), PathBuf::from("test-resources/missing/"));
+
+        let mut pool = RepositoryPool::empty();
+        pool.add(notify.into(), mapper, DEFAULT_REFRESH_SECONDS)
+            .unwrap();
+
+        assert!(!pool.update_due());
+
+        let repository = pool.repositories().next().unwrap();
+        assert_eq!(1, repository.failures());
+        assert!(repository.last_error().is_some());
+        assert!(repository.state().is_none());
+        assert!(repository.next_update() > Time::now());
+
+        // Not getting a first snapshot is counted as well.
+        let status = pool.status();
+        let stats = status.repositories[0].rrdp_stats.as_ref().unwrap();
+        assert_eq!(1, stats.count(RrdpUpdateOutcome::Error));
+    }
+
+    #[test]
+    fn pool_mirrors_upstream_erik_relay() {
+        test_with_dir("pool_mirrors_upstream_erik_relay", |dir| {
+            let notify = https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let mut mapper = FetchMapper::empty();
+            mapper.add_disk_mapper(
+                (&notify).into(),
+                PathBuf::from("test-resources/rrdp-rev2656/"),
+            );
+            let mut upstream = RepositoryPool::empty();
+            upstream
+                .add(notify.into(), mapper, DEFAULT_REFRESH_SECONDS)
+                .unwrap();
+            upstream.update_due();
+            let content = RelayContent::from_pool(&upstream);
+
+            let base_dir = dir.join("upstream");
+            ErikTreeOutput::create(ErikTreeConfig {
+                base_dir: base_dir.clone(),
+                state_path: dir.join("state.json"),
+                cleanup_after: 0,
+            })
+            .unwrap()
+            .update(&content)
+            .unwrap();
+
+            let server = https("https://upstream.example.net/");
+            let mut mapper = FetchMapper::empty();
+            mapper.add_disk_mapper((&server).into(), base_dir);
+
+            let fqdn = content.indexes().next().unwrap().0.clone();
+            let mut pool = RepositoryPool::empty();
+            pool.add(
+                RepositorySource::Erik { server, fqdn },
+                mapper,
+                DEFAULT_REFRESH_SECONDS,
+            )
+            .unwrap();
+            assert!(pool.update_due());
+
+            assert_eq!(upstream.elements().len(), pool.elements().len());
+            assert_eq!(upstream.manifests().len(), pool.manifests().len());
+            assert_eq!(
+                content.indexes().next().unwrap().1,
+                RelayContent::from_pool(&pool).indexes().next().unwrap().1
+            );
+        });
+    }
+}
@@ -0,0 +1,410 @@
+//! Lazily proxy the content of an upstream Erik relay.
+//!
+//! Objects are fetched on demand, checked against their hash, and kept
+//! in a size-bounded on-disk cache. The least recently used objects
+//! are evicted first. Indexes change, so they are only kept for a
+//! short time.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, anyhow};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use bytes::Bytes;
+use log::{debug, info, warn};
+use rpki::{rrdp::Hash, uri};
+
+use crate::{
+    fetch::{
+        erik::ErikRelayClient,
+        retrieval::{FetchMapper, FetchResponse, Fqdn},
+    },
+    util::{self, Time},
+};
+
+/// The default maximum size of the object cache, in bytes.
+pub const DEFAULT_MAX_CACHE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// The default number of seconds that a proxied index is served
+/// before it is fetched again.
+pub const DEFAULT_INDEX_TTL: i64 = 10;
+
+/// Where the proxy gets its content, and how much it keeps.
+#[derive(Clone, Debug)]
+pub struct ErikProxyConfig {
+    /// The base URI of the upstream relay.
+    pub upstream: uri::Https,
+
+    /// The directory where cached objects are kept.
+    pub cache_dir: PathBuf,
+
+    /// The maximum total size of the cached objects, in bytes.
+    pub max_cache_size: u64,
+
+    /// Seconds to serve a proxied index before fetching it again.
+    pub index_ttl: i64,
+}
+
+/// A caching proxy for an upstream Erik relay.
+///
+/// Fetching is done without holding the lock on the cache, so that
+/// a slow upstream does not block requests for cached content.
+#[derive(Debug)]
+pub struct ErikProxy {
+    config: ErikProxyConfig,
+    client: ErikRelayClient,
+    cache: Mutex<ProxyCache>,
+}
+
+impl ErikProxy {
+    /// Creates the proxy. Objects that are already in the cache
+    /// directory are picked up, oldest first.
+    pub fn create(config: ErikProxyConfig, fetch_mapper: FetchMapper) -> anyhow::Result<Self> {
+        fs::create_dir_all(&config.cache_dir).with_context(|| {
+            format!(
+                "Cannot create cache directory {}",
+                config.cache_dir.display()
+            )
+        })?;
+
+        let mut cache = ProxyCache::default();
+        for (hash, size) in Self::read_cache_dir(&config.cache_dir)? {
+            cache.insert(hash, size);
+        }
+        info!(
+            "Proxy cache at {} has {} objects, {} bytes",
+            config.cache_dir.display(),
+            cache.objects.len(),
+            cache.size
+        );
+
+        let client = ErikRelayClient::new(config.upstream.clone(), fetch_mapper);
+        let proxy = ErikProxy {
+            config,
+            client,
+            cache: Mutex::new(cache),
+        };
+        proxy.evict()?;
+        Ok(proxy)
+    }
+
+    pub fn config(&self) -> &ErikProxyConfig {
+        &self.config
+    }
+
+    /// Returns the index for the scope, from upstream unless we got it
+    /// less than the TTL ago.
+    pub fn index(&self, fqdn: &Fqdn) -> anyhow::Result<Bytes> {
+        let fresh_since = Time::seconds_ago(self.config.index_ttl);
+        let cached = self.cache.lock().unwrap().indexes.get(fqdn).cloned();
+        if let Some(cached) = cached.as_ref()
+            && cached.fetched > fresh_since
+        {
+            return Ok(cached.bytes.clone());
+        }
+
+        debug!("Fetching index for {fqdn} from upstream");
+        let etag = cached.as_ref().and_then(|c| c.etag.as_ref());
+        let (bytes, etag) = match (self.client.fetch_index(fqdn, etag)?, cached) {
+            (FetchResponse::Data { bytes, etag, .. }, _) => (bytes, etag),
+            (FetchResponse::UnModified, Some(cached)) => (cached.bytes, cached.etag),
+            (FetchResponse::UnModified, None) => {
+                return Err(anyhow!(
+                    "Upstream index for {fqdn} unmodified, but not cached"
+                ));
+            }
+        };
+        let cached = CachedIndex {
+            fetched: Time::now(),
+            bytes: bytes.clone(),
+            etag,
+        };
+        self.cache
+            .lock()
+            .unwrap()
+            .indexes
+            .insert(fqdn.clone(), cached);
+        Ok(bytes)
+    }
+
+    /// Returns the object with the given hash, from the cache or else
+    /// from upstream.
+    pub fn object(&self, hash: Hash) -> anyhow::Result<Bytes> {
+        let path = self.object_path(&hash);
+        if self.cache.lock().unwrap().touch(&hash) {
+            match util::read_file(&path) {
+                Ok(bytes) if hash.matches(bytes.as_ref()) => return Ok(bytes),
+                _ => {
+                    warn!("Dropping missing or corrupt cached object {hash}");
+                    let mut cache = self.cache.lock().unwrap();
+                    cache.remove(&hash);
+                    Self::remove_object_file(&path)?;
+                }
+            }
+        }
+
+        debug!("Fetching object {hash} from upstream");
+        let bytes = self.client.fetch_object(hash)?;
+        let size = bytes.len() as u64;
+        if size <= self.config.max_cache_size {
+            // Files are only written and removed while holding the
+            // lock, so that the cache always tracks what is on disk.
+            let mut cache = self.cache.lock().unwrap();
+            util::write_file(&path, &bytes)?;
+            cache.insert(hash, size);
+            drop(cache);
+            self.evict()?;
+        }
+        Ok(bytes)
+    }
+
+    /// Removes the least recently used objects until the cache fits.
+    fn evict(&self) -> anyhow::Result<()> {
+        let mut cache = self.cache.lock().unwrap();
+        while cache.size > self.config.max_cache_size {
+            let Some(hash) = cache.least_recently_used() else {
+                break;
+            };
+            cache.remove(&hash);
+            debug!("Evicting {hash} from proxy cache");
+            Self::remove_object_file(&self.object_path(&hash))?;
+        }
+        Ok(())
+    }
+
+    /// Removes the file of a cached object, if there is one.
+    fn remove_object_file(path: &Path) -> anyhow::Result<()> {
+        if path.exists() {
+            fs::remove_file(path)
+                .with_context(|| format!("Cannot remove file {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    fn object_path(&self, hash: &Hash) -> PathBuf {
+        self.config
+            .cache_dir
+            .join(URL_SAFE_NO_PAD.encode(hash.as_slice()))
+    }
+
+    /// Returns the hash and size of the objects in the directory, in
+    /// order of their modification time. Other files are ignored.
+    fn read_cache_dir(dir: &Path) -> anyhow::Result<Vec<(Hash, u64)>> {
+        let mut found = vec![];
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            let name = entry.file_name();
+            let hash = name
+                .to_str()
+                .and_then(|name| URL_SAFE_NO_PAD.decode(name).ok())
+                .and_then(|bytes| Hash::try_from(bytes.as_slice()).ok());
+            if let Some(hash) = hash
+                && meta.is_file()
+            {
+                found.push((meta.modified()?, hash, meta.len()));
+            }
+        }
+        found.sort_by_key(|(modified, _, _)| *modified);
+        Ok(found
+            .into_iter()
+            .map(|(_, hash, size)| (hash, size))
+            .collect())
+    }
+}
+
+/// The bookkeeping of the cache.
+///
+/// Every use of an object gives it a new, higher tick. The object
+/// with the lowest tick is the least recently used.
+#[derive(Debug, Default)]
+struct ProxyCache {
+    /// The size and last tick of each cached object.
+    objects: HashMap<Hash, CachedObject>,
+
+    /// The cached objects by their last tick.
+    by_tick: BTreeMap<u64, Hash>,
+
+    /// The total size of the cached objects.
+    size: u64,
+
+    /// The last tick that was handed out.
+    tick: u64,
+
+    /// Recently fetched indexes.
+    indexes: HashMap<Fqdn, CachedIndex>,
+}
+
+#[derive(Clone, Debug)]
+struct CachedIndex {
+    fetched: Time,
+    bytes: Bytes,
+    etag: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct CachedObject {
+    size: u64,
+    tick: u64,
+}
+
+impl ProxyCache {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn insert(&mut self, hash: Hash, size: u64) {
+        self.remove(&hash);
+        let tick = self.next_tick();
+        self.objects.insert(hash, CachedObject { size, tick });
+        self.by_tick.insert(tick, hash);
+        self.size += size;
+    }
+
+    /// Marks the object as used. Returns false if it is not cached.
+    fn touch(&mut self, hash: &Hash) -> bool {
+        let tick = self.next_tick();
+        match self.objects.get_mut(hash) {
+            Some(object) => {
+                self.by_tick.remove(&object.tick);
+                object.tick = tick;
+                self.by_tick.insert(tick, *hash);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, hash: &Hash) {
+        if let Some(object) = self.objects.remove(hash) {
+            self.by_tick.remove(&object.tick);
+            self.size -= object.size;
+        }
+    }
+
+    fn least_recently_used(&self) -> Option<Hash> {
+        self.by_tick.values().next().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        erik::relay::RelayContent,
+        fetch::pool::{DEFAULT_REFRESH_SECONDS, RepositoryPool},
+        output::erik::{ErikTreeConfig, ErikTreeOutput},
+        util::{https, test_with_dir},
+    };
+
+    use super::*;
+
+    fn write_upstream(dir: &Path) -> RelayContent {
+        let notify = https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+        let mut mapper = FetchMapper::empty();
+        mapper.add_disk_mapper(
+            (&notify).into(),
+            PathBuf::from("test-resources/rrdp-rev2656/"),
+        );
+        let mut pool = RepositoryPool::empty();
+        pool.add(notify.into(), mapper, DEFAULT_REFRESH_SECONDS)
+            .unwrap();
+        pool.update_due();
+        let content = RelayContent::from_pool(&pool);
+
+        ErikTreeOutput::create(ErikTreeConfig {
+            base_dir: dir.join("upstream"),
+            state_path: dir.join("state.json"),
+            cleanup_after: 0,
+        })
+        .unwrap()
+        .update(&content)
+        .unwrap();
+        content
+    }
+
+    #[test]
+    fn proxy_caches_and_evicts() {
+        test_with_dir("proxy_caches_and_evicts", |dir| {
+            let content = write_upstream(&dir);
+            let mut objects: Vec<(Hash, Bytes)> = content
+                .objects()
+                .map(|(hash, bytes)| (*hash, bytes.clone()))
+                .take(3)
+                .collect();
+            objects.sort_by_key(|(_, bytes)| bytes.len());
+
+            // Room for the smallest object with either of the others, but
+            // never all three.
+            let (first, second, third) = (&objects[0], &objects[1], &objects[2]);
+            let max_cache_size = (first.1.len() + third.1.len()) as u64;
+
+            let upstream = https("https://upstream.example.net/");
+            let mut mapper = FetchMapper::empty();
+            mapper.add_disk_mapper((&upstream).into(), dir.join("upstream"));
+            let config = ErikProxyConfig {
+                upstream,
+                cache_dir: dir.join("cache"),
+                max_cache_size,
+                index_ttl: DEFAULT_INDEX_TTL,
+            };
+            let proxy = ErikProxy::create(config.clone(), mapper.clone()).unwrap();
+
+            let (fqdn, index) = content.indexes().next().unwrap();
+            assert_eq!(index, &proxy.index(fqdn).unwrap());
+
+            assert_eq!(first.1, proxy.object(first.0).unwrap());
+            assert_eq!(second.1, proxy.object(second.0).unwrap());
+            assert!(proxy.object_path(&first.0).exists());
+
+            // Using the first makes the second the least recently used
+            proxy.object(first.0).unwrap();
+            assert_eq!(third.1, proxy.object(third.0).unwrap());
+            assert!(!proxy.object_path(&second.0).exists());
+            assert!(proxy.object_path(&first.0).exists());
+            assert!(proxy.cache.lock().unwrap().size <= max_cache_size);
+
+            // The cache is picked up again, and still fits
+            let proxy = ErikProxy::create(config, mapper).unwrap();
+            assert!(proxy.cache.lock().unwrap().size <= max_cache_size);
+
+            // Unknown objects cannot be retrieved
+            assert!(proxy.object(Hash::from_data(b"unknown")).is_err());
+        });
+    }
+
+    #[test]
+    fn proxy_concurrent_misses() {
+        test_with_dir("proxy_concurrent_misses", |dir| {
+            let content = write_upstream(&dir);
+            let (hash, bytes) = content.objects().next().unwrap();
+
+            let upstream = https("https://upstream.example.net/");
+            let mut mapper = FetchMapper::empty();
+            mapper.add_disk_mapper((&upstream).into(), dir.join("upstream"));
+            let config = ErikProxyConfig {
+                upstream,
+                cache_dir: dir.join("cache"),
+                max_cache_size: DEFAULT_MAX_CACHE_SIZE,
+                index_ttl: DEFAULT_INDEX_TTL,
+            };
+            let proxy = ErikProxy::create(config, mapper).unwrap();
+
+            // All misses for the same object write it at the same time.
+            std::thread::scope(|scope| {
+                let fetches: Vec<_> = (0..8)
+                    .map(|_| scope.spawn(|| proxy.object(*hash)))
+                    .collect();
+                for fetch in fetches {
+                    assert_eq!(bytes, &fetch.join().unwrap().unwrap());
+                }
+            });
+            assert_eq!(bytes.len() as u64, proxy.cache.lock().unwrap().size);
+            assert_eq!(1, fs::read_dir(dir.join("cache")).unwrap().count());
+        });
+    }
+}
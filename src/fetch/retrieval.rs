@@ -1,22 +1,120 @@
 //! This module is responsible for all fetching things from disk
 //! or HTTPS, or mapping HTTPS requests to disk for testing.
 
-use std::{collections::HashMap, path::PathBuf, str::FromStr, time::Duration};
+use std::{collections::HashMap, fmt, path::PathBuf, str::FromStr, sync::OnceLock, time::Duration};
 
 use anyhow::{Context, anyhow};
 use bytes::Bytes;
-use reqwest::{StatusCode, blocking::Client, header};
+use reqwest::{
+    Certificate, StatusCode,
+    blocking::{Client, ClientBuilder, Response},
+    header,
+};
 use rpki::uri;
+use serde::{Deserialize, Serialize};
 use structopt::clap::{crate_name, crate_version};
 
 use crate::util;
 
 pub const USER_AGENT: &str = concat!(crate_name!(), "/", crate_version!());
 
+/// The HTTP client, shared so that connections are re-used.
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// How server certificates are checked. Fixed once the first client
+/// is built.
+static FETCH_MODE: OnceLock<FetchMode> = OnceLock::new();
+
+/// Extra trust anchors for server certificates. Fixed once the first
+/// client is built.
+static ROOT_CERTIFICATES: OnceLock<Vec<Certificate>> = OnceLock::new();
+
+/// How the TLS certificates of servers are checked when fetching.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FetchMode {
+    /// Certificates must be valid for the host.
+    #[default]
+    Strict,
+
+    /// Any certificate is accepted. Meant for testing with servers
+    /// that use self-signed certificates.
+    Insecure,
+}
+
+impl FromStr for FetchMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(FetchMode::Strict),
+            "insecure" => Ok(FetchMode::Insecure),
+            _ => Err(anyhow!(
+                "invalid fetch mode: {s}, expected strict or insecure"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for FetchMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FetchMode::Strict => write!(f, "strict"),
+            FetchMode::Insecure => write!(f, "insecure"),
+        }
+    }
+}
+
+/// Sets how certificates are checked for all fetches. This can only
+/// be done once, before anything is fetched.
+pub fn set_fetch_mode(mode: FetchMode) -> anyhow::Result<()> {
+    FETCH_MODE
+        .set(mode)
+        .map_err(|_| anyhow!("The fetch mode can only be set before fetching"))
+}
+
+/// Trusts the PEM certificates in the given files as well, e.g. a
+/// local test CA. This can only be done once, before anything is
+/// fetched.
+pub fn set_root_certificates(paths: &[PathBuf]) -> anyhow::Result<()> {
+    let mut certificates = vec![];
+    for path in paths {
+        let pem = util::read_file(path)?;
+        let mut found = Certificate::from_pem_bundle(pem.as_ref())
+            .with_context(|| format!("Cannot read certificates from {}", path.display()))?;
+        if found.is_empty() {
+            return Err(anyhow!("No certificates in {}", path.display()));
+        }
+        certificates.append(&mut found);
+    }
+    ROOT_CERTIFICATES
+        .set(certificates)
+        .map_err(|_| anyhow!("The root certificates can only be set before fetching"))
+}
+
+/// Returns a builder for HTTP clients, which check certificates
+/// according to the fetch mode.
+pub fn client_builder() -> ClientBuilder {
+    let insecure = *FETCH_MODE.get_or_init(FetchMode::default) == FetchMode::Insecure;
+    strict_client_builder()
+        .danger_accept_invalid_certs(insecure)
+        .danger_accept_invalid_hostnames(insecure)
+}
+
+/// Returns a builder for HTTP clients which always check certificates,
+/// whatever the fetch mode. Use this for clients that send secrets.
+pub fn strict_client_builder() -> ClientBuilder {
+    let mut builder = Client::builder();
+    for certificate in ROOT_CERTIFICATES.get_or_init(Vec::new) {
+        builder = builder.add_root_certificate(certificate.clone());
+    }
+    builder
+}
+
 /// The FQDN host part of a URI, as used in the Erik protocol,
 /// as well as in mapping content for FQDNs to local disk, e.g.
 /// for testing.
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash, Ord, PartialOrd, Serialize)]
 pub struct Fqdn(String);
 
 impl Fqdn {
@@ -35,6 +133,12 @@ impl From<&uri::Https> for Fqdn {
     }
 }
 
+impl From<&uri::Rsync> for Fqdn {
+    fn from(uri: &uri::Rsync) -> Self {
+        Self(uri.authority().to_ascii_lowercase())
+    }
+}
+
 impl FromStr for Fqdn {
     type Err = std::convert::Infallible;
 
@@ -43,20 +147,30 @@ impl FromStr for Fqdn {
     }
 }
 
+impl fmt::Display for Fqdn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 /// Maps fetches for URIs to a ResolvedSource
 ///
 /// Contains 0 or more DiskMappers that know how to map
-/// matching URIs to a location on disk. If there is no
-/// applicable mapper then the URI will be used as is.
+/// matching URIs to a location on disk, and 0 or more
+/// HttpMappers that map them to a plain HTTP base URL.
+/// If there is no applicable mapper then the URI will be
+/// used as is.
 #[derive(Clone, Debug, Default)]
 pub struct FetchMapper {
     disk_mappers: HashMap<Fqdn, PathBuf>,
+    http_mappers: HashMap<Fqdn, String>,
 }
 
 impl FetchMapper {
     pub fn empty() -> Self {
         FetchMapper {
             disk_mappers: HashMap::new(),
+            http_mappers: HashMap::new(),
         }
     }
 
@@ -64,9 +178,21 @@ impl FetchMapper {
         self.disk_mappers.insert(fqdn, base_dir);
     }
 
+    /// Maps URIs for the FQDN to plain HTTP, e.g. `http://localhost:3000`.
+    /// This is meant for testing relays on localhost without TLS.
+    pub fn add_http_mapper(&mut self, fqdn: Fqdn, base_url: String) {
+        self.http_mappers
+            .insert(fqdn, base_url.trim_end_matches('/').to_string());
+    }
+
     pub fn resolve(&self, uri: uri::Https) -> ResolvedSource {
         let fqdn = Fqdn::from(&uri);
 
+        if let Some(base_url) = self.http_mappers.get(&fqdn) {
+            let path = uri.path().trim_start_matches('/');
+            return ResolvedSource::Http(format!("{base_url}/{path}"));
+        }
+
         match self.disk_mappers.get(&fqdn) {
             Some(base_path) => {
                 let path = match uri.path().strip_prefix('/') {
@@ -89,55 +215,14 @@ impl FetchMapper {
 pub enum ResolvedSource {
     File(PathBuf),
     Uri(uri::Https),
+    Http(String),
 }
 
 impl ResolvedSource {
     pub fn fetch(&self, etag: Option<&String>) -> anyhow::Result<FetchResponse> {
         match self {
-            ResolvedSource::Uri(uri) => {
-                let client = Client::builder()
-                    .danger_accept_invalid_certs(true) // make this configurable
-                    .danger_accept_invalid_hostnames(true)
-                    .timeout(Duration::from_secs(60))
-                    .build()?;
-
-                let mut request_builder = client.get(uri.as_str());
-                request_builder = request_builder.header(header::USER_AGENT, USER_AGENT);
-
-                if let Some(etag) = etag {
-                    request_builder = request_builder.header(header::IF_NONE_MATCH, etag);
-                }
-
-                let response = request_builder
-                    .send()
-                    .with_context(|| format!("Could not GET: {uri}"))?;
-
-                match response.status() {
-                    StatusCode::OK => {
-                        let etag = match response.headers().get(header::ETAG) {
-                            None => None,
-                            Some(header_value) => Some(
-                                header_value
-                                    .to_str()
-                                    .with_context(|| "invalid ETag in response header")?
-                                    .to_owned(),
-                            ),
-                        };
-
-                        let bytes = response.bytes().with_context(|| {
-                            format!("Got no response from '{uri}' even though the status was OK")
-                        })?;
-
-                        Ok(FetchResponse::Data { bytes, etag })
-                    }
-                    StatusCode::NOT_MODIFIED => Ok(FetchResponse::UnModified),
-                    _ => Err(anyhow!(
-                        "Got unexpected HTTP response to GET for {}: {}",
-                        uri,
-                        response.status()
-                    )),
-                }
-            }
+            ResolvedSource::Uri(uri) => Self::fetch_url(uri.as_str(), etag),
+            ResolvedSource::Http(url) => Self::fetch_url(url, etag),
             ResolvedSource::File(path) => {
                 let bytes = util::read_file(path).with_context(|| {
                     format!(
@@ -145,8 +230,113 @@ impl ResolvedSource {
                         path.to_string_lossy()
                     )
                 })?;
-                Ok(FetchResponse::Data { bytes, etag: None })
+                Ok(FetchResponse::Data {
+                    bytes,
+                    etag: None,
+                    link: None,
+                })
+            }
+        }
+    }
+
+    /// Opens a long-lived stream, such as a Server-Sent Events feed.
+    /// There is no timeout, so this is only useful for sources which
+    /// send data, or keep-alives, regularly.
+    pub fn open_stream(&self, accept: &str) -> anyhow::Result<Response> {
+        let url = match self {
+            ResolvedSource::Uri(uri) => uri.as_str(),
+            ResolvedSource::Http(url) => url.as_str(),
+            ResolvedSource::File(path) => {
+                return Err(anyhow!("Cannot stream from file: {}", path.display()));
+            }
+        };
+
+        let client = client_builder().timeout(None).build()?;
+
+        client
+            .get(url)
+            .header(header::USER_AGENT, USER_AGENT)
+            .header(header::ACCEPT, accept)
+            .send()
+            .with_context(|| format!("Could not GET: {url}"))?
+            .error_for_status()
+            .with_context(|| format!("Could not open stream at {url}"))
+    }
+
+    /// Posts the body, and returns the response body.
+    pub fn post(&self, content_type: &str, body: Vec<u8>) -> anyhow::Result<Bytes> {
+        let url = match self {
+            ResolvedSource::Uri(uri) => uri.as_str(),
+            ResolvedSource::Http(url) => url.as_str(),
+            ResolvedSource::File(path) => {
+                return Err(anyhow!("Cannot post to file: {}", path.display()));
+            }
+        };
+
+        Self::client()?
+            .post(url)
+            .header(header::USER_AGENT, USER_AGENT)
+            .header(header::CONTENT_TYPE, content_type)
+            .body(body)
+            .send()
+            .with_context(|| format!("Could not POST: {url}"))?
+            .error_for_status()
+            .with_context(|| format!("Unexpected response to POST for {url}"))?
+            .bytes()
+            .with_context(|| format!("Got no response from '{url}'"))
+    }
+
+    fn client() -> anyhow::Result<&'static Client> {
+        if let Some(client) = CLIENT.get() {
+            return Ok(client);
+        }
+        let client = client_builder().timeout(Duration::from_secs(60)).build()?;
+        Ok(CLIENT.get_or_init(|| client))
+    }
+
+    fn fetch_url(uri: &str, etag: Option<&String>) -> anyhow::Result<FetchResponse> {
+        let client = Self::client()?;
+
+        let mut request_builder = client.get(uri);
+        request_builder = request_builder.header(header::USER_AGENT, USER_AGENT);
+
+        if let Some(etag) = etag {
+            request_builder = request_builder.header(header::IF_NONE_MATCH, etag);
+        }
+
+        let response = request_builder
+            .send()
+            .with_context(|| format!("Could not GET: {uri}"))?;
+
+        match response.status() {
+            StatusCode::OK => {
+                let etag = match response.headers().get(header::ETAG) {
+                    None => None,
+                    Some(header_value) => Some(
+                        header_value
+                            .to_str()
+                            .with_context(|| "invalid ETag in response header")?
+                            .to_owned(),
+                    ),
+                };
+                let link = response
+                    .headers()
+                    .get(header::LINK)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_owned);
+
+                let bytes = response.bytes().with_context(|| {
+                    format!("Got no response from '{uri}' even though the status was OK")
+                })?;
+
+                Ok(FetchResponse::Data { bytes, etag, link })
             }
+            StatusCode::NOT_MODIFIED => Ok(FetchResponse::UnModified),
+            _ => Err(anyhow!(
+                "Got unexpected HTTP response to GET for {}: {}",
+                uri,
+                response.status()
+            )),
         }
     }
 }
@@ -154,7 +344,12 @@ impl ResolvedSource {
 /// Contains a response from a fetch
 #[derive(Clone, Debug)]
 pub enum FetchResponse {
-    Data { bytes: Bytes, etag: Option<String> },
+    Data {
+        bytes: Bytes,
+        etag: Option<String>,
+        /// The Link header, if any.
+        link: Option<String>,
+    },
     UnModified,
 }
 
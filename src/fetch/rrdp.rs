@@ -1,6 +1,6 @@
 //! Fetch content from an RRDP source.
 
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use anyhow::{Context, anyhow};
 use bytes::Bytes;
@@ -44,6 +44,66 @@ impl NotificationFileResponse {
     }
 }
 
+/// The outcome of an attempt to update an RRDP repository.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum RrdpUpdateOutcome {
+    /// Updated using deltas.
+    Delta,
+
+    /// Updated using the snapshot, because the session changed or
+    /// the deltas could not be used.
+    Snapshot,
+
+    /// The notification file was not modified.
+    UnModified,
+
+    /// The update failed.
+    Error,
+}
+
+impl RrdpUpdateOutcome {
+    pub const ALL: [RrdpUpdateOutcome; 4] = [
+        RrdpUpdateOutcome::Delta,
+        RrdpUpdateOutcome::Snapshot,
+        RrdpUpdateOutcome::UnModified,
+        RrdpUpdateOutcome::Error,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RrdpUpdateOutcome::Delta => "delta",
+            RrdpUpdateOutcome::Snapshot => "snapshot",
+            RrdpUpdateOutcome::UnModified => "unmodified",
+            RrdpUpdateOutcome::Error => "error",
+        }
+    }
+}
+
+/// How updates of an RRDP repository went. This is kept by the pool,
+/// so that failures to get a first snapshot are counted as well.
+#[derive(Clone, Debug, Default)]
+pub struct RrdpUpdateStats {
+    counts: HashMap<RrdpUpdateOutcome, u64>,
+    last: Option<(RrdpUpdateOutcome, Duration)>,
+}
+
+impl RrdpUpdateStats {
+    pub fn record(&mut self, outcome: RrdpUpdateOutcome, duration: Duration) {
+        *self.counts.entry(outcome).or_default() += 1;
+        self.last = Some((outcome, duration));
+    }
+
+    /// The number of updates with the given outcome.
+    pub fn count(&self, outcome: RrdpUpdateOutcome) -> u64 {
+        self.counts.get(&outcome).copied().unwrap_or_default()
+    }
+
+    /// The outcome and duration of the last update, if any.
+    pub fn last(&self) -> Option<(RrdpUpdateOutcome, Duration)> {
+        self.last
+    }
+}
+
 /// Gets content from an RRDP source. Fully trusts the
 /// RRDP source to be complete and reliable with regards
 /// to withdraws and updates.
@@ -80,6 +140,10 @@ pub struct RrdpState {
     /// All current manifest references. Derived and updated
     /// whenever the elements are updated.
     manifests: HashMap<KeyIdentifier, Arc<ManifestRef>>,
+
+    /// How the last update went. The initial snapshot counts as an
+    /// update.
+    outcome: RrdpUpdateOutcome,
 }
 
 impl RrdpState {
@@ -109,9 +173,15 @@ impl RrdpState {
             etag,
             elements,
             manifests,
+            outcome: RrdpUpdateOutcome::Snapshot,
         })
     }
 
+    /// Sets the mapper used to retrieve RRDP files from now on.
+    pub fn set_fetch_mapper(&mut self, fetch_mapper: FetchMapper) {
+        self.fetch_mapper = fetch_mapper;
+    }
+
     /// Update.
     ///
     /// Returns:
@@ -119,8 +189,17 @@ impl RrdpState {
     /// Ok(true)  in case there was an update
     /// Ok(false) in case there was no update
     pub fn update(&mut self) -> anyhow::Result<bool> {
+        let result = self.update_with_outcome();
+        self.outcome = match &result {
+            Ok(outcome) => *outcome,
+            Err(_) => RrdpUpdateOutcome::Error,
+        };
+        result.map(|outcome| outcome != RrdpUpdateOutcome::UnModified)
+    }
+
+    fn update_with_outcome(&mut self) -> anyhow::Result<RrdpUpdateOutcome> {
         match Self::get_notification_file(&self.notify, &self.etag, &self.fetch_mapper)? {
-            NotificationFileResponse::UnModified => Ok(false),
+            NotificationFileResponse::UnModified => Ok(RrdpUpdateOutcome::UnModified),
             NotificationFileResponse::Notification {
                 etag,
                 mut notification_file,
@@ -145,24 +224,54 @@ impl RrdpState {
                     // Nothing to do here. Likely ETags are not supported by the
                     // server (or we are mapping to disk) and we got a response,
                     // it's no different from what we have already.
-                    return Ok(false);
+                    return Ok(RrdpUpdateOutcome::UnModified);
                 }
 
                 if self.session_id != notification_file.session_id() {
                     // session changed, we will have to use the snapshot
-                    self.update_from_snapshot(&notification_file)?
+                    self.update_from_snapshot(&notification_file)?;
+                    Ok(RrdpUpdateOutcome::Snapshot)
+                } else if self.update_from_deltas(&mut notification_file).is_err() {
+                    // deltas failed, fall back to snapshot
+                    self.update_from_snapshot(&notification_file)?;
+                    Ok(RrdpUpdateOutcome::Snapshot)
                 } else {
-                    // try delta, if if fails fall back to snapshot
-                    if self.update_from_deltas(&mut notification_file).is_err() {
-                        self.update_from_snapshot(&notification_file)?;
-                    }
+                    Ok(RrdpUpdateOutcome::Delta)
                 }
-
-                Ok(true)
             }
         }
     }
 
+    /// The RRDP notify URI of this repository.
+    pub fn notify(&self) -> &uri::Https {
+        &self.notify
+    }
+
+    /// The current RRDP session.
+    pub fn session_id(&self) -> Uuid {
+        self.session_id
+    }
+
+    /// The current RRDP serial.
+    pub fn serial(&self) -> u64 {
+        self.serial
+    }
+
+    /// All current elements by their SHA256 hash.
+    pub fn elements(&self) -> &HashMap<Hash, Arc<RepoContentElement>> {
+        &self.elements
+    }
+
+    /// All current manifest references by their AKI.
+    pub fn manifests(&self) -> &HashMap<KeyIdentifier, Arc<ManifestRef>> {
+        &self.manifests
+    }
+
+    /// How the last update went.
+    pub fn outcome(&self) -> RrdpUpdateOutcome {
+        self.outcome
+    }
+
     fn update_from_deltas(
         &mut self,
         notification_file: &mut NotificationFile,
@@ -171,47 +280,79 @@ impl RrdpState {
             return Err(anyhow!("There is a gap in the deltas"));
         }
 
-        let mut new_elements: HashMap<Hash, Arc<RepoContentElement>> = HashMap::new();
-        for delta_ref in notification_file.deltas() {
+        let mut elements = self.elements.clone();
+        let mut by_uri: HashMap<uri::Rsync, Hash> = elements
+            .iter()
+            .map(|(hash, el)| (el.uri().clone(), *hash))
+            .collect();
+        let mut published: HashMap<Hash, Arc<RepoContentElement>> = HashMap::new();
+
+        // Only the deltas after our serial apply, and they must take
+        // us all the way to the serial of the notification file.
+        let deltas: Vec<_> = notification_file
+            .deltas()
+            .iter()
+            .filter(|delta_ref| delta_ref.serial() > self.serial)
+            .collect();
+        if deltas.first().map(|delta_ref| delta_ref.serial()) != Some(self.serial + 1)
+            || deltas.last().map(|delta_ref| delta_ref.serial()) != Some(notification_file.serial())
+        {
+            return Err(anyhow!(
+                "The deltas do not cover our serial up to the current one"
+            ));
+        }
+
+        for delta_ref in deltas {
             let delta = Self::get_delta_file(delta_ref.uri(), &self.fetch_mapper)?;
+            if delta.session_id() != self.session_id || delta.serial() != delta_ref.serial() {
+                return Err(anyhow!("Delta does not match the notification file"));
+            }
 
-            // Sanity check the updates and withdraws as mismatches indicate
-            // that we are out of sync and should do a full snapshot resync
-            // instead.
-            //
-            // But other than that we do not remove any content here. We keep
-            // old files (by hash) around. It is not yet implemented, but the
-            // idea is to use the current set of manifests to determine which
-            // objects are not longer referenced, and may be moved into some
-            // cold(er) storage in case we need to save space or memory.
+            // Publishes, updates and withdraws that do not match what
+            // we have indicate that we are out of sync and should do a
+            // full snapshot resync instead.
             for el in delta.into_elements() {
-                match el {
+                let (uri, data) = match el {
                     rrdp::DeltaElement::Publish(publish_element) => {
                         let (uri, data) = publish_element.unpack();
-                        let hash = Hash::from_data(data.as_ref());
-                        let rce = Arc::new(RepoContentElement { uri, data });
-                        new_elements.insert(hash, rce);
+                        if by_uri.contains_key(&uri) {
+                            return Err(anyhow!("Deltas contain publish for an existing object"));
+                        }
+                        (uri, data)
                     }
                     rrdp::DeltaElement::Update(update_element) => {
                         let (uri, hash, data) = update_element.unpack();
-                        if !self.elements.contains_key(&hash) && !new_elements.contains_key(&hash) {
+                        if by_uri.get(&uri) != Some(&hash) {
                             return Err(anyhow!("Deltas contain update for an unknown object"));
                         }
-                        let rce = Arc::new(RepoContentElement { uri, data });
-                        new_elements.insert(hash, rce);
+                        Self::remove_element(&mut elements, &uri, &hash);
+                        (uri, data)
                     }
                     rrdp::DeltaElement::Withdraw(withdraw_element) => {
-                        let hash = withdraw_element.hash();
-                        if !self.elements.contains_key(hash) && !new_elements.contains_key(hash) {
+                        let (uri, hash) = withdraw_element.unpack();
+                        if by_uri.remove(&uri) != Some(hash) {
                             return Err(anyhow!("Deltas contain withdraw for an unknown object"));
                         }
+                        Self::remove_element(&mut elements, &uri, &hash);
+                        continue;
                     }
-                }
+                };
+                let hash = Hash::from_data(data.as_ref());
+                let rce = Arc::new(RepoContentElement { uri, data });
+                by_uri.insert(rce.uri.clone(), hash);
+                elements.insert(hash, rce.clone());
+                published.insert(hash, rce);
             }
         }
-        let new_manifests = Self::manifests_from_elements(&new_elements);
 
-        self.add_new_elements(new_elements);
+        // Only objects that are still there can bring a new manifest.
+        published.retain(|hash, _| elements.contains_key(hash));
+        let new_manifests = Self::manifests_from_elements(&published);
+
+        self.serial = notification_file.serial();
+        self.elements = elements;
+        self.manifests
+            .retain(|_, mft_ref| self.elements.contains_key(&mft_ref.hash));
         self.add_new_manifests(new_manifests);
 
         Ok(())
@@ -224,20 +365,21 @@ impl RrdpState {
         self.serial = snapshot.serial();
         self.session_id = snapshot.session_id();
 
-        let elements = Self::elements_from_snapshot(snapshot);
-        let manifests = Self::manifests_from_elements(&elements);
-
-        self.add_new_elements(elements);
-        self.add_new_manifests(manifests);
+        self.elements = Self::elements_from_snapshot(snapshot);
+        self.manifests = Self::manifests_from_elements(&self.elements);
 
         Ok(())
     }
 
-    fn add_new_elements(&mut self, elements: HashMap<Hash, Arc<RepoContentElement>>) {
-        for (hash, rce) in elements {
-            // Insert the element if it's missing by way of
-            // clippy's opinion of idiomatic Rust.
-            self.elements.entry(hash).or_insert(rce);
+    /// Removes the element that was replaced or withdrawn, unless the
+    /// same data is also found at another URI.
+    fn remove_element(
+        elements: &mut HashMap<Hash, Arc<RepoContentElement>>,
+        uri: &uri::Rsync,
+        hash: &Hash,
+    ) {
+        if elements.get(hash).is_some_and(|el| el.uri() == uri) {
+            elements.remove(hash);
         }
     }
 
@@ -259,7 +401,7 @@ impl RrdpState {
         fetch_mapper: &FetchMapper,
     ) -> anyhow::Result<NotificationFileResponse> {
         match fetch_mapper.resolve(notify.clone()).fetch(etag.as_ref())? {
-            FetchResponse::Data { bytes, etag } => {
+            FetchResponse::Data { bytes, etag, .. } => {
                 let notification_file = NotificationFile::parse(bytes.as_ref())
                     .with_context(|| "Failed to parse notification file")?;
 
@@ -310,7 +452,7 @@ impl RrdpState {
     /// This assumes that there is only 1 manifest for an AKI, and
     /// performs NO validation that the Manifest EE cert is validly
     /// signed by a keypair that matches the AKI.
-    fn manifests_from_elements(
+    pub(crate) fn manifests_from_elements(
         elements: &HashMap<Hash, Arc<RepoContentElement>>,
     ) -> HashMap<KeyIdentifier, Arc<ManifestRef>> {
         elements
@@ -336,6 +478,10 @@ pub struct RepoContentElement {
 }
 
 impl RepoContentElement {
+    pub fn new(uri: uri::Rsync, data: Bytes) -> Self {
+        RepoContentElement { uri, data }
+    }
+
     pub fn try_manifest_ref(&self, accept_stale: bool) -> anyhow::Result<ManifestRef> {
         if self.uri.ends_with(".mft") {
             let mft = Manifest::decode(self.data.as_ref(), false)?;
@@ -367,6 +513,10 @@ impl RepoContentElement {
         }
     }
 
+    pub fn uri(&self) -> &uri::Rsync {
+        &self.uri
+    }
+
     pub fn data(&self) -> &Bytes {
         &self.data
     }
@@ -440,7 +590,7 @@ impl RepoContent {
 mod tests {
     use std::path::PathBuf;
 
-    use crate::util::https;
+    use crate::util::{TestRrdpSource, https, test_with_dir};
 
     use super::*;
 
@@ -453,10 +603,37 @@ mod tests {
             PathBuf::from("test-resources/rrdp-rev2656/"),
         );
 
-        let rrdp_state = RrdpState::create(notification_uri, mapper).unwrap();
+        let mut rrdp_state = RrdpState::create(notification_uri, mapper).unwrap();
 
         assert!(!rrdp_state.elements.is_empty());
         assert!(!rrdp_state.manifests.is_empty());
+        assert_eq!(RrdpUpdateOutcome::Snapshot, rrdp_state.outcome());
+
+        // The files on disk do not change
+        assert!(!rrdp_state.update().unwrap());
+        assert_eq!(RrdpUpdateOutcome::UnModified, rrdp_state.outcome());
+    }
+
+    #[test]
+    fn follow_deltas_with_update_and_withdraw() {
+        test_with_dir("rrdp_follow_deltas", |dir| {
+            let updated = "rsync://example.net/repo/updated.roa";
+            let withdrawn = "rsync://example.net/repo/withdrawn.roa";
+            let mut source = TestRrdpSource::create(dir, &[(updated, "old"), (withdrawn, "gone")]);
+            let mut state = RrdpState::create(source.notify(), source.fetch_mapper()).unwrap();
+            assert_eq!(2, state.elements().len());
+
+            source.change(&[(updated, Some("new")), (withdrawn, None)]);
+            assert!(state.update().unwrap());
+            assert_eq!(RrdpUpdateOutcome::Delta, state.outcome());
+            assert_eq!(2, state.serial());
+
+            // Only the current object is kept
+            let elements: Vec<_> = state.elements().values().collect();
+            assert_eq!(1, elements.len());
+            assert_eq!(updated, elements[0].uri().as_str());
+            assert_eq!(b"new", elements[0].data().as_ref());
+        });
     }
 
     #[test]
@@ -0,0 +1,342 @@
+//! Read content from a local rsync-style directory tree.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use anyhow::{Context, anyhow};
+use log::{debug, trace};
+use rpki::{crypto::KeyIdentifier, rrdp::Hash, uri};
+
+use crate::{
+    erik::asn1::ManifestRef,
+    fetch::rrdp::{RepoContentElement, RrdpState},
+    util,
+};
+
+/// A file that was seen in the last scan.
+#[derive(Clone, Debug)]
+struct ScannedFile {
+    modified: SystemTime,
+    size: u64,
+    hash: Hash,
+}
+
+/// Gets content from a local directory that is laid out the way an
+/// rsync repository is: `<base_dir>/<host>/<module>/<path>`. Every
+/// file is mapped to its `rsync://<host>/<module>/<path>` URI.
+///
+/// Unlike RrdpState this does not keep withdrawn objects around. The
+/// elements always reflect the files found in the last scan.
+///
+/// Re-scans use the modification time and size of files to avoid
+/// reading unchanged files. Files that were touched, but have the
+/// same content, are detected by their hash and are not reported
+/// as a change.
+#[derive(Clone, Debug)]
+pub struct RsyncDirState {
+    /// The base directory containing host directories.
+    base_dir: PathBuf,
+
+    /// The files found in the last scan, by their path.
+    files: HashMap<PathBuf, ScannedFile>,
+
+    /// All current elements.
+    elements: HashMap<Hash, Arc<RepoContentElement>>,
+
+    /// All current manifest references. Derived and updated
+    /// whenever the elements are updated.
+    manifests: HashMap<KeyIdentifier, Arc<ManifestRef>>,
+}
+
+impl RsyncDirState {
+    /// Create a new state for the given directory, and scan it.
+    pub fn create(base_dir: PathBuf) -> anyhow::Result<Self> {
+        if !base_dir.is_dir() {
+            return Err(anyhow!(
+                "Rsync source is not a directory: {}",
+                base_dir.display()
+            ));
+        }
+
+        let mut state = RsyncDirState {
+            base_dir,
+            files: HashMap::new(),
+            elements: HashMap::new(),
+            manifests: HashMap::new(),
+        };
+        state.update()?;
+        Ok(state)
+    }
+
+    /// Re-scans the directory.
+    ///
+    /// Returns:
+    /// Err       in case of issues
+    /// Ok(true)  in case there was an update
+    /// Ok(false) in case there was no update
+    pub fn update(&mut self) -> anyhow::Result<bool> {
+        let mut paths = vec![];
+        Self::find_files(&self.base_dir, &mut paths)?;
+
+        // Only files that map to a URI count, so changes are found by
+        // comparing those with the files of the last scan.
+        let mut changed = false;
+        let mut files = HashMap::new();
+        let mut elements = HashMap::new();
+
+        for path in paths {
+            let Some(uri) = self.uri_for_path(&path) else {
+                trace!("Skipping file outside of a module: {}", path.display());
+                continue;
+            };
+
+            let meta = std::fs::metadata(&path)
+                .with_context(|| format!("Cannot read metadata for {}", path.display()))?;
+            let modified = meta.modified()?;
+            let size = meta.len();
+
+            // Re-use the existing element if the file looks untouched.
+            if let Some(existing) = self.files.get(&path)
+                && existing.modified == modified
+                && existing.size == size
+                && let Some(element) = self.elements.get(&existing.hash)
+            {
+                elements.insert(existing.hash, element.clone());
+                files.insert(path, existing.clone());
+                continue;
+            }
+
+            let data = util::read_file(&path)
+                .with_context(|| format!("Cannot read file {}", path.display()))?;
+            let hash = Hash::from_data(data.as_ref());
+
+            match self.files.get(&path) {
+                Some(existing) if existing.hash == hash => {}
+                _ => {
+                    debug!("Found new or changed file {}", path.display());
+                    changed = true;
+                }
+            }
+
+            elements.insert(hash, Arc::new(RepoContentElement::new(uri, data)));
+            files.insert(
+                path,
+                ScannedFile {
+                    modified,
+                    size,
+                    hash,
+                },
+            );
+        }
+
+        // Files that are gone are always a change.
+        changed |= self.files.keys().any(|path| !files.contains_key(path));
+
+        if changed {
+            self.manifests = RrdpState::manifests_from_elements(&elements);
+        }
+        self.files = files;
+        self.elements = elements;
+
+        Ok(changed)
+    }
+
+    /// Updates only the given paths, rather than re-scanning the full
+    /// directory. Paths can be files or directories. Paths that no
+    /// longer exist are withdrawn, including all files that we knew
+    /// of under a removed directory.
+    ///
+    /// Returns Ok(true) if there was an update.
+    pub fn update_paths(&mut self, changed: &HashSet<PathBuf>) -> anyhow::Result<bool> {
+        let mut paths = HashSet::new();
+        for path in changed {
+            if path.symlink_metadata().is_ok_and(|meta| meta.is_dir()) {
+                let mut found = vec![];
+                Self::find_files(path, &mut found)?;
+                paths.extend(found);
+            } else {
+                paths.insert(path.clone());
+            }
+            paths.extend(self.files.keys().filter(|k| k.starts_with(path)).cloned());
+        }
+
+        let mut updated = false;
+        for path in paths {
+            updated |= self.update_path(path)?;
+        }
+
+        if updated {
+            self.manifests = RrdpState::manifests_from_elements(&self.elements);
+        }
+
+        Ok(updated)
+    }
+
+    /// Publishes, updates or withdraws a single file.
+    fn update_path(&mut self, path: PathBuf) -> anyhow::Result<bool> {
+        let uri = match self.uri_for_path(&path) {
+            Some(uri) if path.is_file() => uri,
+            _ => {
+                return Ok(match self.files.remove(&path) {
+                    Some(withdrawn) => {
+                        debug!("Withdrawn file {}", path.display());
+                        self.forget_element(withdrawn.hash);
+                        true
+                    }
+                    None => false,
+                });
+            }
+        };
+
+        let meta = std::fs::metadata(&path)
+            .with_context(|| format!("Cannot read metadata for {}", path.display()))?;
+        let data = util::read_file(&path)
+            .with_context(|| format!("Cannot read file {}", path.display()))?;
+        let hash = Hash::from_data(data.as_ref());
+
+        let scanned = ScannedFile {
+            modified: meta.modified()?,
+            size: meta.len(),
+            hash,
+        };
+
+        match self.files.insert(path.clone(), scanned) {
+            Some(previous) if previous.hash == hash => Ok(false),
+            previous => {
+                debug!("Found new or changed file {}", path.display());
+                if let Some(previous) = previous {
+                    self.forget_element(previous.hash);
+                }
+                self.elements
+                    .insert(hash, Arc::new(RepoContentElement::new(uri, data)));
+                Ok(true)
+            }
+        }
+    }
+
+    /// Removes an element, unless another file has the same content.
+    fn forget_element(&mut self, hash: Hash) {
+        if !self.files.values().any(|f| f.hash == hash) {
+            self.elements.remove(&hash);
+        }
+    }
+
+    /// The base directory of this source.
+    pub fn base_dir(&self) -> &Path {
+        &self.base_dir
+    }
+
+    /// All current elements by their SHA256 hash.
+    pub fn elements(&self) -> &HashMap<Hash, Arc<RepoContentElement>> {
+        &self.elements
+    }
+
+    /// All current manifest references by their AKI.
+    pub fn manifests(&self) -> &HashMap<KeyIdentifier, Arc<ManifestRef>> {
+        &self.manifests
+    }
+
+    /// Maps a path under the base dir to its rsync URI. Returns None
+    /// if the path is not inside a module directory.
+    fn uri_for_path(&self, path: &Path) -> Option<uri::Rsync> {
+        let rel = path.strip_prefix(&self.base_dir).ok()?;
+        let components: Vec<_> = rel
+            .components()
+            .map(|c| c.as_os_str().to_str())
+            .collect::<Option<_>>()?;
+
+        if components.len() < 3 {
+            return None;
+        }
+
+        uri::Rsync::from_string(format!("rsync://{}", components.join("/"))).ok()
+    }
+
+    /// Finds all files under the directory. Symlinks to files are
+    /// followed, but symlinks to directories are not, so that they
+    /// cannot lead us around in circles.
+    fn find_files(dir: &Path, paths: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+        for entry in std::fs::read_dir(dir)
+            .with_context(|| format!("Cannot read directory {}", dir.display()))?
+        {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                Self::find_files(&path, paths)?;
+            } else if path.is_file() {
+                paths.push(path);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{fetch::rrdp::RepoContent, util::test_with_dir};
+
+    use super::*;
+
+    /// Writes the test snapshot content as an rsync tree.
+    fn write_test_tree(base_dir: &Path) {
+        let content = RepoContent::create_test().unwrap();
+        for element in content.elements().values() {
+            let uri = element.uri();
+            let path = base_dir
+                .join(uri.canonical_authority().as_ref())
+                .join(uri.module_name())
+                .join(uri.path());
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, element.data()).unwrap();
+        }
+    }
+
+    #[test]
+    fn scan_rsync_dir() {
+        test_with_dir("rsync_dir_scan", |dir| {
+            write_test_tree(&dir);
+
+            let mut state = RsyncDirState::create(dir.clone()).unwrap();
+            let content = RepoContent::create_test().unwrap();
+            assert_eq!(content.elements().len(), state.elements().len());
+            assert_eq!(content.manifests().len(), state.manifests().len());
+
+            let element = state.elements().values().next().unwrap().clone();
+            let uri = element.uri();
+            let path = dir
+                .join(uri.canonical_authority().as_ref())
+                .join(uri.module_name())
+                .join(uri.path());
+            assert_eq!(Some(uri.clone()), state.uri_for_path(&path));
+
+            // Nothing changed
+            assert!(!state.update().unwrap());
+
+            // Files outside of a module and links back up the tree
+            // are ignored.
+            std::fs::write(dir.join("README"), b"not rpki").unwrap();
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(&dir, path.parent().unwrap().join("loop")).unwrap();
+            assert!(!state.update().unwrap());
+            assert_eq!(content.elements().len(), state.elements().len());
+
+            // Same content, new mtime
+            std::fs::write(&path, element.data()).unwrap();
+            assert!(!state.update().unwrap());
+
+            // Withdrawn
+            std::fs::remove_file(&path).unwrap();
+            assert!(state.update().unwrap());
+            assert_eq!(content.elements().len() - 1, state.elements().len());
+
+            // Published again
+            std::fs::write(&path, element.data()).unwrap();
+            assert!(state.update().unwrap());
+            assert_eq!(content.elements().len(), state.elements().len());
+        });
+    }
+}
@@ -0,0 +1,206 @@
+//! Watch a local directory for changes published by a CA.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use log::{debug, warn};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use rpki::{crypto::KeyIdentifier, rrdp::Hash};
+
+use crate::{
+    erik::asn1::ManifestRef,
+    fetch::{rrdp::RepoContentElement, rsync_dir::RsyncDirState},
+};
+
+/// The default time that a directory must be quiet before changes
+/// are picked up.
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Changes seen by the watcher, but not yet applied.
+#[derive(Debug, Default)]
+struct PendingChanges {
+    paths: HashSet<PathBuf>,
+    last_event: Option<Instant>,
+    rescan: bool,
+}
+
+impl PendingChanges {
+    /// Returns true if there were no new events for the debounce
+    /// period. As the last event settled, so did all others.
+    fn is_settled(&self, debounce: Duration) -> bool {
+        self.last_event
+            .is_some_and(|last| last.elapsed() >= debounce)
+    }
+}
+
+/// Gets content from a local rsync-style directory, like RsyncDirState,
+/// but watches the directory for changes instead of re-scanning it.
+///
+/// Changes are only applied after the directory has been quiet for
+/// the debounce period. This way we will not index a half-written
+/// set of objects while a CA is still publishing.
+#[derive(Debug)]
+pub struct WatchDirState {
+    dir: RsyncDirState,
+    debounce: Duration,
+    pending: Arc<Mutex<PendingChanges>>,
+
+    // Kept so that the watch stays active for as long as we live.
+    _watcher: RecommendedWatcher,
+}
+
+impl WatchDirState {
+    /// Scans the given directory and starts watching it.
+    pub fn create(base_dir: PathBuf, debounce: Duration) -> anyhow::Result<Self> {
+        // Events use absolute paths, so we need to use an absolute base
+        // directory to map them back to rsync URIs.
+        let base_dir = std::fs::canonicalize(&base_dir)
+            .with_context(|| format!("Cannot watch directory {}", base_dir.display()))?;
+
+        let pending = Arc::new(Mutex::new(PendingChanges::default()));
+
+        let event_pending = pending.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+                let mut pending = event_pending.lock().unwrap();
+                match res {
+                    Ok(event) => {
+                        if matches!(event.kind, EventKind::Access(_)) {
+                            return;
+                        }
+                        if event.need_rescan() {
+                            pending.rescan = true;
+                        }
+                        pending.paths.extend(event.paths);
+                    }
+                    Err(e) => {
+                        warn!("Error watching directory, will re-scan: {e}");
+                        pending.rescan = true;
+                    }
+                }
+                pending.last_event = Some(Instant::now());
+            })?;
+
+        // Start watching before the initial scan, so that we do not
+        // miss changes made while scanning.
+        watcher
+            .watch(&base_dir, RecursiveMode::Recursive)
+            .with_context(|| format!("Cannot watch directory {}", base_dir.display()))?;
+
+        let dir = RsyncDirState::create(base_dir)?;
+
+        Ok(WatchDirState {
+            dir,
+            debounce,
+            pending,
+            _watcher: watcher,
+        })
+    }
+
+    /// Returns true if there are changes that have settled and can
+    /// be applied.
+    pub fn has_pending_changes(&self) -> bool {
+        self.pending.lock().unwrap().is_settled(self.debounce)
+    }
+
+    /// Applies settled changes.
+    ///
+    /// Returns:
+    /// Err       in case of issues
+    /// Ok(true)  in case there was an update
+    /// Ok(false) in case there was no update
+    pub fn update(&mut self) -> anyhow::Result<bool> {
+        // Check and take in one go, so that events that come in after
+        // the check stay pending until they settled too.
+        let pending = {
+            let mut pending = self.pending.lock().unwrap();
+            if !pending.is_settled(self.debounce) {
+                return Ok(false);
+            }
+            std::mem::take(&mut *pending)
+        };
+        if pending.rescan {
+            debug!(
+                "Re-scanning watched directory {}",
+                self.base_dir().display()
+            );
+            self.dir.update()
+        } else {
+            debug!(
+                "Applying {} changed paths in {}",
+                pending.paths.len(),
+                self.base_dir().display()
+            );
+            self.dir.update_paths(&pending.paths)
+        }
+    }
+
+    /// The watched directory.
+    pub fn base_dir(&self) -> &Path {
+        self.dir.base_dir()
+    }
+
+    /// All current elements by their SHA256 hash.
+    pub fn elements(&self) -> &HashMap<Hash, Arc<RepoContentElement>> {
+        self.dir.elements()
+    }
+
+    /// All current manifest references by their AKI.
+    pub fn manifests(&self) -> &HashMap<KeyIdentifier, Arc<ManifestRef>> {
+        self.dir.manifests()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::test_with_dir;
+
+    use super::*;
+
+    /// Waits until changes are settled. Late events for the same
+    /// change can push this back, so we do not just sleep.
+    fn wait_until_settled(state: &WatchDirState) {
+        let started = Instant::now();
+        while !state.has_pending_changes() {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn watch_dir_debounces_changes() {
+        test_with_dir("watch_dir_debounce", |dir| {
+            let debounce = Duration::from_millis(200);
+            let mut state = WatchDirState::create(dir.clone(), debounce).unwrap();
+            assert!(state.elements().is_empty());
+
+            let file = dir.join("example.net/repo/ca/object.roa");
+            std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+            std::fs::write(&file, b"not really a roa").unwrap();
+
+            // Wait for the event, but changes are not applied until
+            // the debounce period has passed.
+            let started = Instant::now();
+            while state.pending.lock().unwrap().last_event.is_none() {
+                assert!(started.elapsed() < Duration::from_secs(10));
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            assert!(!state.update().unwrap());
+
+            wait_until_settled(&state);
+            assert!(state.update().unwrap());
+            assert_eq!(1, state.elements().len());
+
+            std::fs::remove_file(&file).unwrap();
+            std::thread::sleep(debounce);
+            wait_until_settled(&state);
+            assert!(state.update().unwrap());
+            assert!(state.elements().is_empty());
+        });
+    }
+}
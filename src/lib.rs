@@ -1,4 +1,12 @@
+pub mod admin;
+pub mod config;
+pub mod dashboard;
 pub mod erik;
 pub mod fetch;
+pub mod metrics;
+pub mod output;
+pub mod publication;
+pub mod status;
+pub mod tls;
 // pub mod to_be_cleaned;
 pub mod util;
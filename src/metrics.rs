@@ -0,0 +1,403 @@
+//! Metrics of the relay, in the Prometheus text exposition format.
+//!
+//! Requests and served objects are counted as they happen. Everything
+//! else is derived from the latest status of the pool and the content
+//! that is served when the metrics are rendered.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Write as _},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::{
+    erik::relay::RelayContent,
+    fetch::{pool::PoolStatus, rrdp::RrdpUpdateOutcome},
+};
+
+/// The content type of the rendered metrics.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// The upper bounds of the request duration histogram, in seconds.
+const DURATION_BUCKETS: [f64; 11] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// The metrics that are counted while serving.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Request statistics by route and status code.
+    requests: Mutex<HashMap<(String, u16), RequestStats>>,
+
+    objects_served: AtomicU64,
+    bytes_served: AtomicU64,
+}
+
+#[derive(Clone, Debug, Default)]
+struct RequestStats {
+    count: u64,
+    duration_sum: f64,
+
+    /// The number of requests in each bucket. These are not
+    /// cumulative, that is done when rendering.
+    buckets: [u64; DURATION_BUCKETS.len()],
+}
+
+impl Metrics {
+    /// Counts a request for a route, by the path pattern of the route
+    /// rather than the actual path.
+    pub fn record_request(&self, route: &str, status: u16, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut requests = self.requests.lock().unwrap();
+        let stats = requests.entry((route.to_string(), status)).or_default();
+        stats.count += 1;
+        stats.duration_sum += seconds;
+        if let Some(bucket) = DURATION_BUCKETS.iter().position(|le| seconds <= *le) {
+            stats.buckets[bucket] += 1;
+        }
+    }
+
+    /// Counts objects that were served, and their total size.
+    pub fn record_served(&self, objects: u64, bytes: u64) {
+        self.objects_served.fetch_add(objects, Ordering::Relaxed);
+        self.bytes_served.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Renders all metrics.
+    pub fn render(&self, pool: &PoolStatus, content: &RelayContent) -> String {
+        let mut target = Target::default();
+        self.render_requests(&mut target);
+        Self::render_repositories(&mut target, pool);
+        Self::render_content(&mut target, pool, content);
+        target.out
+    }
+
+    fn render_requests(&self, target: &mut Target) {
+        let mut requests: Vec<_> = self
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, stats)| (key.clone(), stats.clone()))
+            .collect();
+        requests.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        target.header(
+            "http_requests_total",
+            "counter",
+            "HTTP requests by route and status.",
+        );
+        for ((route, status), stats) in &requests {
+            let status = status.to_string();
+            target.sample(
+                "http_requests_total",
+                &[("route", route), ("status", &status)],
+                stats.count,
+            );
+        }
+
+        target.header(
+            "http_request_duration_seconds",
+            "histogram",
+            "Time taken to respond to HTTP requests.",
+        );
+        for ((route, status), stats) in &requests {
+            let status = status.to_string();
+            let mut cumulative = 0;
+            for (le, count) in DURATION_BUCKETS.iter().zip(stats.buckets) {
+                cumulative += count;
+                let le = le.to_string();
+                target.sample(
+                    "http_request_duration_seconds_bucket",
+                    &[("route", route), ("status", &status), ("le", &le)],
+                    cumulative,
+                );
+            }
+            let labels = [("route", route.as_str()), ("status", &status)];
+            target.sample(
+                "http_request_duration_seconds_bucket",
+                &[labels[0], labels[1], ("le", "+Inf")],
+                stats.count,
+            );
+            target.sample(
+                "http_request_duration_seconds_sum",
+                &labels,
+                stats.duration_sum,
+            );
+            target.sample("http_request_duration_seconds_count", &labels, stats.count);
+        }
+
+        target.header("objects_served_total", "counter", "Objects served.");
+        target.sample(
+            "objects_served_total",
+            &[],
+            self.objects_served.load(Ordering::Relaxed),
+        );
+        target.header(
+            "served_bytes_total",
+            "counter",
+            "Total size of the objects served.",
+        );
+        target.sample(
+            "served_bytes_total",
+            &[],
+            self.bytes_served.load(Ordering::Relaxed),
+        );
+    }
+
+    fn render_repositories(target: &mut Target, pool: &PoolStatus) {
+        let mut repositories: Vec<_> = pool
+            .repositories
+            .iter()
+            .map(|status| (status.source.to_string(), status))
+            .collect();
+        repositories.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        target.header(
+            "repository_elements",
+            "gauge",
+            "Current objects per repository.",
+        );
+        for (name, status) in &repositories {
+            target.sample(
+                "repository_elements",
+                &[("repository", name)],
+                status.elements,
+            );
+        }
+
+        target.header(
+            "repository_failures",
+            "gauge",
+            "Consecutive failed updates per repository.",
+        );
+        for (name, status) in &repositories {
+            target.sample(
+                "repository_failures",
+                &[("repository", name)],
+                status.failures,
+            );
+        }
+
+        target.header(
+            "repository_last_update_seconds",
+            "gauge",
+            "Unix time of the last successful update per repository.",
+        );
+        for (name, status) in &repositories {
+            if let Some(last_update) = status.last_update {
+                target.sample(
+                    "repository_last_update_seconds",
+                    &[("repository", name)],
+                    last_update.timestamp(),
+                );
+            }
+        }
+
+        target.header(
+            "rrdp_serial",
+            "gauge",
+            "Current serial per RRDP repository and session.",
+        );
+        for (name, status) in &repositories {
+            if let (Some(session), Some(serial)) = (status.session, status.serial) {
+                let session = session.to_string();
+                target.sample(
+                    "rrdp_serial",
+                    &[("repository", name), ("session", &session)],
+                    serial,
+                );
+            }
+        }
+
+        target.header(
+            "rrdp_updates_total",
+            "counter",
+            "RRDP updates per repository by outcome.",
+        );
+        for (name, status) in &repositories {
+            let Some(stats) = status.rrdp_stats.as_ref() else {
+                continue;
+            };
+            for outcome in RrdpUpdateOutcome::ALL {
+                target.sample(
+                    "rrdp_updates_total",
+                    &[("repository", name), ("outcome", outcome.as_str())],
+                    stats.count(outcome),
+                );
+            }
+        }
+
+        target.header(
+            "rrdp_last_update_duration_seconds",
+            "gauge",
+            "Duration of the last RRDP update per repository, with its outcome.",
+        );
+        for (name, status) in &repositories {
+            if let Some((outcome, duration)) = status.rrdp_stats.as_ref().and_then(|s| s.last()) {
+                target.sample(
+                    "rrdp_last_update_duration_seconds",
+                    &[("repository", name), ("outcome", outcome.as_str())],
+                    duration.as_secs_f64(),
+                );
+            }
+        }
+    }
+
+    fn render_content(target: &mut Target, pool: &PoolStatus, content: &RelayContent) {
+        let mut summaries: Vec<_> = content.index_summaries().collect();
+        summaries.sort_by_key(|(fqdn, _)| fqdn.to_string());
+
+        target.header("indexes", "gauge", "Erik indexes served.");
+        target.sample("indexes", &[], summaries.len());
+
+        target.header("index_partitions", "gauge", "Partitions per Erik index.");
+        for (fqdn, summary) in &summaries {
+            let fqdn = fqdn.to_string();
+            target.sample(
+                "index_partitions",
+                &[("fqdn", &fqdn)],
+                summary.partition_count(),
+            );
+        }
+
+        target.header(
+            "index_time_seconds",
+            "gauge",
+            "Unix time of the index time per Erik index.",
+        );
+        for (fqdn, summary) in &summaries {
+            let fqdn = fqdn.to_string();
+            target.sample(
+                "index_time_seconds",
+                &[("fqdn", &fqdn)],
+                summary.index_time().timestamp(),
+            );
+        }
+
+        target.header(
+            "manifests",
+            "gauge",
+            "Manifests found in the repositories, by what was decided about them.",
+        );
+        let manifests = pool.manifests;
+        for (decision, count) in [
+            ("used", manifests.used),
+            ("superseded", manifests.superseded),
+            ("invalid", manifests.invalid),
+            ("rejected", manifests.rejected),
+        ] {
+            target.sample("manifests", &[("decision", decision)], count);
+        }
+    }
+}
+
+/// Writes metrics in the text exposition format. All metric names
+/// get the `epic_` prefix.
+#[derive(Default)]
+struct Target {
+    out: String,
+}
+
+impl Target {
+    const PREFIX: &str = "epic_";
+
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let prefix = Self::PREFIX;
+        writeln!(self.out, "# HELP {prefix}{name} {help}").unwrap();
+        writeln!(self.out, "# TYPE {prefix}{name} {kind}").unwrap();
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl fmt::Display) {
+        write!(self.out, "{}{name}", Self::PREFIX).unwrap();
+        if !labels.is_empty() {
+            self.out.push('{');
+            for (i, (label, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.out.push(',');
+                }
+                write!(self.out, "{label}=\"").unwrap();
+                for c in value.chars() {
+                    match c {
+                        '\\' => self.out.push_str("\\\\"),
+                        '"' => self.out.push_str("\\\""),
+                        '\n' => self.out.push_str("\\n"),
+                        c => self.out.push(c),
+                    }
+                }
+                self.out.push('"');
+            }
+            self.out.push('}');
+        }
+        writeln!(self.out, " {value}").unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        fetch::{
+            pool::{DEFAULT_REFRESH_SECONDS, RepositoryPool},
+            retrieval::FetchMapper,
+        },
+        util::https,
+    };
+
+    use super::*;
+
+    #[test]
+    fn render_metrics() {
+        let notify = https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+        let mut mapper = FetchMapper::empty();
+        mapper.add_disk_mapper(
+            (&notify).into(),
+            PathBuf::from("test-resources/rrdp-rev2656/"),
+        );
+        let mut pool = RepositoryPool::empty();
+        pool.add(notify.into(), mapper, DEFAULT_REFRESH_SECONDS)
+            .unwrap();
+        pool.update_due();
+        let content = RelayContent::from_pool(&pool);
+
+        let metrics = Metrics::default();
+        metrics.record_request("/.well-known/ni/{alg}/{val}", 200, Duration::from_millis(3));
+        metrics.record_request("/.well-known/ni/{alg}/{val}", 200, Duration::from_secs(5));
+        metrics.record_served(2, 100);
+
+        let rendered = metrics.render(&pool.status(), &content);
+        let lines: Vec<&str> = rendered.lines().collect();
+        let route = r#"route="/.well-known/ni/{alg}/{val}",status="200""#;
+        for expected in [
+            format!("epic_http_requests_total{{{route}}} 2"),
+            format!(r#"epic_http_request_duration_seconds_bucket{{{route},le="0.0025"}} 0"#),
+            format!(r#"epic_http_request_duration_seconds_bucket{{{route},le="0.005"}} 1"#),
+            format!(r#"epic_http_request_duration_seconds_bucket{{{route},le="+Inf"}} 2"#),
+            "epic_objects_served_total 2".to_string(),
+            "epic_served_bytes_total 100".to_string(),
+            "epic_indexes 1".to_string(),
+            concat!(
+                r#"epic_rrdp_updates_total{repository="https://krill-ui-dev.do.nlnetlabs.nl/"#,
+                r#"rrdp/notification.xml",outcome="snapshot"} 1"#
+            )
+            .to_string(),
+        ] {
+            assert!(lines.contains(&expected.as_str()), "missing: {expected}");
+        }
+    }
+
+    #[test]
+    fn escape_label_values() {
+        let mut target = Target::default();
+        target.sample("test", &[("label", "a \"quoted\\\" value\n")], 1);
+        assert_eq!(
+            "epic_test{label=\"a \\\"quoted\\\\\\\" value\\n\"} 1\n",
+            target.out
+        );
+    }
+}
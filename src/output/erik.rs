@@ -0,0 +1,252 @@
+//! Write the content of the relay as a static Erik relay tree, which can
+//! be served by any web server.
+//!
+//! The layout follows the relay URIs:
+//!
+//! - `.well-known/erik/index/<fqdn>` for each index
+//! - `.well-known/ni/sha-256/<b64url>` for each partition and object
+//!
+//! This is the same layout that `FetchMapper::add_disk_mapper` reads.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use log::{debug, info};
+use rpki::rrdp::Hash;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    erik::{ni::NamedInformation, relay::RelayContent},
+    output::DeprecatedFiles,
+    util,
+};
+
+/// The default number of seconds that files are kept after they are
+/// no longer referenced.
+pub const DEFAULT_CLEANUP_AFTER: i64 = 600;
+
+const INDEX_DIR: &str = ".well-known/erik/index";
+
+/// Where and how the static tree is written.
+#[derive(Clone, Debug)]
+pub struct ErikTreeConfig {
+    /// The directory that is served as the root of the relay.
+    pub base_dir: PathBuf,
+
+    /// The file where our own state is kept.
+    pub state_path: PathBuf,
+
+    /// Seconds to keep unreferenced files around, so that clients
+    /// that just read a previous index can still get them.
+    pub cleanup_after: i64,
+}
+
+/// Writes relay content as a static tree.
+///
+/// Objects are named by their hash, so they never change. They are
+/// only written if we did not write them before. Indexes are replaced
+/// atomically. Because objects are written before the indexes that
+/// refer to them, a client never finds an index that refers to a
+/// missing object.
+#[derive(Debug)]
+pub struct ErikTreeOutput {
+    config: ErikTreeConfig,
+    state: ErikTreeState,
+}
+
+impl ErikTreeOutput {
+    /// Creates the output, recovering the prior state if there is one.
+    pub fn create(config: ErikTreeConfig) -> anyhow::Result<Self> {
+        let state = if config.state_path.exists() {
+            let json = util::read_file(&config.state_path)?;
+            serde_json::from_slice(json.as_ref()).with_context(|| {
+                format!(
+                    "Cannot deserialize Erik tree state from {}",
+                    config.state_path.display()
+                )
+            })?
+        } else {
+            ErikTreeState::default()
+        };
+
+        Ok(ErikTreeOutput { config, state })
+    }
+
+    pub fn config(&self) -> &ErikTreeConfig {
+        &self.config
+    }
+
+    /// Writes the given content.
+    ///
+    /// Returns Ok(true) if anything was written or removed.
+    pub fn update(&mut self, content: &RelayContent) -> anyhow::Result<bool> {
+        let mut changed = false;
+
+        let mut objects = HashSet::new();
+        for (hash, bytes) in content.objects() {
+            if !self.state.objects.contains(hash) {
+                util::write_file(&self.object_path(hash), bytes)?;
+                changed = true;
+            }
+            objects.insert(*hash);
+        }
+
+        let mut indexes = HashMap::new();
+        for (fqdn, bytes) in content.indexes() {
+            let hash = Hash::from_data(bytes);
+            let name = fqdn.to_string();
+            if self.state.indexes.get(&name) != Some(&hash) {
+                debug!("Writing Erik index for {fqdn}");
+                util::write_file(&self.index_path(&name), bytes)?;
+                changed = true;
+            }
+            indexes.insert(name, hash);
+        }
+
+        // Deprecate what is no longer referenced, and revive anything
+        // that came back before it was cleaned up.
+        for hash in self.state.objects.difference(&objects) {
+            self.state.deprecated.add(Self::rel_object_path(hash));
+        }
+        for name in self.state.indexes.keys() {
+            if !indexes.contains_key(name) {
+                self.state.deprecated.add(Self::rel_index_path(name));
+            }
+        }
+        let current: HashSet<String> = objects
+            .iter()
+            .map(Self::rel_object_path)
+            .chain(indexes.keys().map(|name| Self::rel_index_path(name)))
+            .collect();
+        self.state
+            .deprecated
+            .revive(|rel_path| current.contains(rel_path));
+
+        self.state.objects = objects;
+        self.state.indexes = indexes;
+
+        changed |= self.clean()?;
+        self.persist()?;
+
+        Ok(changed)
+    }
+
+    /// Removes files that were deprecated for long enough.
+    ///
+    /// Returns Ok(true) if anything was removed.
+    fn clean(&mut self) -> anyhow::Result<bool> {
+        let removed = self
+            .state
+            .deprecated
+            .clean(&self.config.base_dir, self.config.cleanup_after)?;
+        if removed > 0 {
+            info!("Removed {removed} unreferenced files from Erik tree");
+        }
+        Ok(removed > 0)
+    }
+
+    fn persist(&self) -> anyhow::Result<()> {
+        let json = serde_json::to_vec(&self.state)?;
+        util::write_file(&self.config.state_path, &json).with_context(|| {
+            format!(
+                "Could not save state to {}",
+                self.config.state_path.display()
+            )
+        })
+    }
+
+    fn object_path(&self, hash: &Hash) -> PathBuf {
+        self.config.base_dir.join(Self::rel_object_path(hash))
+    }
+
+    fn rel_object_path(hash: &Hash) -> String {
+        NamedInformation::new(*hash).well_known_path()
+    }
+
+    fn index_path(&self, name: &str) -> PathBuf {
+        self.config.base_dir.join(Self::rel_index_path(name))
+    }
+
+    fn rel_index_path(name: &str) -> String {
+        format!("{INDEX_DIR}/{name}")
+    }
+}
+
+/// The state of the tree, persisted as JSON.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct ErikTreeState {
+    /// The hashes of all objects that we wrote and are current.
+    objects: HashSet<Hash>,
+
+    /// The hashes of the current indexes by their scope.
+    indexes: HashMap<String, Hash>,
+
+    /// Files which may be cleaned up after some time.
+    deprecated: DeprecatedFiles,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        erik::ni::WELL_KNOWN_PATH,
+        fetch::{
+            erik::ErikSyncState,
+            pool::{DEFAULT_REFRESH_SECONDS, RepositoryPool},
+            retrieval::FetchMapper,
+        },
+        util::{https, test_with_dir},
+    };
+
+    use super::*;
+
+    fn test_content() -> RelayContent {
+        let notify = https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+        let mut mapper = FetchMapper::empty();
+        mapper.add_disk_mapper(
+            (&notify).into(),
+            PathBuf::from("test-resources/rrdp-rev2656/"),
+        );
+
+        let mut pool = RepositoryPool::empty();
+        pool.add(notify.into(), mapper, DEFAULT_REFRESH_SECONDS)
+            .unwrap();
+        pool.update_due();
+        RelayContent::from_pool(&pool)
+    }
+
+    #[test]
+    fn write_and_read_back_tree() {
+        test_with_dir("output_erik_tree", |dir| {
+            let config = ErikTreeConfig {
+                base_dir: dir.join("relay"),
+                state_path: dir.join("state.json"),
+                cleanup_after: 0,
+            };
+            let mut output = ErikTreeOutput::create(config.clone()).unwrap();
+
+            let content = test_content();
+            assert!(output.update(&content).unwrap());
+            assert!(!output.update(&content).unwrap());
+
+            // Read it back the way a client would
+            let server = https("https://relay.example.net/");
+            let mut mapper = FetchMapper::empty();
+            mapper.add_disk_mapper((&server).into(), config.base_dir.clone());
+
+            let fqdn = content.indexes().next().unwrap().0.clone();
+            let state = ErikSyncState::create(server, fqdn, mapper).unwrap();
+            assert!(!state.elements().is_empty());
+
+            // Everything becomes unreferenced, and is cleaned right away
+            // because the grace period is 0 seconds. Empty directories
+            // go too.
+            let mut output = ErikTreeOutput::create(config.clone()).unwrap();
+            assert!(output.update(&RelayContent::empty()).unwrap());
+            assert!(!config.base_dir.join(WELL_KNOWN_PATH).exists());
+            assert!(config.state_path.exists());
+        });
+    }
+}
@@ -0,0 +1,70 @@
+//! Write the content of the relay in other formats, so that relying
+//! parties that do not (yet) support Erik can still use it.
+
+use std::path::Path;
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::util::{self, Time};
+
+pub mod erik;
+pub mod rrdp;
+pub mod rsync;
+
+/// Files that we wrote, but that are no longer referenced. They are
+/// kept for some time, so that clients that just read something that
+/// refers to them can still get them.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct DeprecatedFiles(Vec<DeprecatedFile>);
+
+/// A file which is no longer referenced, by its path relative to the
+/// directory of the output.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct DeprecatedFile {
+    since: Time,
+    rel_path: String,
+}
+
+impl DeprecatedFiles {
+    /// Deprecates a file as of now.
+    pub fn add(&mut self, rel_path: String) {
+        self.0.push(DeprecatedFile {
+            since: Time::now(),
+            rel_path,
+        });
+    }
+
+    /// Keeps files that are referenced again.
+    pub fn revive(&mut self, is_current: impl Fn(&str) -> bool) {
+        self.0.retain(|d| !is_current(&d.rel_path));
+    }
+
+    /// Removes files that were deprecated for at least `cleanup_after`
+    /// seconds, and then their parent directories if they are empty.
+    /// If this fails, the files that are left are tried again next
+    /// time.
+    ///
+    /// Returns the number of files that were removed.
+    pub fn clean(&mut self, base_dir: &Path, cleanup_after: i64) -> anyhow::Result<usize> {
+        let clean_before = Time::seconds_ago(cleanup_after);
+        let mut removed = 0;
+
+        for deprecated in self.0.iter().filter(|d| d.since <= clean_before) {
+            let path = base_dir.join(&deprecated.rel_path);
+            if path.exists() {
+                debug!(
+                    "Removing {}, deprecated since {}",
+                    path.display(),
+                    deprecated.since
+                );
+                util::remove_file_and_empty_parent_dirs(&path, base_dir)?;
+                removed += 1;
+            }
+        }
+
+        self.0.retain(|d| d.since > clean_before);
+        Ok(removed)
+    }
+}
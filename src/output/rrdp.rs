@@ -0,0 +1,510 @@
+//! Re-publish the content of the relay as a standard RRDP repository.
+//!
+//! The repository has its own session and serial, independent of the
+//! sources that the content came from.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, anyhow};
+use log::{debug, info, warn};
+use rpki::{
+    rrdp::{
+        Delta, DeltaElement, DeltaInfo, Hash, NotificationFile, PublishElement, Snapshot,
+        SnapshotInfo, UpdateElement, WithdrawElement,
+    },
+    uri,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{fetch::rrdp::RepoContentElement, output::DeprecatedFiles, util};
+
+/// The default maximum number of deltas to keep.
+pub const DEFAULT_MAX_DELTAS: usize = 100;
+
+/// The default number of seconds that files are kept after they are
+/// no longer referenced by the notification file.
+pub const DEFAULT_CLEANUP_AFTER: i64 = 600;
+
+const NOTIFICATION_FILE: &str = "notification.xml";
+
+/// Where and how the RRDP repository is written.
+#[derive(Clone, Debug)]
+pub struct RrdpOutputConfig {
+    /// The directory that is served at `base_uri`.
+    pub rrdp_dir: PathBuf,
+
+    /// The file where our own state is kept.
+    pub state_path: PathBuf,
+
+    /// The public base URI of the RRDP directory. The notification
+    /// file will be at `notification.xml` under this URI.
+    pub base_uri: uri::Https,
+
+    /// The maximum number of deltas in the notification file.
+    pub max_deltas: usize,
+
+    /// Seconds to keep deprecated files around, so that relying
+    /// parties that just read the previous notification file can
+    /// still get them.
+    pub cleanup_after: i64,
+}
+
+impl RrdpOutputConfig {
+    /// The public URI of the notification file.
+    pub fn notification_uri(&self) -> anyhow::Result<uri::Https> {
+        self.base_uri
+            .join(NOTIFICATION_FILE.as_bytes())
+            .map_err(|e| anyhow!("Cannot derive notification uri: {e}"))
+    }
+}
+
+/// Writes relay content as an RRDP repository.
+///
+/// New snapshot and delta files are staged under paths that include
+/// the session and serial, so they never replace files that relying
+/// parties may still be reading. They only become visible when the
+/// notification file is replaced, which happens last. Files that are
+/// no longer referenced are deprecated and removed after the
+/// configured `cleanup_after` time.
+#[derive(Debug)]
+pub struct RrdpOutput {
+    config: RrdpOutputConfig,
+    state: RrdpOutputState,
+}
+
+impl RrdpOutput {
+    /// Creates the output, recovering the prior state if there is
+    /// one. Otherwise a new session is started on the first update.
+    pub fn create(config: RrdpOutputConfig) -> anyhow::Result<Self> {
+        let state = if config.state_path.exists() {
+            RrdpOutputState::recover(&config.state_path)?
+        } else {
+            info!("No prior RRDP output state, will start a new session");
+            RrdpOutputState::new()
+        };
+
+        let output = RrdpOutput { config, state };
+        if output.state.snapshot.is_some() {
+            // The notification may not have been written if we
+            // stopped right after persisting our state.
+            output.write_notification()?;
+        }
+
+        Ok(output)
+    }
+
+    pub fn config(&self) -> &RrdpOutputConfig {
+        &self.config
+    }
+
+    pub fn session_id(&self) -> Uuid {
+        self.state.session_id
+    }
+
+    pub fn serial(&self) -> u64 {
+        self.state.serial
+    }
+
+    /// Updates the RRDP repository to the given current object at
+    /// each URI.
+    ///
+    /// Returns Ok(true) if a new serial was written, or Ok(false) if
+    /// the content was unchanged.
+    pub fn update(
+        &mut self,
+        current: &HashMap<uri::Rsync, Arc<RepoContentElement>>,
+    ) -> anyhow::Result<bool> {
+        let delta_elements = self.state.delta_elements(current);
+        if self.state.snapshot.is_some() && delta_elements.is_empty() {
+            debug!("RRDP output is up to date at serial {}", self.state.serial);
+            return Ok(false);
+        }
+
+        let session_id = self.state.session_id;
+        let serial = self.state.serial + 1;
+
+        let mut publish: Vec<PublishElement> = current
+            .values()
+            .map(|el| PublishElement::new(el.uri().clone(), el.data().clone()))
+            .collect();
+        publish.sort_by(|a, b| a.uri().as_str().cmp(b.uri().as_str()));
+
+        let snapshot = Snapshot::new(session_id, serial, publish);
+        let mut snapshot_xml = vec![];
+        snapshot.write_xml(&mut snapshot_xml)?;
+        let snapshot_state = FileState::write(
+            &self.config.rrdp_dir,
+            format!("{session_id}/{serial}/snapshot.xml"),
+            &snapshot_xml,
+        )?;
+
+        // There is no delta for the first serial of a session.
+        let delta_state = if self.state.snapshot.is_some() {
+            let delta = Delta::new(session_id, serial, delta_elements);
+            let mut delta_xml = vec![];
+            delta.write_xml(&mut delta_xml)?;
+            Some(FileState::write(
+                &self.config.rrdp_dir,
+                format!("{session_id}/{serial}/delta.xml"),
+                &delta_xml,
+            )?)
+        } else {
+            None
+        };
+
+        self.state.apply(
+            serial,
+            current
+                .iter()
+                .map(|(uri, el)| (uri.clone(), Hash::from_data(el.data().as_ref())))
+                .collect(),
+            snapshot_state,
+            delta_state,
+            self.config.max_deltas,
+        );
+        self.state.persist(&self.config.state_path)?;
+        self.write_notification()?;
+
+        // Only remove old files once the notification no longer refers
+        // to them. Files that stay behind are removed next time.
+        match self
+            .state
+            .deprecated_files
+            .clean(&self.config.rrdp_dir, self.config.cleanup_after)
+        {
+            Ok(0) => {}
+            Ok(removed) => info!("Removed {removed} deprecated RRDP files"),
+            Err(e) => warn!("Could not remove deprecated RRDP files: {e:#}"),
+        }
+
+        info!("Wrote RRDP session {session_id} serial {serial}");
+        Ok(true)
+    }
+
+    /// Writes the notification file for the current state. It is
+    /// written to a temporary file first and then renamed, so that
+    /// partially written files are never served.
+    fn write_notification(&self) -> anyhow::Result<()> {
+        let snapshot = self
+            .state
+            .snapshot
+            .as_ref()
+            .ok_or_else(|| anyhow!("Trying to write notification file without snapshot"))?;
+
+        let snapshot_info = SnapshotInfo::new(self.uri(&snapshot.rel_path)?, snapshot.hash);
+
+        let mut deltas = vec![];
+        for delta in &self.state.deltas {
+            deltas.push(DeltaInfo::new(
+                delta.serial,
+                self.uri(&delta.file.rel_path)?,
+                delta.file.hash,
+            ));
+        }
+
+        let notification = NotificationFile::new(
+            self.state.session_id,
+            self.state.serial,
+            snapshot_info,
+            deltas,
+        );
+
+        let mut bytes = vec![];
+        notification.write_xml(&mut bytes)?;
+        util::write_file(&self.config.rrdp_dir.join(NOTIFICATION_FILE), &bytes)
+    }
+
+    fn uri(&self, rel_path: &str) -> anyhow::Result<uri::Https> {
+        self.config
+            .base_uri
+            .join(rel_path.as_bytes())
+            .map_err(|e| anyhow!("Could not resolve relative path {rel_path}, error: {e}"))
+    }
+}
+
+//------------ RrdpOutputState -----------------------------------------------
+
+/// The state of the RRDP output, persisted as JSON.
+#[derive(Debug, Deserialize, Serialize)]
+struct RrdpOutputState {
+    #[serde(deserialize_with = "util::de_uuid", serialize_with = "util::ser_uuid")]
+    session_id: Uuid,
+
+    /// The current serial, 0 if nothing was written yet.
+    serial: u64,
+
+    /// The hashes of the objects in the current snapshot.
+    objects: HashMap<uri::Rsync, Hash>,
+
+    /// The current snapshot file.
+    snapshot: Option<FileState>,
+
+    /// The current delta files, in descending serial order.
+    deltas: Vec<DeltaState>,
+
+    /// Files which may be cleaned up after some time.
+    deprecated_files: DeprecatedFiles,
+}
+
+impl RrdpOutputState {
+    fn new() -> Self {
+        RrdpOutputState {
+            session_id: Uuid::new_v4(),
+            serial: 0,
+            objects: HashMap::new(),
+            snapshot: None,
+            deltas: vec![],
+            deprecated_files: DeprecatedFiles::default(),
+        }
+    }
+
+    fn recover(path: &Path) -> anyhow::Result<Self> {
+        let json = util::read_file(path)
+            .with_context(|| format!("Cannot read state file at: {}", path.display()))?;
+        let state: RrdpOutputState = serde_json::from_slice(json.as_ref())
+            .with_context(|| format!("Cannot deserialize RRDP state from {}", path.display()))?;
+
+        info!(
+            "Recovered RRDP output state => session: {}, serial: {}",
+            state.session_id, state.serial
+        );
+        Ok(state)
+    }
+
+    fn persist(&self, path: &Path) -> anyhow::Result<()> {
+        let json = serde_json::to_string_pretty(&self)?;
+        util::write_file(path, json.as_bytes())
+            .with_context(|| format!("Could not save state to {}.", path.display()))
+    }
+
+    /// Returns the delta from the current objects to the given ones.
+    fn delta_elements(
+        &self,
+        current: &HashMap<uri::Rsync, Arc<RepoContentElement>>,
+    ) -> Vec<DeltaElement> {
+        let mut elements = vec![];
+
+        for (uri, el) in current {
+            match self.objects.get(uri) {
+                None => elements.push(DeltaElement::Publish(PublishElement::new(
+                    uri.clone(),
+                    el.data().clone(),
+                ))),
+                Some(hash) if !hash.matches(el.data().as_ref()) => elements.push(
+                    DeltaElement::Update(UpdateElement::new(uri.clone(), *hash, el.data().clone())),
+                ),
+                Some(_) => {}
+            }
+        }
+
+        for (uri, hash) in &self.objects {
+            if !current.contains_key(uri) {
+                elements.push(DeltaElement::Withdraw(WithdrawElement::new(
+                    uri.clone(),
+                    *hash,
+                )));
+            }
+        }
+
+        elements
+    }
+
+    /// Moves to the new serial. The old snapshot and any deltas beyond
+    /// `max_deltas` are deprecated.
+    fn apply(
+        &mut self,
+        serial: u64,
+        objects: HashMap<uri::Rsync, Hash>,
+        snapshot: FileState,
+        delta: Option<FileState>,
+        max_deltas: usize,
+    ) {
+        if let Some(old) = self.snapshot.replace(snapshot) {
+            self.deprecated_files.add(old.rel_path);
+        }
+
+        if let Some(file) = delta {
+            self.deltas.insert(0, DeltaState { serial, file });
+        }
+        if self.deltas.len() > max_deltas {
+            for delta in self.deltas.drain(max_deltas..) {
+                self.deprecated_files.add(delta.file.rel_path);
+            }
+        }
+
+        self.serial = serial;
+        self.objects = objects;
+    }
+}
+
+//------------ FileState -----------------------------------------------------
+
+/// A snapshot or delta file that we wrote.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct FileState {
+    rel_path: String,
+    hash: Hash,
+}
+
+impl FileState {
+    fn write(base_dir: &Path, rel_path: String, xml: &[u8]) -> anyhow::Result<Self> {
+        util::write_file(&base_dir.join(&rel_path), xml)?;
+        Ok(FileState {
+            rel_path,
+            hash: Hash::from_data(xml),
+        })
+    }
+}
+
+/// A delta file that is referenced by the notification file.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct DeltaState {
+    serial: u64,
+    file: FileState,
+}
+
+#[cfg(test)]
+mod tests {
+    use rpki::rrdp::{Delta, NotificationFile};
+
+    use crate::{
+        fetch::{
+            pool::{DEFAULT_REFRESH_SECONDS, RepositoryPool},
+            rrdp::RepoContent,
+        },
+        util::{TestRrdpSource, https, test_with_dir},
+    };
+
+    use super::*;
+
+    fn test_config(dir: &Path, cleanup_after: i64) -> RrdpOutputConfig {
+        RrdpOutputConfig {
+            rrdp_dir: dir.join("rrdp"),
+            state_path: dir.join("state.json"),
+            base_uri: https("https://relay.example.net/rrdp/"),
+            max_deltas: 2,
+            cleanup_after,
+        }
+    }
+
+    fn test_objects() -> HashMap<uri::Rsync, Arc<RepoContentElement>> {
+        RepoContent::create_test()
+            .unwrap()
+            .elements()
+            .values()
+            .map(|el| {
+                let el = RepoContentElement::new(el.uri().clone(), el.data().clone());
+                (el.uri().clone(), Arc::new(el))
+            })
+            .collect()
+    }
+
+    fn read_notification(dir: &Path) -> NotificationFile {
+        let bytes = util::read_file(&dir.join("rrdp").join(NOTIFICATION_FILE)).unwrap();
+        NotificationFile::parse(bytes.as_ref()).unwrap()
+    }
+
+    #[test]
+    fn write_snapshot_and_deltas() {
+        test_with_dir("output_rrdp_write", |dir| {
+            let mut output = RrdpOutput::create(test_config(&dir, 0)).unwrap();
+            let mut objects = test_objects();
+
+            assert!(output.update(&objects).unwrap());
+            assert!(!output.update(&objects).unwrap());
+
+            let notification = read_notification(&dir);
+            assert_eq!(output.session_id(), notification.session_id());
+            assert_eq!(1, notification.serial());
+            assert!(notification.deltas().is_empty());
+
+            // Withdraw an element, and expect a delta
+            let withdrawn = objects.keys().next().unwrap().clone();
+            objects.remove(&withdrawn);
+            assert!(output.update(&objects).unwrap());
+
+            let notification = read_notification(&dir);
+            assert_eq!(2, notification.serial());
+            assert_eq!(1, notification.deltas().len());
+
+            let delta_path = dir
+                .join("rrdp")
+                .join(format!("{}/2/delta.xml", output.session_id()));
+            let delta_bytes = util::read_file(&delta_path).unwrap();
+            assert!(
+                notification.deltas()[0]
+                    .hash()
+                    .matches(delta_bytes.as_ref())
+            );
+            let delta = Delta::parse(delta_bytes.as_ref()).unwrap();
+            assert!(matches!(delta.elements(), [DeltaElement::Withdraw(_)]));
+
+            // The old snapshot is cleaned up right away, because we
+            // configured cleanup_after to be 0 seconds.
+            let old_snapshot = dir
+                .join("rrdp")
+                .join(format!("{}/1/snapshot.xml", output.session_id()));
+            assert!(!old_snapshot.exists());
+
+            // Deltas are limited to max_deltas
+            for _ in 0..3 {
+                let withdrawn = objects.keys().next().unwrap().clone();
+                objects.remove(&withdrawn);
+                output.update(&objects).unwrap();
+            }
+            let notification = read_notification(&dir);
+            assert_eq!(5, notification.serial());
+            assert_eq!(2, notification.deltas().len());
+
+            // State is recovered with the same session and serial
+            let recovered = RrdpOutput::create(test_config(&dir, 0)).unwrap();
+            assert_eq!(output.session_id(), recovered.session_id());
+            assert_eq!(5, recovered.serial());
+        });
+    }
+
+    #[test]
+    fn write_current_objects_of_source() {
+        test_with_dir("output_rrdp_source", |dir| {
+            let updated = "rsync://example.net/repo/updated.roa";
+            let withdrawn = "rsync://example.net/repo/withdrawn.roa";
+            let mut source = TestRrdpSource::create(
+                dir.join("source"),
+                &[(updated, "old"), (withdrawn, "gone")],
+            );
+            let notify = source.notify();
+            let mut pool = RepositoryPool::empty();
+            pool.add(
+                notify.clone().into(),
+                source.fetch_mapper(),
+                DEFAULT_REFRESH_SECONDS,
+            )
+            .unwrap();
+            pool.update_due();
+
+            let mut output = RrdpOutput::create(test_config(&dir, 0)).unwrap();
+            assert!(output.update(&pool.objects()).unwrap());
+
+            source.change(&[(updated, Some("new")), (withdrawn, None)]);
+            assert!(pool.update(&notify.into()).unwrap());
+            assert!(output.update(&pool.objects()).unwrap());
+
+            let snapshot_path = dir
+                .join("rrdp")
+                .join(format!("{}/2/snapshot.xml", output.session_id()));
+            let snapshot_bytes = util::read_file(&snapshot_path).unwrap();
+            let snapshot = Snapshot::parse(snapshot_bytes.as_ref()).unwrap();
+            let published: Vec<(&str, &[u8])> = snapshot
+                .elements()
+                .iter()
+                .map(|el| (el.uri().as_str(), el.data().as_ref()))
+                .collect();
+            assert_eq!(vec![(updated, b"new".as_ref())], published);
+        });
+    }
+}
@@ -0,0 +1,301 @@
+//! Write the content of the relay as an rsync-style directory tree.
+//!
+//! The tree can be served by rsyncd, or used by validators as an
+//! offline cache.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, anyhow};
+use log::{debug, info};
+use rpki::uri;
+
+use crate::{
+    fetch::rrdp::RepoContentElement,
+    util::{self, Time},
+};
+
+/// The name of the directory, or symlink, with the current content.
+pub const CURRENT_DIR: &str = "current";
+
+/// Where and how the rsync tree is written.
+#[derive(Clone, Debug)]
+pub struct RsyncOutputConfig {
+    /// The base directory. The current content is always found in
+    /// `<rsync_dir>/current`.
+    pub rsync_dir: PathBuf,
+
+    /// Include the host in the path, so that content from different
+    /// rsync base URIs can be served:
+    /// `<rsync_dir>/current/<host>/<module>/..`. Otherwise paths
+    /// start with the module.
+    pub include_host: bool,
+
+    /// Swap the current content by replacing a symlink. Otherwise the
+    /// directories are moved, which is not entirely atomic.
+    pub use_symlinks: bool,
+}
+
+impl RsyncOutputConfig {
+    pub fn new(rsync_dir: PathBuf, include_host: bool) -> Self {
+        RsyncOutputConfig {
+            rsync_dir,
+            include_host,
+            use_symlinks: cfg!(unix),
+        }
+    }
+
+    /// The path of the current content.
+    pub fn current(&self) -> PathBuf {
+        self.rsync_dir.join(CURRENT_DIR)
+    }
+}
+
+/// Writes relay content as an rsync tree.
+///
+/// Each update writes the complete object set to a new directory,
+/// which then replaces the current directory. Readers therefore see
+/// either the old or the new set, never a mix.
+#[derive(Clone, Debug)]
+pub struct RsyncOutput {
+    config: RsyncOutputConfig,
+}
+
+impl RsyncOutput {
+    pub fn new(config: RsyncOutputConfig) -> Self {
+        RsyncOutput { config }
+    }
+
+    pub fn config(&self) -> &RsyncOutputConfig {
+        &self.config
+    }
+
+    /// Writes the given current object at each URI, and makes them
+    /// the current content.
+    pub fn write(
+        &self,
+        objects: &HashMap<uri::Rsync, Arc<RepoContentElement>>,
+    ) -> anyhow::Result<()> {
+        let new_dir = self.new_dir();
+
+        for (uri, el) in objects {
+            let path = new_dir.join(self.rel_path(uri)?);
+            util::write_file(&path, el.data())?;
+        }
+        // Make sure that there is a directory, even without content.
+        fs::create_dir_all(&new_dir)?;
+
+        if self.config.use_symlinks {
+            self.swap_symlink(&new_dir)?;
+        } else {
+            self.swap_move(&new_dir)?;
+        }
+
+        info!(
+            "Wrote {} objects to rsync dir {}",
+            objects.len(),
+            self.config.current().display()
+        );
+        Ok(())
+    }
+
+    /// Returns the path of an object relative to the current dir.
+    fn rel_path(&self, uri: &uri::Rsync) -> anyhow::Result<PathBuf> {
+        let path = uri.path();
+        if path.split('/').any(|part| part == "..") {
+            return Err(anyhow!("Refusing to write object with uri {uri}"));
+        }
+
+        let mut rel = PathBuf::new();
+        if self.config.include_host {
+            rel.push(uri.canonical_authority().as_ref());
+        }
+        rel.push(uri.module_name());
+        rel.push(path);
+        Ok(rel)
+    }
+
+    /// A new directory, named after the current time. A suffix is
+    /// added if needed, so that we never write into the directory
+    /// that is current.
+    fn new_dir(&self) -> PathBuf {
+        let name = format!("{CURRENT_DIR}-{}", Time::now().timestamp());
+        let mut dir = self.config.rsync_dir.join(&name);
+        let mut count = 0;
+        while dir.exists() {
+            count += 1;
+            dir = self.config.rsync_dir.join(format!("{name}-{count}"));
+        }
+        dir
+    }
+
+    /// Points the current symlink to the new dir, and removes the dir
+    /// that it pointed to before. A symlink is created next to the
+    /// current one, and then renamed over it, which is atomic.
+    #[cfg(unix)]
+    fn swap_symlink(&self, new_dir: &Path) -> anyhow::Result<()> {
+        let current = self.config.current();
+        let old_dir = fs::read_link(&current).ok();
+
+        let target = new_dir.canonicalize()?;
+        let tmp_link = self.config.rsync_dir.join(format!("{CURRENT_DIR}.tmp"));
+        if fs::symlink_metadata(&tmp_link).is_ok() {
+            fs::remove_file(&tmp_link)?;
+        }
+        std::os::unix::fs::symlink(&target, &tmp_link)
+            .with_context(|| format!("Cannot create symlink {}", tmp_link.display()))?;
+
+        if current.is_dir() && fs::symlink_metadata(&current)?.is_dir() {
+            // Switching from moves to symlinks, we cannot rename a
+            // link over a directory.
+            fs::remove_dir_all(&current)?;
+        }
+        fs::rename(&tmp_link, &current)
+            .with_context(|| format!("Cannot replace symlink {}", current.display()))?;
+
+        if let Some(old_dir) = old_dir
+            && old_dir != target
+        {
+            debug!("Removing old rsync dir {}", old_dir.display());
+            fs::remove_dir_all(&old_dir)
+                .with_context(|| format!("Cannot remove old dir {}", old_dir.display()))?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn swap_symlink(&self, new_dir: &Path) -> anyhow::Result<()> {
+        self.swap_move(new_dir)
+    }
+
+    /// Moves the current dir out of the way, and moves the new dir in
+    /// its place.
+    fn swap_move(&self, new_dir: &Path) -> anyhow::Result<()> {
+        let current = self.config.current();
+        let old = self.config.rsync_dir.join(format!("{CURRENT_DIR}.old"));
+
+        if old.exists() {
+            fs::remove_dir_all(&old)?;
+        }
+        if fs::symlink_metadata(&current).is_ok_and(|meta| meta.file_type().is_symlink()) {
+            let old_dir = fs::read_link(&current)?;
+            fs::remove_file(&current)?;
+            fs::remove_dir_all(old_dir)?;
+        } else if current.exists() {
+            fs::rename(&current, &old)
+                .with_context(|| format!("Cannot move {} out of the way", current.display()))?;
+        }
+
+        fs::rename(new_dir, &current)
+            .with_context(|| format!("Cannot move new dir to {}", current.display()))?;
+
+        if old.exists() {
+            fs::remove_dir_all(&old)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        fetch::{
+            pool::{DEFAULT_REFRESH_SECONDS, RepositoryPool},
+            rrdp::RepoContent,
+        },
+        util::{TestRrdpSource, test_with_dir},
+    };
+
+    use super::*;
+
+    fn test_objects() -> HashMap<uri::Rsync, Arc<RepoContentElement>> {
+        RepoContent::create_test()
+            .unwrap()
+            .elements()
+            .values()
+            .map(|el| {
+                let el = RepoContentElement::new(el.uri().clone(), el.data().clone());
+                (el.uri().clone(), Arc::new(el))
+            })
+            .collect()
+    }
+
+    fn check_write(dir: PathBuf, use_symlinks: bool) {
+        let config = RsyncOutputConfig {
+            rsync_dir: dir.clone(),
+            include_host: true,
+            use_symlinks,
+        };
+        let output = RsyncOutput::new(config.clone());
+        let mut objects = test_objects();
+
+        output.write(&objects).unwrap();
+        let el = objects.values().next().unwrap().clone();
+        let uri = el.uri();
+        let path = config
+            .current()
+            .join(uri.canonical_authority().as_ref())
+            .join(uri.module_name())
+            .join(uri.path());
+        assert_eq!(el.data(), &util::read_file(&path).unwrap());
+
+        // Write again without the element, it should be gone and only
+        // the current dir (and link target) should remain.
+        objects.remove(uri);
+        output.write(&objects).unwrap();
+        assert!(!path.exists());
+
+        let entries = fs::read_dir(&dir).unwrap().count();
+        assert_eq!(if use_symlinks { 2 } else { 1 }, entries);
+    }
+
+    #[test]
+    fn write_rsync_dir_with_moves() {
+        test_with_dir("output_rsync_moves", |dir| check_write(dir, false));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_rsync_dir_with_symlinks() {
+        test_with_dir("output_rsync_symlinks", |dir| check_write(dir, true));
+    }
+
+    #[test]
+    fn write_current_objects_of_source() {
+        test_with_dir("output_rsync_source", |dir| {
+            let updated = "rsync://example.net/repo/updated.roa";
+            let withdrawn = "rsync://example.net/repo/withdrawn.roa";
+            let mut source = TestRrdpSource::create(
+                dir.join("source"),
+                &[(updated, "old"), (withdrawn, "gone")],
+            );
+            let notify = source.notify();
+            let mut pool = RepositoryPool::empty();
+            pool.add(
+                notify.clone().into(),
+                source.fetch_mapper(),
+                DEFAULT_REFRESH_SECONDS,
+            )
+            .unwrap();
+            pool.update_due();
+
+            let output = RsyncOutput::new(RsyncOutputConfig::new(dir.join("rsync"), false));
+            output.write(&pool.objects()).unwrap();
+
+            source.change(&[(updated, Some("new")), (withdrawn, None)]);
+            assert!(pool.update(&notify.into()).unwrap());
+            output.write(&pool.objects()).unwrap();
+
+            let repo = output.config().current().join("repo");
+            assert_eq!(
+                b"new",
+                util::read_file(&repo.join("updated.roa")).unwrap().as_ref()
+            );
+            assert!(!repo.join("withdrawn.roa").exists());
+        });
+    }
+}
@@ -0,0 +1,669 @@
+//! An RFC 8181 publication server, so that CAs can publish straight
+//! into the relay.
+//!
+//! Published content is kept in the repository pool like any other
+//! repository, so it ends up in the same Erik indexes.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::{Context, anyhow};
+use bytes::Bytes;
+use log::{debug, info, warn};
+use openssl::{pkey::PKey, rsa::Rsa};
+use rpki::{
+    ca::{
+        idcert::IdCert,
+        idexchange::{PublisherHandle, PublisherRequest, RepositoryResponse, ServiceUri},
+        publication::{
+            Base64, ErrorReply, ListElement, ListReply, Message, PublicationCms,
+            PublishDeltaElement, Query, ReportError, ReportErrorCode,
+        },
+    },
+    crypto::softsigner::{KeyId, OpenSslSigner},
+    repository::x509::{Time, Validity},
+    rrdp::Hash,
+    uri,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    erik::asn1::ManifestRef,
+    fetch::rrdp::{RepoContentElement, RrdpState},
+    util,
+};
+
+/// The content type used for RFC 8181 messages.
+pub const CONTENT_TYPE: &str = "application/rpki-publication";
+
+const ID_KEY_FILE: &str = "id.key";
+const ID_CERT_FILE: &str = "id.cer";
+const CONTENT_FILE: &str = "content.json";
+const PUBLISHERS_DIR: &str = "publishers";
+const RESPONSES_DIR: &str = "responses";
+
+/// The URIs that this publication server tells its publishers about.
+#[derive(Clone, Debug)]
+pub struct PublicationConfig {
+    /// The base URI for the RFC 8181 service. The publisher handle
+    /// is appended to this for each publisher.
+    pub service_uri: uri::Https,
+
+    /// The base rsync URI. Each publisher gets its own directory
+    /// under it, named after its handle.
+    pub sia_base: uri::Rsync,
+
+    /// The RRDP notification URI to include in repository responses,
+    /// if any.
+    pub rrdp_notification_uri: Option<uri::Https>,
+}
+
+/// A configured publisher.
+#[derive(Clone, Debug)]
+struct Publisher {
+    id_cert: IdCert,
+    base_uri: uri::Rsync,
+}
+
+/// The currently published objects.
+#[derive(Debug, Default)]
+struct PublishedContent {
+    /// All current objects by their URI.
+    objects: HashMap<uri::Rsync, Arc<RepoContentElement>>,
+
+    /// All current elements. Derived from objects.
+    elements: HashMap<Hash, Arc<RepoContentElement>>,
+
+    /// All current manifest references. Derived from elements.
+    manifests: HashMap<rpki::crypto::KeyIdentifier, Arc<ManifestRef>>,
+}
+
+/// The content as it is persisted on disk.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct PersistedContent {
+    objects: Vec<Arc<RepoContentElement>>,
+}
+
+impl PublishedContent {
+    fn from_objects(objects: HashMap<uri::Rsync, Arc<RepoContentElement>>) -> Self {
+        let elements: HashMap<Hash, Arc<RepoContentElement>> = objects
+            .values()
+            .map(|el| (Hash::from_data(el.data().as_ref()), el.clone()))
+            .collect();
+        let manifests = RrdpState::manifests_from_elements(&elements);
+
+        PublishedContent {
+            objects,
+            elements,
+            manifests,
+        }
+    }
+
+    fn list(&self, base_uri: &uri::Rsync) -> ListReply {
+        let mut reply = ListReply::empty();
+        for (uri, el) in &self.objects {
+            if base_uri.is_parent_of(uri) {
+                reply.add_element(ListElement::new(
+                    uri.clone(),
+                    Hash::from_data(el.data().as_ref()),
+                ));
+            }
+        }
+        reply
+    }
+
+    /// Returns the content with all elements of a delta applied. If
+    /// any of them cannot be applied, none are.
+    fn apply(
+        &self,
+        base_uri: &uri::Rsync,
+        elements: Vec<PublishDeltaElement>,
+    ) -> Result<Self, ReportErrorCode> {
+        let mut objects = self.objects.clone();
+
+        for element in elements {
+            match element {
+                PublishDeltaElement::Publish(publish) => {
+                    let (_, uri, content) = publish.unpack();
+                    Self::check_uri(base_uri, &uri)?;
+                    if objects.contains_key(&uri) {
+                        return Err(ReportErrorCode::ObjectAlreadyPresent);
+                    }
+                    let el = RepoContentElement::new(uri.clone(), content.to_bytes());
+                    objects.insert(uri, Arc::new(el));
+                }
+                PublishDeltaElement::Update(update) => {
+                    let (_, uri, content, hash) = update.unpack();
+                    Self::check_uri(base_uri, &uri)?;
+                    Self::check_hash(&objects, &uri, hash)?;
+                    let el = RepoContentElement::new(uri.clone(), content.to_bytes());
+                    objects.insert(uri, Arc::new(el));
+                }
+                PublishDeltaElement::Withdraw(withdraw) => {
+                    let (_, uri, hash) = withdraw.unpack();
+                    Self::check_uri(base_uri, &uri)?;
+                    Self::check_hash(&objects, &uri, hash)?;
+                    objects.remove(&uri);
+                }
+            }
+        }
+
+        Ok(Self::from_objects(objects))
+    }
+
+    fn check_uri(base_uri: &uri::Rsync, uri: &uri::Rsync) -> Result<(), ReportErrorCode> {
+        if base_uri.is_parent_of(uri) {
+            Ok(())
+        } else {
+            Err(ReportErrorCode::PermissionFailure)
+        }
+    }
+
+    fn check_hash(
+        objects: &HashMap<uri::Rsync, Arc<RepoContentElement>>,
+        uri: &uri::Rsync,
+        hash: Hash,
+    ) -> Result<(), ReportErrorCode> {
+        match objects.get(uri) {
+            None => Err(ReportErrorCode::NoObjectPresent),
+            Some(el) if !hash.matches(el.data().as_ref()) => {
+                Err(ReportErrorCode::NoObjectMatchingHash)
+            }
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+/// An RFC 8181 publication server.
+///
+/// All state is kept in a single directory:
+///
+/// - `id.key` and `id.cer`: the key and ID certificate of the server,
+///   generated when missing
+/// - `publishers/*.xml`: RFC 8183 publisher requests, one for each
+///   publisher that is allowed to publish
+/// - `responses/<handle>.xml`: the RFC 8183 repository responses for
+///   each publisher, generated at start up
+/// - `content.json`: the currently published objects
+pub struct PublicationServer {
+    state_dir: PathBuf,
+    config: PublicationConfig,
+    signer: OpenSslSigner,
+    key_id: KeyId,
+    id_cert: IdCert,
+    publishers: HashMap<PublisherHandle, Publisher>,
+    content: PublishedContent,
+
+    /// Set when the content changed, cleared by `update`.
+    changed: bool,
+}
+
+impl std::fmt::Debug for PublicationServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PublicationServer")
+            .field("state_dir", &self.state_dir)
+            .field("publishers", &self.publishers.keys().collect::<Vec<_>>())
+            .field("objects", &self.content.objects.len())
+            .finish()
+    }
+}
+
+impl PublicationServer {
+    /// Creates a publication server using the given state directory,
+    /// recovering its key, publishers and content if present.
+    pub fn create(state_dir: PathBuf, config: PublicationConfig) -> anyhow::Result<Self> {
+        let signer = OpenSslSigner::new();
+        let (key_id, id_cert) = Self::load_or_create_identity(&state_dir, &signer)?;
+        let content = Self::load_content(&state_dir.join(CONTENT_FILE))?;
+
+        let mut server = PublicationServer {
+            state_dir,
+            config,
+            signer,
+            key_id,
+            id_cert,
+            publishers: HashMap::new(),
+            content,
+            changed: true,
+        };
+        server.load_publishers()?;
+
+        Ok(server)
+    }
+
+    /// Adds a publisher from its RFC 8183 publisher request, and
+    /// returns the repository response for it.
+    pub fn add_publisher(
+        &mut self,
+        request: &PublisherRequest,
+    ) -> anyhow::Result<RepositoryResponse> {
+        let id_cert = request
+            .validate()
+            .map_err(|e| anyhow!("Invalid publisher request: {e}"))?;
+        let handle = request.publisher_handle().clone();
+
+        let base_uri = self
+            .config
+            .sia_base
+            .join(format!("{handle}/").as_bytes())
+            .map_err(|e| anyhow!("Cannot derive base uri for publisher {handle}: {e}"))?;
+
+        let service_uri = self
+            .config
+            .service_uri
+            .join(handle.as_str().as_bytes())
+            .map_err(|e| anyhow!("Cannot derive service uri for publisher {handle}: {e}"))?;
+
+        let response = RepositoryResponse::new(
+            Base64::from_content(&self.id_cert.to_bytes()),
+            handle.clone(),
+            ServiceUri::Https(service_uri),
+            base_uri.clone(),
+            self.config.rrdp_notification_uri.clone(),
+            None,
+        );
+
+        info!("Added publisher {handle} with base uri {base_uri}");
+        self.publishers
+            .insert(handle, Publisher { id_cert, base_uri });
+
+        Ok(response)
+    }
+
+    /// Handles an RFC 8181 CMS wrapped query for the given publisher
+    /// and returns the CMS wrapped reply.
+    ///
+    /// Problems with the query are reported back in an error reply.
+    /// Returns Ok(None) if there is no publisher with this handle, and
+    /// an error only if we cannot sign our reply.
+    pub fn process(&mut self, handle: &str, query: &[u8]) -> anyhow::Result<Option<Bytes>> {
+        let Some(publisher) = PublisherHandle::from_str(handle)
+            .ok()
+            .and_then(|handle| self.publishers.get(&handle))
+            .cloned()
+        else {
+            return Ok(None);
+        };
+
+        let reply = match self.reply(&publisher, query) {
+            Ok(reply) => reply,
+            Err(code) => {
+                warn!("Error processing query from publisher {handle}: {code}");
+                Message::error(ErrorReply::for_error(ReportError::with_code(code)))
+            }
+        };
+
+        let cms = PublicationCms::create(reply, &self.key_id, &self.signer)
+            .map_err(|e| anyhow!("Cannot sign reply: {e}"))?;
+        Ok(Some(cms.to_bytes()))
+    }
+
+    fn reply(&mut self, publisher: &Publisher, query: &[u8]) -> Result<Message, ReportErrorCode> {
+        let cms = PublicationCms::decode(query).map_err(|_| ReportErrorCode::XmlError)?;
+        cms.validate(publisher.id_cert.public_key())
+            .map_err(|_| ReportErrorCode::BadCmsSignature)?;
+
+        match cms.into_message().as_query() {
+            Ok(Query::List) => Ok(Message::list_reply(self.content.list(&publisher.base_uri))),
+            Ok(Query::Delta(delta)) => {
+                debug!(
+                    "Applying delta with {} elements under {}",
+                    delta.len(),
+                    publisher.base_uri
+                );
+                let content = self
+                    .content
+                    .apply(&publisher.base_uri, delta.into_elements())?;
+
+                // Only use the new content once it is safe on disk, so
+                // that what we serve never gets lost on a restart.
+                self.persist_content(&content).map_err(|e| {
+                    warn!("Cannot save published content: {e:#}");
+                    ReportErrorCode::OtherError
+                })?;
+                self.content = content;
+                self.changed = true;
+                Ok(Message::success())
+            }
+            Err(_) => Err(ReportErrorCode::XmlError),
+        }
+    }
+
+    /// The directory where this server keeps its state.
+    pub fn state_dir(&self) -> &Path {
+        &self.state_dir
+    }
+
+    /// The ID certificate of this server.
+    pub fn id_cert(&self) -> &IdCert {
+        &self.id_cert
+    }
+
+    /// Returns Ok(true) if the content changed since the last call.
+    pub fn update(&mut self) -> anyhow::Result<bool> {
+        Ok(std::mem::take(&mut self.changed))
+    }
+
+    /// Returns true if the content changed since the last update.
+    pub fn has_pending_changes(&self) -> bool {
+        self.changed
+    }
+
+    /// All current elements by their SHA256 hash.
+    pub fn elements(&self) -> &HashMap<Hash, Arc<RepoContentElement>> {
+        &self.content.elements
+    }
+
+    /// All current manifest references by their AKI.
+    pub fn manifests(&self) -> &HashMap<rpki::crypto::KeyIdentifier, Arc<ManifestRef>> {
+        &self.content.manifests
+    }
+
+    fn load_or_create_identity(
+        state_dir: &Path,
+        signer: &OpenSslSigner,
+    ) -> anyhow::Result<(KeyId, IdCert)> {
+        let key_path = state_dir.join(ID_KEY_FILE);
+        let cert_path = state_dir.join(ID_CERT_FILE);
+
+        if key_path.exists() && cert_path.exists() {
+            let pem = util::read_file(&key_path)?;
+            let key_id = signer
+                .key_from_pem(pem.as_ref())
+                .with_context(|| format!("Cannot read key from {}", key_path.display()))?;
+            let id_cert = IdCert::decode(util::read_file(&cert_path)?)
+                .with_context(|| format!("Cannot read ID cert from {}", cert_path.display()))?;
+            return Ok((key_id, id_cert));
+        }
+
+        info!("Creating new publication server identity");
+        let pem = PKey::from_rsa(Rsa::generate(2048)?)?.private_key_to_pem_pkcs8()?;
+        let key_id = signer.key_from_pem(&pem)?;
+
+        let validity = Validity::new(Time::five_minutes_ago(), Time::years_from_now(15));
+        let id_cert = IdCert::new_ta(validity, &key_id, signer)
+            .map_err(|e| anyhow!("Cannot create ID certificate: {e}"))?;
+
+        Self::write_key(&key_path, &pem)?;
+        util::write_file(&cert_path, id_cert.to_bytes().as_ref())?;
+
+        Ok((key_id, id_cert))
+    }
+
+    /// Writes the private key so that only we can read it.
+    #[cfg(unix)]
+    fn write_key(path: &Path, pem: &[u8]) -> anyhow::Result<()> {
+        use std::{
+            fs::{OpenOptions, Permissions},
+            io::Write,
+            os::unix::fs::{OpenOptionsExt, PermissionsExt},
+        };
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("Cannot create key file {}", path.display()))?;
+        // The mode is only used for new files.
+        file.set_permissions(Permissions::from_mode(0o600))?;
+        file.write_all(pem)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Writes the private key. There are no unix permissions here, so
+    /// access to the state dir must be restricted instead.
+    #[cfg(not(unix))]
+    fn write_key(path: &Path, pem: &[u8]) -> anyhow::Result<()> {
+        util::write_file(path, pem)
+    }
+
+    /// Adds all publishers found in the publishers directory, and
+    /// writes their repository responses.
+    fn load_publishers(&mut self) -> anyhow::Result<()> {
+        let publishers_dir = self.state_dir.join(PUBLISHERS_DIR);
+        if !publishers_dir.is_dir() {
+            return Ok(());
+        }
+
+        for entry in std::fs::read_dir(&publishers_dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "xml") {
+                continue;
+            }
+
+            let xml = util::read_file(&path)?;
+            let request = PublisherRequest::parse(xml.as_ref())
+                .map_err(|e| anyhow!("Cannot parse {}: {e}", path.display()))?;
+            let response = self.add_publisher(&request)?;
+
+            let response_path = self
+                .state_dir
+                .join(RESPONSES_DIR)
+                .join(format!("{}.xml", request.publisher_handle()));
+            util::write_file(&response_path, &response.to_xml_vec())?;
+        }
+
+        Ok(())
+    }
+
+    fn load_content(path: &Path) -> anyhow::Result<PublishedContent> {
+        if !path.exists() {
+            return Ok(PublishedContent::default());
+        }
+
+        let json = util::read_file(path)?;
+        let persisted: PersistedContent = serde_json::from_slice(json.as_ref())
+            .with_context(|| format!("Cannot parse published content in {}", path.display()))?;
+
+        Ok(PublishedContent::from_objects(
+            persisted
+                .objects
+                .into_iter()
+                .map(|el| (el.uri().clone(), el))
+                .collect(),
+        ))
+    }
+
+    fn persist_content(&self, content: &PublishedContent) -> anyhow::Result<()> {
+        let persisted = PersistedContent {
+            objects: content.objects.values().cloned().collect(),
+        };
+        let json = serde_json::to_vec(&persisted)?;
+        util::write_file(&self.state_dir.join(CONTENT_FILE), &json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rpki::{
+        ca::publication::{Publish, PublishDelta, Reply, Update, Withdraw},
+        crypto::{PublicKeyFormat, Signer},
+    };
+
+    use crate::util::{https, test_with_dir};
+
+    use super::*;
+
+    struct TestPublisher {
+        signer: OpenSslSigner,
+        key_id: KeyId,
+        request: PublisherRequest,
+    }
+
+    impl TestPublisher {
+        fn create(handle: &str) -> Self {
+            let signer = OpenSslSigner::new();
+            let key_id = signer.create_key(PublicKeyFormat::Rsa).unwrap();
+            let validity = Validity::new(Time::five_minutes_ago(), Time::next_year());
+            let id_cert = IdCert::new_ta(validity, &key_id, &signer).unwrap();
+
+            let request = PublisherRequest::new(
+                Base64::from_content(&id_cert.to_bytes()),
+                PublisherHandle::from_str(handle).unwrap(),
+                None,
+            );
+
+            TestPublisher {
+                signer,
+                key_id,
+                request,
+            }
+        }
+
+        fn send(&self, server: &mut PublicationServer, message: Message) -> Reply {
+            let query = PublicationCms::create(message, &self.key_id, &self.signer).unwrap();
+            let handle = self.request.publisher_handle().as_str();
+            let reply_bytes = server.process(handle, &query.to_bytes()).unwrap().unwrap();
+
+            let reply = PublicationCms::decode(&reply_bytes).unwrap();
+            reply.validate(server.id_cert().public_key()).unwrap();
+            reply.into_message().as_reply().unwrap()
+        }
+    }
+
+    fn test_config() -> PublicationConfig {
+        PublicationConfig {
+            service_uri: https("https://localhost:3000/rfc8181/"),
+            sia_base: uri::Rsync::from_str("rsync://localhost/repo/").unwrap(),
+            rrdp_notification_uri: None,
+        }
+    }
+
+    fn uri(s: &str) -> uri::Rsync {
+        uri::Rsync::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn publish_list_and_withdraw() {
+        test_with_dir("publication_publish_list_withdraw", |dir| {
+            let mut server = PublicationServer::create(dir.clone(), test_config()).unwrap();
+            let publisher = TestPublisher::create("alice");
+            let response = server.add_publisher(&publisher.request).unwrap();
+            assert_eq!(&uri("rsync://localhost/repo/alice/"), response.sia_base());
+
+            let object_uri = uri("rsync://localhost/repo/alice/object.cer");
+            let content = Base64::from_content(b"object");
+
+            let mut delta = PublishDelta::empty();
+            delta.add_publish(Publish::new(None, object_uri.clone(), content.clone()));
+            assert_eq!(
+                Reply::Success,
+                publisher.send(&mut server, Message::delta(delta.clone()))
+            );
+            assert!(server.update().unwrap());
+            assert_eq!(1, server.elements().len());
+
+            // Publishing the same object again is an error
+            assert!(matches!(
+                publisher.send(&mut server, Message::delta(delta)),
+                Reply::ErrorReply(_)
+            ));
+
+            // Publishing outside of our base is an error
+            let mut delta = PublishDelta::empty();
+            delta.add_publish(Publish::new(
+                None,
+                uri("rsync://localhost/repo/bob/object.cer"),
+                content.clone(),
+            ));
+            assert!(matches!(
+                publisher.send(&mut server, Message::delta(delta)),
+                Reply::ErrorReply(_)
+            ));
+
+            match publisher.send(&mut server, Message::list_query()) {
+                Reply::List(list) => {
+                    assert_eq!(1, list.elements().len());
+                    assert_eq!(&object_uri, list.elements()[0].uri());
+                }
+                other => panic!("Expected list reply, got: {other:?}"),
+            }
+
+            let new_content = Base64::from_content(b"updated");
+            let mut delta = PublishDelta::empty();
+            delta.add_update(Update::new(
+                None,
+                object_uri.clone(),
+                new_content.clone(),
+                content.to_hash(),
+            ));
+            assert_eq!(
+                Reply::Success,
+                publisher.send(&mut server, Message::delta(delta))
+            );
+
+            // Content is recovered from disk
+            let mut recovered = PublicationServer::create(dir.clone(), test_config()).unwrap();
+            assert_eq!(1, recovered.elements().len());
+
+            let mut delta = PublishDelta::empty();
+            delta.add_withdraw(Withdraw::new(
+                None,
+                object_uri.clone(),
+                new_content.to_hash(),
+            ));
+            recovered.add_publisher(&publisher.request).unwrap();
+            assert_eq!(
+                Reply::Success,
+                publisher.send(&mut recovered, Message::delta(delta))
+            );
+            assert!(recovered.elements().is_empty());
+            assert!(recovered.update().unwrap());
+
+            // Nothing changes if the content cannot be saved.
+            std::fs::remove_file(dir.join(CONTENT_FILE)).unwrap();
+            std::fs::create_dir(dir.join(CONTENT_FILE)).unwrap();
+            let mut delta = PublishDelta::empty();
+            delta.add_publish(Publish::new(None, object_uri, content));
+            assert!(matches!(
+                publisher.send(&mut recovered, Message::delta(delta)),
+                Reply::ErrorReply(_)
+            ));
+            assert!(!recovered.update().unwrap());
+            assert!(recovered.elements().is_empty());
+
+            // The key is private.
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+
+                let meta = std::fs::metadata(dir.join(ID_KEY_FILE)).unwrap();
+                assert_eq!(0o600, meta.permissions().mode() & 0o777);
+            }
+        });
+    }
+
+    #[test]
+    fn reject_unknown_publisher_and_bad_signature() {
+        test_with_dir("publication_reject", |dir| {
+            let mut server = PublicationServer::create(dir, test_config()).unwrap();
+            let alice = TestPublisher::create("alice");
+            server.add_publisher(&alice.request).unwrap();
+
+            // Bob is not configured
+            let bob = TestPublisher::create("bob");
+            let query =
+                PublicationCms::create(Message::list_query(), &bob.key_id, &bob.signer).unwrap();
+            assert!(server.process("bob", &query.to_bytes()).unwrap().is_none());
+
+            // Bob's query sent as alice fails the signature check
+            let reply_bytes = server.process("alice", &query.to_bytes()).unwrap().unwrap();
+            let reply = PublicationCms::decode(&reply_bytes)
+                .unwrap()
+                .into_message()
+                .as_reply()
+                .unwrap();
+            assert!(matches!(reply, Reply::ErrorReply(_)));
+        });
+    }
+}
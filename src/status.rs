@@ -0,0 +1,198 @@
+//! The status and content of a relay, as reported in JSON by epic.
+
+use rpki::{repository::x509::Time, rrdp::Hash, uri};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    erik::{media_type::ObjectKind, ni::NamedInformation, relay::RelayContent},
+    fetch::{
+        pool::{PoolStatus, RepositorySource},
+        retrieval::Fqdn,
+    },
+};
+
+/// The status of the relay, its repositories and its indexes.
+#[derive(Clone, Debug, Serialize)]
+pub struct RelayStatus {
+    /// Whether the relay has content to serve. This is false until
+    /// the first successful update.
+    pub ready: bool,
+
+    pub repositories: Vec<RepositoryReport>,
+    pub indexes: Vec<IndexReport>,
+}
+
+/// The status of a repository in the pool.
+#[derive(Clone, Debug, Serialize)]
+pub struct RepositoryReport {
+    /// Where the content comes from, for humans.
+    pub source: String,
+
+    /// The RRDP notify URI, for RRDP repositories.
+    pub notify: Option<uri::Https>,
+
+    pub session: Option<Uuid>,
+    pub serial: Option<u64>,
+
+    /// When the repository was last updated successfully.
+    pub last_update: Option<Time>,
+
+    /// The error of the last update, if it failed.
+    pub last_error: Option<String>,
+
+    /// The number of current objects.
+    pub elements: usize,
+}
+
+/// The status of a served Erik index.
+#[derive(Clone, Debug, Serialize)]
+pub struct IndexReport {
+    pub fqdn: Fqdn,
+    pub index_time: Time,
+
+    /// The hash of the encoded index.
+    pub hash: Hash,
+
+    /// The number of partitions.
+    pub partitions: usize,
+}
+
+impl RelayStatus {
+    pub fn new(ready: bool, pool: &PoolStatus, content: &RelayContent) -> Self {
+        let mut repositories: Vec<RepositoryReport> = pool
+            .repositories
+            .iter()
+            .map(|status| RepositoryReport {
+                source: status.source.to_string(),
+                notify: match &status.source {
+                    RepositorySource::Rrdp(notify) => Some(notify.clone()),
+                    _ => None,
+                },
+                session: status.session,
+                serial: status.serial,
+                last_update: status.last_update.map(Time::from),
+                last_error: status.last_error.clone(),
+                elements: status.elements,
+            })
+            .collect();
+        repositories.sort_by(|a, b| a.source.cmp(&b.source));
+
+        RelayStatus {
+            ready,
+            repositories,
+            indexes: IndexReport::all(content),
+        }
+    }
+}
+
+impl IndexReport {
+    /// Reports all indexes in the content, ordered by scope.
+    pub fn all(content: &RelayContent) -> Vec<Self> {
+        let mut indexes: Vec<IndexReport> = content
+            .index_summaries()
+            .map(|(fqdn, summary)| IndexReport {
+                fqdn: fqdn.clone(),
+                index_time: summary.index_time(),
+                hash: summary.hash(),
+                partitions: summary.partition_count(),
+            })
+            .collect();
+        indexes.sort_by_key(|index| index.fqdn.to_string());
+        indexes
+    }
+}
+
+/// What we know about a served object.
+#[derive(Clone, Debug, Serialize)]
+pub struct ObjectReport {
+    pub hash: Hash,
+
+    /// The ni name of the object.
+    pub ni: String,
+
+    pub size: usize,
+    pub kind: ObjectKind,
+    pub media_type: String,
+
+    /// Where the object was published, for repository objects.
+    pub uri: Option<uri::Rsync>,
+}
+
+impl ObjectReport {
+    /// Reports the object with the given hash, if it is in the
+    /// content.
+    pub fn new(content: &RelayContent, hash: &Hash, erik_media_type: &str) -> Option<Self> {
+        let data = content.object(hash)?;
+        let kind = content.object_kind(hash).unwrap_or(ObjectKind::Unknown);
+        Some(ObjectReport {
+            hash: *hash,
+            ni: NamedInformation::new(*hash).to_string(),
+            size: data.len(),
+            kind,
+            media_type: kind.media_type(erik_media_type).to_string(),
+            uri: content.element(hash).map(|el| el.uri().clone()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        fetch::{
+            pool::{DEFAULT_REFRESH_SECONDS, RepositoryPool},
+            retrieval::FetchMapper,
+        },
+        util::https,
+    };
+
+    use super::*;
+
+    #[test]
+    fn status_as_json() {
+        let notify = https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+        let mut mapper = FetchMapper::empty();
+        mapper.add_disk_mapper(
+            (&notify).into(),
+            PathBuf::from("test-resources/rrdp-rev2656/"),
+        );
+        let mut pool = RepositoryPool::empty();
+        pool.add(notify.into(), mapper, DEFAULT_REFRESH_SECONDS)
+            .unwrap();
+        pool.update_due();
+        let content = RelayContent::from_pool(&pool);
+
+        let status = RelayStatus::new(true, &pool.status(), &content);
+        let json = serde_json::to_value(&status).unwrap();
+
+        let repository = &json["repositories"][0];
+        assert_eq!(
+            "https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml",
+            repository["notify"]
+        );
+        assert_eq!(2656, repository["serial"]);
+        assert_eq!(440, repository["elements"]);
+        assert!(repository["last_update"].is_string());
+        assert!(repository["last_error"].is_null());
+
+        let index = &json["indexes"][0];
+        assert_eq!("krill-ui-dev.do.nlnetlabs.nl", index["fqdn"]);
+        assert_eq!(7, index["partitions"]);
+        assert_eq!(64, index["hash"].as_str().unwrap().len());
+
+        let (hash, element) = content
+            .element_by_uri(
+                &content
+                    .objects()
+                    .find_map(|(hash, _)| content.element(hash).map(|el| el.uri().clone()))
+                    .unwrap(),
+            )
+            .unwrap();
+        let object = ObjectReport::new(&content, hash, "application/rpki-erik").unwrap();
+        assert_eq!(Some(element.uri()), object.uri.as_ref());
+        assert_eq!(element.data().len(), object.size);
+        assert!(object.ni.starts_with("ni:///sha-256;"));
+    }
+}
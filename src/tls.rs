@@ -0,0 +1,286 @@
+//! Serving over TLS.
+//!
+//! The certificate chain and key are read from PEM files. They can be
+//! reloaded at any time: new connections then get the new certificate,
+//! while established connections are not affected.
+
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::{Context, anyhow};
+use log::{debug, info};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        ServerConfig,
+        crypto::{CryptoProvider, ring},
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+    },
+    server::TlsStream,
+};
+
+/// How long a client may take to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The number of connections that may wait to be served after their
+/// handshake completed.
+const ACCEPT_BACKLOG: usize = 64;
+
+/// The certificate that is presented to clients, which can be reloaded
+/// from its files.
+pub struct ServerCertificate {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ServerCertificate {
+    /// Loads the certificate chain and the private key for it.
+    pub fn load(cert_file: PathBuf, key_file: PathBuf) -> anyhow::Result<Arc<Self>> {
+        let provider = Arc::new(ring::default_provider());
+        let current = Self::read(&cert_file, &key_file, &provider)?;
+        Ok(Arc::new(ServerCertificate {
+            cert_file,
+            key_file,
+            provider,
+            current: RwLock::new(Arc::new(current)),
+        }))
+    }
+
+    /// Reads the files again. If they cannot be used, the current
+    /// certificate is kept.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let certified = Self::read(&self.cert_file, &self.key_file, &self.provider)?;
+        *self.current.write().unwrap() = Arc::new(certified);
+        info!("Reloaded TLS certificate {}", self.cert_file.display());
+        Ok(())
+    }
+
+    fn read(
+        cert_file: &Path,
+        key_file: &Path,
+        provider: &CryptoProvider,
+    ) -> anyhow::Result<CertifiedKey> {
+        let chain = CertificateDer::pem_file_iter(cert_file)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| anyhow!("Cannot read certificates from {}: {e}", cert_file.display()))?;
+        if chain.is_empty() {
+            return Err(anyhow!("No certificates in {}", cert_file.display()));
+        }
+        let key = PrivateKeyDer::from_pem_file(key_file)
+            .map_err(|e| anyhow!("Cannot read private key from {}: {e}", key_file.display()))?;
+        CertifiedKey::from_der(chain, key, provider).with_context(|| {
+            format!(
+                "Cannot use key {} with certificate {}",
+                key_file.display(),
+                cert_file.display()
+            )
+        })
+    }
+
+    /// Returns the TLS configuration for serving with this certificate.
+    pub fn server_config(self: &Arc<Self>) -> anyhow::Result<Arc<ServerConfig>> {
+        let mut config = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+}
+
+impl fmt::Debug for ServerCertificate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ServerCertificate")
+            .field("cert_file", &self.cert_file)
+            .field("key_file", &self.key_file)
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for ServerCertificate {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Accepts TLS connections, for use with `axum::serve`.
+///
+/// Handshakes are done in their own tasks, so that slow clients do not
+/// hold up others. Connections are accepted until the listener is
+/// dropped.
+pub struct TlsListener {
+    local_addr: SocketAddr,
+    accepted: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, config: Arc<ServerConfig>) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (sender, accepted) = mpsc::channel(ACCEPT_BACKLOG);
+        tokio::spawn(accept_connections(
+            listener,
+            TlsAcceptor::from(config),
+            sender,
+        ));
+        Ok(TlsListener {
+            local_addr,
+            accepted,
+        })
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.accepted.recv().await {
+            Some(accepted) => accepted,
+            // The accepting task only stops once we are dropped.
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+async fn accept_connections(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    sender: mpsc::Sender<(TlsStream<TcpStream>, SocketAddr)>,
+) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = sender.closed() => return,
+        };
+        let (stream, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // Mostly running out of file descriptors, which may
+                // resolve itself.
+                debug!("Cannot accept connection: {e}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    // The listener may be gone by now, that is fine.
+                    let _ = sender.send((stream, addr)).await;
+                }
+                Ok(Err(e)) => debug!("TLS handshake with {addr} failed: {e}"),
+                Err(_) => debug!("TLS handshake with {addr} timed out"),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::serve::Listener;
+    use tokio_rustls::{
+        TlsConnector,
+        rustls::{ClientConfig, RootCertStore, pki_types::ServerName},
+    };
+
+    use crate::util::test_with_dir;
+
+    use super::*;
+
+    const TLS_DIR: &str = "test-resources/tls";
+
+    fn test_file(name: &str) -> PathBuf {
+        Path::new(TLS_DIR).join(name)
+    }
+
+    /// Connects to the listener, trusting the test CA, and returns the
+    /// certificate that the server presented.
+    async fn server_certificate(listener: &mut TlsListener) -> CertificateDer<'static> {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(test_file("ca.crt")).unwrap())
+            .unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let name = ServerName::try_from("localhost").unwrap();
+            let stream = connector.connect(name, stream).await.unwrap();
+            stream.get_ref().1.peer_certificates().unwrap()[0].clone()
+        });
+        let (_server_stream, _) = listener.accept().await;
+        client.await.unwrap()
+    }
+
+    #[test]
+    fn reject_mismatched_key() {
+        assert!(
+            ServerCertificate::load(test_file("localhost.crt"), test_file("renewed.key")).is_err()
+        );
+        assert!(
+            ServerCertificate::load(test_file("localhost.key"), test_file("localhost.key"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn reload_certificate() {
+        test_with_dir("reload_certificate", |dir| {
+            let cert_file = dir.join("server.crt");
+            let key_file = dir.join("server.key");
+            std::fs::copy(test_file("localhost.crt"), &cert_file).unwrap();
+            std::fs::copy(test_file("localhost.key"), &key_file).unwrap();
+
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                let certificate =
+                    ServerCertificate::load(cert_file.clone(), key_file.clone()).unwrap();
+                let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let mut listener =
+                    TlsListener::new(tcp, certificate.server_config().unwrap()).unwrap();
+
+                let localhost = CertificateDer::from_pem_file(test_file("localhost.crt")).unwrap();
+                assert_eq!(localhost, server_certificate(&mut listener).await);
+
+                // A renewed certificate without its key is not used.
+                std::fs::copy(test_file("renewed.crt"), &cert_file).unwrap();
+                assert!(certificate.reload().is_err());
+                assert_eq!(localhost, server_certificate(&mut listener).await);
+
+                std::fs::copy(test_file("renewed.key"), &key_file).unwrap();
+                certificate.reload().unwrap();
+                let renewed = CertificateDer::from_pem_file(test_file("renewed.crt")).unwrap();
+                assert_eq!(renewed, server_certificate(&mut listener).await);
+            });
+        })
+    }
+}
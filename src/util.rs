@@ -1,8 +1,15 @@
-use std::{fmt, fs::File, io::Read, path::Path};
+use std::{
+    fmt,
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
+use anyhow::Context;
 use base64::{Engine as _, engine::general_purpose::STANDARD_NO_PAD};
 use bytes::Bytes;
-use chrono::{Local, TimeZone};
+use chrono::{DateTime, Local, Utc};
 use rpki::dep::bcder::Ia5String;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use uuid::Uuid;
@@ -17,6 +24,53 @@ pub fn read_file(file_path: &Path) -> anyhow::Result<Bytes> {
     Ok(Bytes::from(buf))
 }
 
+/// Writes a file, creating its parent directories if needed. The data is
+/// written to a temporary file first, and then renamed, so that readers
+/// never see a partially written file. Each write uses its own temporary
+/// file, so that concurrent writes of the same file do not interfere.
+pub fn write_file(file_path: &Path, buf: &[u8]) -> anyhow::Result<()> {
+    let dir = file_path
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("Cannot determine parent of {}", file_path.display()))?;
+    std::fs::create_dir_all(dir)?;
+
+    static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut tmp_name = file_path.as_os_str().to_owned();
+    tmp_name.push(format!(
+        ".{}-{}.tmp",
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp_path = Path::new(&tmp_name);
+
+    let mut f = File::create(tmp_path)?;
+    f.write_all(buf)?;
+    f.sync_all()?;
+    std::fs::rename(tmp_path, file_path)?;
+    Ok(())
+}
+
+/// Removes a file, and then its parent directories for as long as they
+/// are empty. Directories are never removed above the given base dir.
+pub fn remove_file_and_empty_parent_dirs(path: &Path, base_dir: &Path) -> anyhow::Result<()> {
+    if path.is_file() {
+        std::fs::remove_file(path)
+            .with_context(|| format!("Cannot remove file {}", path.display()))?;
+    }
+
+    // This is 'best effort'. Removing a dir fails when it is not empty,
+    // which means that we are done.
+    let mut parent = path.parent();
+    while let Some(dir) = parent {
+        if dir == base_dir || !dir.starts_with(base_dir) || std::fs::remove_dir(dir).is_err() {
+            break;
+        }
+        parent = dir.parent();
+    }
+
+    Ok(())
+}
+
 //----------------------------------------------------------------------------
 //------------ Time Support --------------------------------------------------
 //----------------------------------------------------------------------------
@@ -32,18 +86,34 @@ impl Time {
 
     pub fn seconds_ago(seconds: i64) -> Self {
         let mut now = Self::now();
-        now.0 -= seconds;
+        now.0 = now.0.saturating_sub(seconds);
+        now
+    }
+
+    pub fn seconds_from_now(seconds: i64) -> Self {
+        let mut now = Self::now();
+        now.0 = now.0.saturating_add(seconds);
         now
     }
 
     pub fn timestamp(&self) -> i64 {
         self.0
     }
+
+    /// The time as a date. Times that cannot be represented, such as
+    /// saturated ones, are clamped to the earliest or latest date.
+    fn to_date_time(self) -> DateTime<Utc> {
+        DateTime::from_timestamp(self.0, 0).unwrap_or(if self.0 < 0 {
+            DateTime::<Utc>::MIN_UTC
+        } else {
+            DateTime::<Utc>::MAX_UTC
+        })
+    }
 }
 
 impl fmt::Display for Time {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let time = Local.timestamp_opt(self.0, 0).unwrap();
+        let time = self.to_date_time().with_timezone(&Local);
         write!(f, "{}", time.to_rfc3339())
     }
 }
@@ -54,6 +124,12 @@ impl From<rpki::repository::x509::Time> for Time {
     }
 }
 
+impl From<Time> for rpki::repository::x509::Time {
+    fn from(time: Time) -> Self {
+        rpki::repository::x509::Time::new(time.to_date_time())
+    }
+}
+
 //----------------------------------------------------------------------------
 //------------ Serde Support -------------------------------------------------
 //----------------------------------------------------------------------------
@@ -158,3 +234,215 @@ pub fn https(s: &str) -> rpki::uri::Https {
 
     rpki::uri::Https::from_str(s).unwrap()
 }
+
+#[cfg(test)]
+pub fn rsync(s: &str) -> rpki::uri::Rsync {
+    use std::str::FromStr;
+
+    rpki::uri::Rsync::from_str(s).unwrap()
+}
+
+#[cfg(test)]
+/// The status, headers and body of a response from a test server.
+pub type TestResponse = (u16, Vec<(&'static str, String)>, Vec<u8>);
+
+#[cfg(test)]
+/// Serves plain HTTP on localhost from a background thread, and
+/// returns the base URL. Each request is answered by `respond`, which
+/// gets the method, path and body of the request. Only what the tests
+/// need is supported: the request body is read by its Content-Length,
+/// and the connection is closed after each response.
+pub fn test_http_server<F>(respond: F) -> String
+where
+    F: Fn(&str, &str, &[u8]) -> TestResponse + Send + 'static,
+{
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+        thread,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            if reader.read_line(&mut request_line).is_err() {
+                continue;
+            }
+            let mut parts = request_line.split_whitespace();
+            let method = parts.next().unwrap_or_default().to_string();
+            let path = parts.next().unwrap_or_default().to_string();
+
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 || line.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':')
+                    && name.eq_ignore_ascii_case("content-length")
+                {
+                    content_length = value.trim().parse().unwrap_or(0);
+                }
+            }
+            let mut body = vec![0; content_length];
+            if reader.read_exact(&mut body).is_err() {
+                continue;
+            }
+
+            let (status, headers, body) = respond(&method, &path, &body);
+            let mut head = format!(
+                "HTTP/1.1 {status} Test\r\nContent-Length: {}\r\nConnection: close\r\n",
+                body.len()
+            );
+            for (name, value) in headers {
+                head.push_str(&format!("{name}: {value}\r\n"));
+            }
+            head.push_str("\r\n");
+            let mut stream = reader.into_inner();
+            let _ = stream
+                .write_all(head.as_bytes())
+                .and_then(|_| stream.write_all(&body));
+        }
+    });
+
+    base_url
+}
+
+#[cfg(test)]
+/// An RRDP repository on disk that tests can change. Each change is
+/// written as a delta, together with a new snapshot and notification
+/// file, so that it can be followed with deltas or a snapshot.
+pub struct TestRrdpSource {
+    dir: PathBuf,
+    session_id: Uuid,
+    serial: u64,
+    objects: std::collections::HashMap<rpki::uri::Rsync, Bytes>,
+    deltas: Vec<rpki::rrdp::DeltaInfo>,
+}
+
+#[cfg(test)]
+impl TestRrdpSource {
+    /// Creates the repository in `dir`, with the given data by rsync
+    /// URI in its first snapshot.
+    pub fn create(dir: PathBuf, objects: &[(&str, &str)]) -> Self {
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut source = TestRrdpSource {
+            dir,
+            session_id: Uuid::new_v4(),
+            serial: 1,
+            objects: Default::default(),
+            deltas: vec![],
+        };
+        for (uri, data) in objects {
+            source
+                .objects
+                .insert(rsync(uri), Bytes::from(data.to_string()));
+        }
+        source.write_snapshot();
+        source
+    }
+
+    pub fn notify(&self) -> rpki::uri::Https {
+        https("https://rrdp.example.net/notification.xml")
+    }
+
+    /// A mapper that reads the repository from disk.
+    pub fn fetch_mapper(&self) -> crate::fetch::retrieval::FetchMapper {
+        let mut mapper = crate::fetch::retrieval::FetchMapper::empty();
+        mapper.add_disk_mapper((&self.notify()).into(), self.dir.clone());
+        mapper
+    }
+
+    /// Writes the next serial. Objects with data are published or
+    /// updated, objects without data are withdrawn.
+    pub fn change(&mut self, changes: &[(&str, Option<&str>)]) {
+        use rpki::rrdp::{Delta, DeltaInfo, Hash, PublishElement, UpdateElement, WithdrawElement};
+
+        self.serial += 1;
+        let mut elements = vec![];
+        for (uri, data) in changes {
+            let uri = rsync(uri);
+            let old = self.objects.get(&uri).map(|data| Hash::from_data(data));
+            match (old, data) {
+                (None, Some(data)) => {
+                    let data = Bytes::from(data.to_string());
+                    elements.push(PublishElement::new(uri.clone(), data.clone()).into());
+                    self.objects.insert(uri, data);
+                }
+                (Some(hash), Some(data)) => {
+                    let data = Bytes::from(data.to_string());
+                    elements.push(UpdateElement::new(uri.clone(), hash, data.clone()).into());
+                    self.objects.insert(uri, data);
+                }
+                (Some(hash), None) => {
+                    elements.push(WithdrawElement::new(uri.clone(), hash).into());
+                    self.objects.remove(&uri);
+                }
+                (None, None) => panic!("Cannot withdraw unknown object {uri}"),
+            }
+        }
+
+        let mut xml = vec![];
+        Delta::new(self.session_id, self.serial, elements)
+            .write_xml(&mut xml)
+            .unwrap();
+        let rel_path = format!("delta-{}.xml", self.serial);
+        std::fs::write(self.dir.join(&rel_path), &xml).unwrap();
+        self.deltas.push(DeltaInfo::new(
+            self.serial,
+            self.uri(&rel_path),
+            Hash::from_data(&xml),
+        ));
+        self.write_snapshot();
+    }
+
+    fn write_snapshot(&self) {
+        use rpki::rrdp::{Hash, NotificationFile, PublishElement, Snapshot, SnapshotInfo};
+
+        let publish = self
+            .objects
+            .iter()
+            .map(|(uri, data)| PublishElement::new(uri.clone(), data.clone()))
+            .collect();
+        let mut xml = vec![];
+        Snapshot::new(self.session_id, self.serial, publish)
+            .write_xml(&mut xml)
+            .unwrap();
+        let rel_path = format!("snapshot-{}.xml", self.serial);
+        std::fs::write(self.dir.join(&rel_path), &xml).unwrap();
+
+        let snapshot = SnapshotInfo::new(self.uri(&rel_path), Hash::from_data(&xml));
+        let notification =
+            NotificationFile::new(self.session_id, self.serial, snapshot, self.deltas.clone());
+        let mut xml = vec![];
+        notification.write_xml(&mut xml).unwrap();
+        std::fs::write(self.dir.join("notification.xml"), &xml).unwrap();
+    }
+
+    fn uri(&self, rel_path: &str) -> rpki::uri::Https {
+        https(&format!("https://rrdp.example.net/{rel_path}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saturated_times_are_clamped() {
+        for time in [
+            Time::seconds_from_now(i64::MAX),
+            Time::seconds_ago(i64::MAX),
+        ] {
+            let x509 = rpki::repository::x509::Time::from(time);
+            assert_eq!(time.to_date_time(), x509.to_utc());
+            assert!(!time.to_string().is_empty());
+        }
+        let now = Time::now();
+        assert_eq!(now, Time::from(rpki::repository::x509::Time::from(now)));
+    }
+}
@@ -0,0 +1,21 @@
+#!/bin/sh
+# Generates the test CA and the localhost certificates signed by it,
+# for testing epic with TLS. They are valid for a hundred years.
+set -e
+cd "$(dirname "$0")"
+
+openssl req -x509 -newkey rsa:2048 -nodes -days 36500 \
+    -subj "/CN=epic test CA" -keyout ca.key -out ca.crt
+
+for name in localhost renewed; do
+    openssl req -newkey rsa:2048 -nodes -subj "/CN=localhost" \
+        -keyout "$name.key" -out "$name.csr"
+    openssl x509 -req -in "$name.csr" -CA ca.crt -CAkey ca.key \
+        -CAcreateserial -days 36500 -out "$name.crt" \
+        -extfile /dev/stdin <<EXT
+subjectAltName = DNS:localhost, IP:127.0.0.1, IP:::1
+extendedKeyUsage = serverAuth
+EXT
+    rm "$name.csr"
+done
+rm -f ca.srl